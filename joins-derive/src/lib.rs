@@ -0,0 +1,114 @@
+//! Derive macros for the `joins` crate.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, GenericParam, Ident, Index, Member};
+
+/// Derives `joins::JoinKey` from the fields marked with `#[join(key)]`.
+#[proc_macro_derive(JoinKey, attributes(join))]
+pub fn derive_join_key(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    join_key(&input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn join_key(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => return Err(syn::Error::new_spanned(input, "JoinKey can only be derived for structs")),
+    };
+    let fields = match fields {
+        Fields::Named(fields) => &fields.named,
+        Fields::Unnamed(fields) => &fields.unnamed,
+        Fields::Unit => return Err(syn::Error::new_spanned(input, "JoinKey requires at least one #[join(key)] field")),
+    };
+
+    let mut members = Vec::new();
+    let mut types = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let mut key = false;
+        for attr in field.attrs.iter().filter(|attr| attr.path.is_ident("join")) {
+            let arg: Ident = attr.parse_args()?;
+            if arg != "key" {
+                return Err(syn::Error::new_spanned(arg, "expected `key`"));
+            }
+            key = true;
+        }
+        if key {
+            members.push(match &field.ident {
+                Some(ident) => Member::Named(ident.clone()),
+                None => Member::Unnamed(Index::from(i)),
+            });
+            types.push(&field.ty);
+        }
+    }
+
+    let (key_type, key) = match (&members[..], &types[..]) {
+        ([], _) => return Err(syn::Error::new_spanned(input, "JoinKey requires at least one #[join(key)] field")),
+        ([member], [ty]) => (quote!(#ty), quote!(::std::clone::Clone::clone(&self.#member))),
+        _ => (quote!((#(#types,)*)), quote!((#(::std::clone::Clone::clone(&self.#members),)*))),
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::joins::JoinKey for #name #ty_generics #where_clause {
+            type Key = #key_type;
+            fn join_key(&self) -> Self::Key {
+                #key
+            }
+        }
+    })
+}
+
+/// Derives `joins::HeapSize` by summing up the heap sizes of all fields.
+#[proc_macro_derive(HeapSize)]
+pub fn derive_heap_size(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    heap_size(input).into()
+}
+
+/// Binds all fields of a struct or variant, returning the pattern and the sum of their heap sizes.
+fn sum_fields(path: TokenStream2, fields: &Fields) -> (TokenStream2, TokenStream2) {
+    let bindings: Vec<_> = (0..fields.len()).map(|i| quote::format_ident!("f{}", i)).collect();
+    let pattern = match fields {
+        Fields::Named(fields) => {
+            let names = fields.named.iter().map(|f| &f.ident);
+            quote!(#path { #(#names: #bindings),* })
+        }
+        Fields::Unnamed(_) => quote!(#path(#(#bindings),*)),
+        Fields::Unit => quote!(#path),
+    };
+    (pattern, quote!(0 #(+ ::joins::HeapSize::heap_size(#bindings))*))
+}
+
+fn heap_size(mut input: DeriveInput) -> TokenStream2 {
+    for param in &mut input.generics.params {
+        if let GenericParam::Type(param) = param {
+            param.bounds.push(parse_quote!(::joins::HeapSize));
+        }
+    }
+
+    let arms = match &input.data {
+        Data::Struct(data) => vec![sum_fields(quote!(Self), &data.fields)],
+        Data::Enum(data) => data.variants.iter().map(|v| {
+            let name = &v.ident;
+            sum_fields(quote!(Self::#name), &v.fields)
+        }).collect(),
+        Data::Union(_) => return syn::Error::new_spanned(&input, "HeapSize can't be derived for unions").into_compile_error(),
+    };
+    let (patterns, sums): (Vec<_>, Vec<_>) = arms.into_iter().unzip();
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    quote! {
+        impl #impl_generics ::joins::HeapSize for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn heap_size(&self) -> usize {
+                match self {
+                    #(#patterns => #sums,)*
+                }
+            }
+        }
+    }
+}
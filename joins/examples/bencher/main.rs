@@ -1,33 +1,50 @@
 #![feature(type_alias_impl_trait)]
 
+use std::convert::Infallible;
 use std::fmt::Debug;
+use std::mem;
 use std::rc::Rc;
 use std::cmp::Ordering;
 use std::cell::RefCell;
-use futures::{Async, Future, Poll, Stream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Future, Stream, TryStream, TryStreamExt, future};
+use futures::task::noop_waker_ref;
 use named_type::NamedType;
+use pin_project::pin_project;
 use rand::prelude::*;
 
 use joins::*;
 
 
 pub struct BenchStorage(Rc<RefCell<IoSimulator>>);
+impl Storage for BenchStorage {
+    type Error = Infallible;
+}
 impl<T: Clone> ExternalStorage<T> for BenchStorage {
     type External = BenchExternal<T>;
-    fn store(&mut self, tuples: Vec<T>) -> BenchExternal<T> {
+    fn store(&mut self, tuples: Vec<T>) -> Result<BenchExternal<T>, Infallible> {
         self.0.borrow_mut().notify_disk_io(tuples.len(), true);
-        BenchExternal(Rc::new(tuples), Rc::clone(&self.0))
+        Ok(BenchExternal(Rc::new(tuples), Rc::clone(&self.0)))
+    }
+}
+impl<T: Clone> AsyncExternalStorage<T> for BenchStorage {
+    type External = Blocking<BenchExternal<T>>;
+    type Store = future::Ready<Result<Blocking<BenchExternal<T>>, Infallible>>;
+    fn store(&mut self, tuples: Vec<T>) -> Self::Store {
+        future::ready(ExternalStorage::store(self, tuples).map(Blocking))
     }
 }
 pub struct BenchExternal<T>(Rc<Vec<T>>, Rc<RefCell<IoSimulator>>);
 impl<T: Clone> External<T> for BenchExternal<T> {
+    type Error = Infallible;
     type Iter = BenchIter<T>;
-    fn fetch<'a>(&'a self) -> Self::Iter {
-        BenchIter {
+    fn fetch(&self) -> Result<Self::Iter, Infallible> {
+        Ok(BenchIter {
             data: Rc::clone(&self.0),
             sim: Rc::clone(&self.1),
             index: 0,
-        }
+        })
     }
 }
 pub struct BenchIter<T> {
@@ -36,19 +53,18 @@ pub struct BenchIter<T> {
     sim: Rc<RefCell<IoSimulator>>,
 }
 impl<T: Clone> Iterator for BenchIter<T> {
-    type Item = T;
+    type Item = Result<T, Infallible>;
 
-    fn next(&mut self) -> Option<T> {
-        self.data.get(self.index).map(Clone::clone).map(|x| {
+    fn next(&mut self) -> Option<Result<T, Infallible>> {
+        self.data.get(self.index).cloned().inspect(|_| {
             self.sim.borrow_mut().notify_disk_io(1, false);
             self.index += 1;
-            x
-        })
+        }).map(Ok)
     }
 }
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, HeapSize)]
 #[allow(unused)]
 struct Tuple { a: i32, b: i32 }
 
@@ -134,51 +150,55 @@ impl IoSimulator {
         }))
     }
 }
+#[pin_project]
 struct TupleInputThrottle<T> {
+    #[pin]
     underlying: T,
     side: Side,
     simulator: Rc<RefCell<IoSimulator>>,
 }
 impl<T: Stream> Stream for TupleInputThrottle<T> {
     type Item = T::Item;
-    type Error = T::Error;
 
-    fn poll(&mut self) -> Poll<Option<T::Item>, T::Error> {
-        if self.simulator.borrow_mut().read_tuple(self.side) {
-            self.underlying.poll()
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T::Item>> {
+        let this = self.project();
+        if this.simulator.borrow_mut().read_tuple(*this.side) {
+            this.underlying.poll_next(cx)
         } else {
-            Ok(Async::NotReady)
+            Poll::Pending
         }
     }
 }
 impl<T: Rescan> Rescan for TupleInputThrottle<T> {
-    fn rescan(&mut self) {
-        self.underlying.rescan();
+    fn rescan(self: Pin<&mut Self>) {
+        self.project().underlying.rescan();
     }
 }
+#[pin_project]
 #[derive(Debug)]
 pub struct IterVec<T> {
     data: Vec<T>,
     index: usize,
 }
 impl<T: Clone> Stream for IterVec<T> {
-    type Item = T;
-    type Error = ();
+    type Item = Result<T, ()>;
     
-    fn poll(&mut self) -> Poll<Option<T>, ()> {
-        let index = self.index;
-        self.index = index + 1;
-        Ok(Async::Ready(self.data.get(index).map(Clone::clone)))
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<T, ()>>> {
+        let this = self.project();
+        let index = *this.index;
+        *this.index = index + 1;
+        Poll::Ready(this.data.get(index).cloned().map(Ok))
     }
 }
 impl<T: Clone> Rescan for IterVec<T> {
-    fn rescan(&mut self) {
-        self.index = 0;
+    fn rescan(self: Pin<&mut Self>) {
+        *self.project().index = 0;
     }
 }
 
+#[define_opaque(BenchSource)]
 fn bench_source<T: Clone>(data: Vec<T>, simulator: &Rc<RefCell<IoSimulator>>, side: Side) -> BenchSource<T> {
-    let rc = Rc::clone(&simulator);
+    let rc = Rc::clone(simulator);
     TupleInputThrottle {
         underlying: IterVec { data, index: 0 },
         side,
@@ -187,7 +207,7 @@ fn bench_source<T: Clone>(data: Vec<T>, simulator: &Rc<RefCell<IoSimulator>>, si
 }
 
 // TODO: delet this
-type BenchSource<T: Clone> = impl Stream<Item=T, Error=()> + Rescan;
+type BenchSource<T: Clone> = impl TryStream<Ok=T, Error=()> + Rescan;
 
 struct BenchPredicate<P>(P, Rc<RefCell<IoSimulator>>);
 impl<P: JoinPredicate> JoinPredicate for BenchPredicate<P> {
@@ -249,7 +269,7 @@ where
 
     //let mut timings = Vec::new();
     let mut i = 0;
-    let timed = join.inspect(|_| {
+    let timed = join.inspect_ok(|_| {
     let simulator = simulator.borrow();
     //timings.push((simulator.read_tuple_count, simulator.disk_ops_out, simulator.disk_ops_in, simulator.predicate_calls));
     println!("{} {} {} {} {} {} {} {}", i, J::short_type_name(), simulator.read_tuple_count, simulator.disk_ops_out, simulator.disk_ops_in, simulator.predicate_calls, simulator.cmp_calls, simulator.hash_calls);
@@ -257,18 +277,19 @@ where
     });
 
     //println!("Running {} ...", J::short_type_name());
-    let mut collector = timed.collect();
+    let mut collector = Box::pin(timed.try_collect::<Vec<_>>());
+    let mut cx = Context::from_waker(noop_waker_ref());
     loop {
-        match collector.poll() {
-            Ok(Async::Ready(_result)) => {
+        match collector.as_mut().poll(&mut cx) {
+            Poll::Ready(Ok(_result)) => {
                 //println!("{} RESULTS {:?} ({} items)", J::short_type_name(), (), result.len());
                 break;
             }
-            Ok(Async::NotReady) => {
+            Poll::Pending => {
                 //println!("=== REFILL ===");
                 simulator.borrow_mut().add_input_budget();
             }
-            Err(e) => {
+            Poll::Ready(Err(e)) => {
                 eprintln!("{} error: {:?}", J::short_type_name(), e);
                 return;
             }
@@ -284,10 +305,11 @@ where
 fn bench_all<D>(data_left: Vec<D::Left>, data_right: Vec<D::Right>, definition: D)
 where
     D: InnerJoinPredicate + HashPredicate + MergePredicate + Clone,
-    D::Left: Clone + Debug,
-    D::Right: Clone + Debug,
+    D::Left: Clone + Debug + HeapSize,
+    D::Right: Clone + Debug + HeapSize,
     D::Output: Debug {
-    let memory = 10000;
+    let tuples = 10000;
+    let memory = tuples * mem::size_of::<D::Left>();
     println!("Index Algorithm TuplesIn DiskOut DiskIn PredicateCalls CmpCalls HashCalls");
     //bencher::<NestedLoopJoin<_, _, _>, _, _>(data_left.clone(), data_right.clone(), definition.clone(), ());
     //bencher::<BlockNestedLoopJoin<_, _, _>, _, _>(data_left.clone(), data_right.clone(), definition.clone(), memory);
@@ -296,15 +318,29 @@ where
     //bencher::<SimpleHashJoin<_, _, _>, _, _>(data_left.clone(), data_right.clone(), definition.clone(), memory);
     //bencher::<SymmetricHashJoin<_, _, _>, _, _>(data_left.clone(), data_right.clone(), definition.clone(), memory);
     bencher::<ProgressiveMergeJoin<_, _, _, _>, _, _>(data_left.clone(), data_right.clone(), definition.clone(), memory);
-    bencher::<XJoin<_, _, _, _>, _, _>(data_left.clone(), data_right.clone(), definition.clone(), memory);
+    bencher::<XJoin<_, _, _, _>, _, _>(data_left.clone(), data_right.clone(), definition.clone(), XJoinConfig {
+        memory_limit: memory,
+        num_partitions: tuples / 3,
+        pool: None,
+    });
     bencher::<HashMergeJoin<_, _, _, _, _>, _, _>(data_left.clone(), data_right.clone(), definition.clone(), hash_merge::HMJConfig {
         memory_limit: memory,
-        mem_parts_per_disk_part: memory / 20,
-        num_partitions: memory / 2,
+        mem_parts_per_disk_part: tuples / 20,
+        num_partitions: tuples / 2,
         fan_in: 256,
         flushing_policy: hash_merge::flush::Adaptive { a: 10, b: 0.25 },
+        pool: None,
+    });
+    bencher::<GraceHashJoin<_, _, _, _>, _, _>(data_left.clone(), data_right.clone(), definition.clone(), GraceConfig {
+        memory_limit: memory,
+        num_partitions: 64,
+        max_depth: 4,
+    });
+    bencher::<HybridHashJoin<_, _, _, _>, _, _>(data_left.clone(), data_right.clone(), definition.clone(), HybridConfig {
+        memory_limit: memory,
+        num_partitions: 64,
+        max_depth: 4,
     });
-    // TODO: hybrid hash join
 }
 
 fn main() {
@@ -319,7 +355,7 @@ fn main() {
     //let right_sorted: Vec<i32> = (10..20).chain(0..10).collect();
     
     let mut left_sorted: Vec<i32> = (0..1_000_000).collect();
-    let mut right_sorted: Vec<i32> = (0..1_00_000).collect();
+    let mut right_sorted: Vec<i32> = (0..100_000).collect();
     
     let mut rng = SmallRng::seed_from_u64(42);
     left_sorted.shuffle(&mut rng);
@@ -1,12 +1,12 @@
-use joins::{EquiJoin, JoinInMemory, SimpleHashJoin};
+use joins::{EquiJoin, HeapSize, JoinInMemory, SimpleHashJoin};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, HeapSize)]
 #[allow(unused)]
 struct User {
     id: u64,
     name: String,
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, HeapSize)]
 #[allow(unused)]
 struct Post {
     id: u64,
@@ -1,10 +1,10 @@
 use std::io;
 use std::marker::PhantomData;
 use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use bytes::Buf;
-use futures03::{StreamExt, TryFutureExt};
-use futures::{Poll, Stream, Future, try_ready};
-use futures03::compat::{Compat, Stream01CompatExt};
+use futures::{Future, Stream, StreamExt, ready};
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use tokio::fs::File;
@@ -12,15 +12,15 @@ use tokio_util::codec::{Decoder, FramedRead};
 use thiserror::Error;
 use tokio::io::AsyncSeekExt;
 use tokio::task::{JoinError, JoinHandle};
-use joins::{EquiJoin, Join, Rescan, SimpleHashJoin};
+use joins::{EquiJoin, HeapSize, Join, Rescan, SimpleHashJoin};
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, HeapSize)]
 #[allow(unused)]
 struct User {
     id: u64,
     name: String,
 }
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, HeapSize)]
 #[allow(unused)]
 struct Post {
     id: u64,
@@ -39,20 +39,21 @@ async fn main() {
         EquiJoin::new(|user: &User| user.id, |post: &Post| post.user_id),
         (),
         usize::MAX,
-    ).compat();
+    );
 
     while let Some(user) = joined.next().await {
         println!("{:?}", user.unwrap());
     }
 }
 
+#[allow(clippy::enum_variant_names)]
 enum Seeking {
     Invalid,
     NotSeeking(File),
-    Seeking(Compat<JoinHandle<io::Result<File>>>),
+    Seeking(JoinHandle<io::Result<File>>),
 }
 pub struct JsonStreamFile<T> {
-    stream: Compat<FramedRead<File, StreamingJson<T>>>,
+    stream: FramedRead<File, StreamingJson<T>>,
     seeking: Seeking,
 }
 impl<T: DeserializeOwned> JsonStreamFile<T> {
@@ -60,27 +61,30 @@ impl<T: DeserializeOwned> JsonStreamFile<T> {
         let file = File::open(path).await?;
         let file2 = file.try_clone().await?;
         Ok(JsonStreamFile {
-            stream: Compat::new(FramedRead::new(file, StreamingJson::new())),
+            stream: FramedRead::new(file, StreamingJson::new()),
             seeking: Seeking::NotSeeking(file2),
         })
     }
 }
 
 impl<T: DeserializeOwned> Stream for JsonStreamFile<T> {
-    type Item = T;
-    type Error = Error;
+    type Item = Result<T, Error>;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if let Seeking::Seeking(join_handle) = &mut self.seeking {
-            let file = try_ready!(join_handle.poll())?;
+            let file = match ready!(Pin::new(join_handle).poll(cx)) {
+                Ok(Ok(file)) => file,
+                Ok(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                Err(e) => return Poll::Ready(Some(Err(e.into()))),
+            };
             self.seeking = Seeking::NotSeeking(file);
         }
-        self.stream.poll()
+        self.stream.poll_next_unpin(cx)
     }
 }
 
 impl<T: DeserializeOwned> Rescan for JsonStreamFile<T> {
-    fn rescan(&mut self) {
+    fn rescan(mut self: Pin<&mut Self>) {
         if let Seeking::Seeking(_) = self.seeking {
             return;
         }
@@ -92,7 +96,7 @@ impl<T: DeserializeOwned> Rescan for JsonStreamFile<T> {
         let join_handle = tokio::spawn(async move {
             file.rewind().await?;
             Ok(file)
-        }).compat();
+        });
         self.seeking = Seeking::Seeking(join_handle);
     }
 }
@@ -110,7 +114,7 @@ pub enum Error {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct StreamingJson<T> {
-    _type: PhantomData<T>,
+    _type: PhantomData<fn() -> T>,
 }
 
 impl<T> StreamingJson<T> {
@@ -119,6 +123,12 @@ impl<T> StreamingJson<T> {
     }
 }
 
+impl<T> Default for StreamingJson<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: DeserializeOwned> Decoder for StreamingJson<T> {
     type Item = T;
     type Error = Error;
@@ -0,0 +1,25 @@
+//! Interoperability with futures 0.1.
+//!
+//! All joins are native `futures` 0.3 streams. Callers that still produce or consume
+//! futures 0.1 streams can convert in both directions:
+//!
+//! * A 0.1 input stream becomes a join input via `Stream01CompatExt::compat`.
+//!   If the join needs to rescan that input, implement the legacy `Rescan` trait
+//!   from this module for it.
+//! * A join becomes a 0.1 stream via `TryStreamExt::compat` (this requires the join to be `Unpin`).
+
+use std::pin::Pin;
+
+pub use futures::compat::{Compat, Compat01As03, Stream01CompatExt};
+pub use futures::TryStreamExt;
+
+/// The futures 0.1 version of `joins::Rescan`.
+pub trait Rescan: futures01::Stream {
+    fn rescan(&mut self);
+}
+
+impl<S: Rescan> crate::Rescan for Compat01As03<S> {
+    fn rescan(self: Pin<&mut Self>) {
+        self.get_mut().get_mut().rescan();
+    }
+}
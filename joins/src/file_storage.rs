@@ -0,0 +1,174 @@
+//! File-backed `ExternalStorage`.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use futures::future;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tempfile::TempPath;
+
+use crate::{AsyncExternalStorage, Blocking, External, ExternalStorage, Storage};
+
+/// Spills runs to temporary files.
+///
+/// Every run is serialized into its own file within the spill directory, which defaults to
+/// the system's temporary directory. The file is deleted once its `FileRun` is dropped.
+/// Serialization errors are reported as `io::ErrorKind::InvalidData`.
+#[derive(Clone, Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    /// Spills to the system's temporary directory.
+    pub fn new() -> Self {
+        FileStorage::in_dir(std::env::temp_dir())
+    }
+
+    /// Spills to `dir`, which must exist.
+    pub fn in_dir(dir: impl Into<PathBuf>) -> Self {
+        FileStorage { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Default for FileStorage {
+    fn default() -> Self {
+        FileStorage::new()
+    }
+}
+
+pub(crate) fn from_bincode(e: bincode::ErrorKind) -> io::Error {
+    match e {
+        bincode::ErrorKind::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+impl Storage for FileStorage {
+    type Error = io::Error;
+}
+impl<T: Serialize + DeserializeOwned> ExternalStorage<T> for FileStorage {
+    type External = FileRun<T>;
+
+    fn store(&mut self, tuples: Vec<T>) -> io::Result<FileRun<T>> {
+        let file = tempfile::Builder::new().prefix("joins-run-").tempfile_in(&self.dir)?;
+        let mut writer = BufWriter::new(file);
+        for tuple in &tuples {
+            bincode::serialize_into(&mut writer, tuple).map_err(|e| from_bincode(*e))?;
+        }
+        writer.flush()?;
+        let file = writer.into_inner().map_err(io::IntoInnerError::into_error)?;
+        Ok(FileRun { path: file.into_temp_path(), len: tuples.len(), _item: PhantomData })
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> AsyncExternalStorage<T> for FileStorage {
+    type External = Blocking<FileRun<T>>;
+    type Store = future::Ready<io::Result<Blocking<FileRun<T>>>>;
+
+    fn store(&mut self, tuples: Vec<T>) -> Self::Store {
+        future::ready(ExternalStorage::store(self, tuples).map(Blocking))
+    }
+}
+
+/// A run stored in a temporary file, which is deleted on drop.
+pub struct FileRun<T> {
+    path: TempPath,
+    len: usize,
+    _item: PhantomData<fn() -> T>,
+}
+
+impl<T> FileRun<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T: DeserializeOwned> External<T> for FileRun<T> {
+    type Error = io::Error;
+    type Iter = FileRunIter<T>;
+
+    fn fetch(&self) -> io::Result<FileRunIter<T>> {
+        let file = File::open(&self.path)?;
+        Ok(FileRunIter { reader: BufReader::new(file), remaining: self.len, _item: PhantomData })
+    }
+}
+
+/// Streams the tuples of a `FileRun` back from disk.
+///
+/// Iteration stops after the first error.
+pub struct FileRunIter<T> {
+    reader: BufReader<File>,
+    remaining: usize,
+    _item: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> Iterator for FileRunIter<T> {
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<io::Result<T>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let item = bincode::deserialize_from(&mut self.reader).map_err(|e| from_bincode(*e));
+        self.remaining = if item.is_ok() { self.remaining - 1 } else { 0 };
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::TryStreamExt;
+    use futures::executor::block_on;
+    use crate::{EquiJoin, External, ExternalStorage, FileStorage, IterSource, Join, JoinError, SortMergeJoin};
+
+    #[test]
+    fn file_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = FileStorage::in_dir(dir.path());
+        let run = storage.store(vec![(1u32, "a".to_string()), (2, "b".to_string())]).unwrap();
+        assert_eq!(1, std::fs::read_dir(dir.path()).unwrap().count());
+        let tuples: Vec<_> = run.fetch().unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(vec![(1, "a".to_string()), (2, "b".to_string())], tuples);
+        assert_eq!(2, run.fetch().unwrap().count());
+        drop(run);
+        assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+
+        let join = SortMergeJoin::build(
+            IterSource::new((0..100).rev()),
+            IterSource::new(50..150),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+            storage,
+            10,
+        );
+        let results: Vec<_> = block_on(join.try_collect()).unwrap();
+        assert_eq!((50..100).map(|x| (x, x)).collect::<Vec<_>>(), results);
+        assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn file_storage_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let join = SortMergeJoin::build(
+            IterSource::new(0..100u32),
+            IterSource::new(0..100u32),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+            FileStorage::in_dir(dir.path().join("missing")),
+            10,
+        );
+        match block_on(join.try_collect::<Vec<_>>()) {
+            Err(JoinError::Storage(e)) => assert_eq!(std::io::ErrorKind::NotFound, e.kind()),
+            x => panic!("expected a storage error, got {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Memory accounting for byte-denominated memory budgets.
+
+use std::collections::VecDeque;
+use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// A tuple that can report how much memory it occupies.
+///
+/// All memory limits are given in bytes. Joins account every tuple they hold with its
+/// `memory_size`, i.e. its inline size plus whatever it owns on the heap.
+///
+/// This is usually derived: `#[derive(HeapSize)]` sums up the heap sizes of all fields.
+///
+/// # Example
+///
+/// ```
+/// use joins::HeapSize;
+/// #[derive(HeapSize)]
+/// struct User { id: u32, name: String }
+///
+/// let user = User { id: 7, name: String::with_capacity(32) };
+/// assert_eq!(32, user.heap_size());
+/// assert_eq!(std::mem::size_of::<User>() + 32, user.memory_size());
+/// ```
+pub trait HeapSize {
+    /// Bytes owned by this value on the heap.
+    fn heap_size(&self) -> usize;
+
+    /// Total bytes occupied by this value.
+    fn memory_size(&self) -> usize {
+        mem::size_of_val(self) + self.heap_size()
+    }
+}
+
+macro_rules! no_heap {
+    ($($t:ty),*) => {
+        $(impl HeapSize for $t {
+            fn heap_size(&self) -> usize { 0 }
+        })*
+    };
+}
+no_heap!((), bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+/// Borrowed data isn't owned by the tuple, so it doesn't count.
+impl<T: ?Sized> HeapSize for &T {
+    fn heap_size(&self) -> usize { 0 }
+}
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * mem::size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+impl<T: HeapSize> HeapSize for VecDeque<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * mem::size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+impl<T: HeapSize + ?Sized> HeapSize for Box<T> {
+    fn heap_size(&self) -> usize {
+        (**self).memory_size()
+    }
+}
+/// Shared values are counted in full by every owner.
+impl<T: HeapSize + ?Sized> HeapSize for Rc<T> {
+    fn heap_size(&self) -> usize {
+        (**self).memory_size()
+    }
+}
+/// Shared values are counted in full by every owner.
+impl<T: HeapSize + ?Sized> HeapSize for Arc<T> {
+    fn heap_size(&self) -> usize {
+        (**self).memory_size()
+    }
+}
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size)
+    }
+}
+impl<T: HeapSize, E: HeapSize> HeapSize for Result<T, E> {
+    fn heap_size(&self) -> usize {
+        match self {
+            Ok(x) => x.heap_size(),
+            Err(e) => e.heap_size(),
+        }
+    }
+}
+impl<T: HeapSize, const N: usize> HeapSize for [T; N] {
+    fn heap_size(&self) -> usize {
+        self.iter().map(HeapSize::heap_size).sum()
+    }
+}
+
+macro_rules! tuple {
+    ($($name:ident)+) => {
+        impl<$($name: HeapSize),+> HeapSize for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn heap_size(&self) -> usize {
+                let ($($name,)+) = self;
+                0 $(+ $name.heap_size())+
+            }
+        }
+    };
+}
+tuple!(A);
+tuple!(A B);
+tuple!(A B C);
+tuple!(A B C D);
+tuple!(A B C D E);
+tuple!(A B C D E F);
+tuple!(A B C D E F G);
+tuple!(A B C D E F G H);
+tuple!(A B C D E F G H I);
+tuple!(A B C D E F G H I J);
+tuple!(A B C D E F G H I J K);
+tuple!(A B C D E F G H I J K L);
@@ -1,7 +1,13 @@
 use std::convert::Infallible;
-use futures::{Async, Poll, Stream};
-use crate::Rescan;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, future};
+use futures::task::noop_waker_ref;
+use pin_project::pin_project;
+use std::{iter, vec};
+use crate::{AsyncExternalStorage, Blocking, External, ExternalStorage, Rescan, Storage};
 
+#[pin_project]
 pub struct IterSource<I: Iterator + Clone> {
     saved: I,
     used: I,
@@ -18,31 +24,48 @@ impl<I: Iterator + Clone> IterSource<I> {
 }
 
 impl<I: Iterator + Clone> Stream for IterSource<I> {
-    type Item = I::Item;
-    type Error = Infallible;
+    type Item = Result<I::Item, Infallible>;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        Ok(Async::Ready(self.used.next()))
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.project().used.next().map(Ok))
     }
 }
 
 
 impl<I: Iterator + Clone> Rescan for IterSource<I> {
-    fn rescan(&mut self) {
-        self.used = self.saved.clone();
+    fn rescan(self: Pin<&mut Self>) {
+        let this = self.project();
+        *this.used = this.saved.clone();
     }
 }
 
 pub struct IterReady<S>(S);
 
-impl<S: Stream<Error = Infallible>> Iterator for IterReady<S> {
-    type Item = S::Item;
+impl<S: TryStream + Unpin> Iterator for IterReady<S> where S::Error: Into<Infallible> {
+    type Item = S::Ok;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.0.poll() {
-            Ok(Async::Ready(e)) => e,
-            Ok(Async::NotReady) => None,
-            Err(x) => match x {},
+        let mut cx = Context::from_waker(noop_waker_ref());
+        match Pin::new(&mut self.0).try_poll_next(&mut cx) {
+            Poll::Ready(Some(Ok(e))) => Some(e),
+            Poll::Ready(None) | Poll::Pending => None,
+            #[allow(unreachable_code)] // `into` already diverges
+            Poll::Ready(Some(Err(x))) => match x.into() {},
+        }
+    }
+}
+
+/// Like `IterReady`, but for streams that may fail.
+pub(crate) struct TryIterReady<S>(pub(crate) S);
+
+impl<S: TryStream + Unpin> Iterator for TryIterReady<S> {
+    type Item = Result<S::Ok, S::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        match Pin::new(&mut self.0).try_poll_next(&mut cx) {
+            Poll::Ready(item) => item,
+            Poll::Pending => None,
         }
     }
 }
@@ -50,8 +73,33 @@ impl<S: Stream<Error = Infallible>> Iterator for IterReady<S> {
 pub trait IntoIterReady {
     fn iter_ready(self) -> IterReady<Self> where Self: Sized;
 }
-impl<S: Stream<Error = Infallible>> IntoIterReady for S {
+impl<S: TryStream + Unpin> IntoIterReady for S where S::Error: Into<Infallible> {
     fn iter_ready(self) -> IterReady<Self> {
         IterReady(self)
     }
 }
+
+/// Keeps "external" runs in memory, e.g. for joins built using `JoinInMemory`.
+impl Storage for () {
+    type Error = Infallible;
+}
+impl<T: Clone> ExternalStorage<T> for () {
+    type External = Vec<T>;
+    fn store(&mut self, tuples: Vec<T>) -> Result<Vec<T>, Infallible> {
+        Ok(tuples)
+    }
+}
+impl<T: Clone> AsyncExternalStorage<T> for () {
+    type External = Blocking<Vec<T>>;
+    type Store = future::Ready<Result<Blocking<Vec<T>>, Infallible>>;
+    fn store(&mut self, tuples: Vec<T>) -> Self::Store {
+        future::ready(Ok(Blocking(tuples)))
+    }
+}
+impl<T: Clone> External<T> for Vec<T> {
+    type Error = Infallible;
+    type Iter = iter::Map<vec::IntoIter<T>, fn(T) -> Result<T, Infallible>>;
+    fn fetch(&self) -> Result<Self::Iter, Infallible> {
+        Ok(self.clone().into_iter().map(Ok))
+    }
+}
@@ -1,43 +1,55 @@
 use std::collections::VecDeque;
-use futures::{Stream, Poll, try_ready, Async, stream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
 use named_type::NamedType;
 use named_type_derive::*;
-use crate::InnerJoinPredicate;
+use pin_project::pin_project;
+use crate::{HeapSize, InnerJoinPredicate};
 
 use super::{Join, Rescan};
 
+#[pin_project]
 #[derive(NamedType)]
-pub struct BlockNestedLoopJoin<L: Stream, R: Stream, D: InnerJoinPredicate> {
-    left: stream::Fuse<L>,
+pub struct BlockNestedLoopJoin<L: TryStream, R: TryStream, D: InnerJoinPredicate> {
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
     right: R,
     definition: D,
-    buffer: Vec<L::Item>,
+    buffer: Vec<L::Ok>,
+    // bytes held by the buffer
+    buffer_size: usize,
+    memory_limit: usize,
     output_buffer: VecDeque<D::Output>,
 }
 
 impl<L, R, D> Stream for BlockNestedLoopJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error> + Rescan,
-          D: InnerJoinPredicate<Left=L::Item, Right=R::Item> {
-    type Item = D::Output;
-    type Error = L::Error;
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: InnerJoinPredicate<Left=L::Ok, Right=R::Ok> {
+    type Item = Result<D::Output, L::Error>;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
         loop {
-            if let Some(out) = self.output_buffer.pop_front() {
-                return Ok(Async::Ready(Some(out)));
-            } else if (self.buffer.len() < self.buffer.capacity()) && !self.left.is_done() {
-                if let Some(left) =  try_ready!(self.left.poll()) {
-                    self.buffer.push(left);
+            if let Some(out) = this.output_buffer.pop_front() {
+                return Poll::Ready(Some(Ok(out)));
+            } else if (*this.buffer_size < *this.memory_limit) && !this.left.is_done() {
+                if let Some(left) = ready!(this.left.as_mut().try_poll_next(cx)?) {
+                    *this.buffer_size += left.memory_size();
+                    this.buffer.push(left);
                 }
-            } else if let Some(right) = try_ready!(self.right.poll()) {
-                let definition = &self.definition;
-                self.output_buffer.extend(self.buffer.iter().filter_map(|l| definition.eq(l, &right)));
-            } else if self.left.is_done() {
-                return Ok(Async::Ready(None));
+            } else if let Some(right) = ready!(this.right.as_mut().try_poll_next(cx)?) {
+                let definition = &*this.definition;
+                this.output_buffer.extend(this.buffer.iter().filter_map(|l| definition.eq(l, &right)));
+            } else if this.left.is_done() {
+                return Poll::Ready(None);
             } else {
-                self.buffer.clear();
-                self.right.rescan();
+                this.buffer.clear();
+                *this.buffer_size = 0;
+                this.right.as_mut().rescan();
             }
         }
     }
@@ -45,25 +57,27 @@ impl<L, R, D> Stream for BlockNestedLoopJoin<L, R, D>
 /*
 TODO: need to reset Fuse somehow
 impl<L, R, D> Rescan for BlockNestedLoopJoin<L, R, D>
-    where L: Stream + Rescan,
-          R: Stream<Error=L::Error> + Rescan,
-          D: InnerJoinPredicate<Left=L::Item, Right=R::Item> {
-    fn rescan(&mut self) {
-        self.left.rescan();
-        self.right.rescan();
-        self.buffer.clear();
-        self.output_buffer.clear();
+    where L: TryStream + Rescan,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: InnerJoinPredicate<Left=L::Ok, Right=R::Ok> {
+    fn rescan(self: Pin<&mut Self>) {
+        let this = self.project();
+        this.left.rescan();
+        this.right.rescan();
+        this.buffer.clear();
+        this.output_buffer.clear();
     }
 }
 */
 
 
 impl<L, R, D, E> Join<L, R, D, E, usize> for BlockNestedLoopJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error> + Rescan,
-          D: InnerJoinPredicate<Left=L::Item, Right=R::Item> {
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: InnerJoinPredicate<Left=L::Ok, Right=R::Ok> {
     fn build(left: L, right: R, definition: D, _: E, memory_size: usize) -> Self {
-        BlockNestedLoopJoin { left: left.fuse(), right, definition, buffer: Vec::with_capacity(memory_size), output_buffer: VecDeque::new() }
+        BlockNestedLoopJoin { left: left.into_stream().fuse(), right, definition, buffer: Vec::new(), buffer_size: 0, memory_limit: memory_size, output_buffer: VecDeque::new() }
     }
 }
 
@@ -0,0 +1,452 @@
+use std::mem;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, stream};
+use multimap::MultiMap;
+use named_type::NamedType;
+use named_type_derive::*;
+use pin_project::pin_project;
+use crate::{HeapSize, InnerJoinPredicate};
+
+use super::{Join, JoinError, ExternalStorage, External, Storage};
+use crate::predicate::{HashPredicate, mix_hash};
+
+/// Configuration of a `GraceHashJoin`.
+#[derive(Clone, Copy, Debug)]
+pub struct GraceConfig {
+    /// Maximum size in bytes of the tuples (of both inputs) that are held in memory at once.
+    pub memory_limit: usize,
+    /// Number of partitions an input (or an overflowing partition) is split into.
+    pub num_partitions: usize,
+    /// How often an overflowing partition may be re-partitioned before it is
+    /// joined block by block instead.
+    pub max_depth: usize,
+}
+
+/// A blocking hash join that partitions both inputs into `ExternalStorage` runs.
+///
+/// Once both inputs are exhausted, matching partitions are joined pairwise. A partition whose
+/// left side does not fit into memory is re-partitioned with a different hash seed.
+/// If that doesn't help (e.g. because all tuples share the same hash value) or `max_depth` is
+/// reached, the partition is joined by repeatedly building a table from a memory-full of left
+/// tuples and scanning the right side of the partition.
+#[pin_project]
+#[derive(NamedType)]
+pub struct GraceHashJoin<L, R, D, E>
+    where
+        L: TryStream,
+        R: TryStream,
+        D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok>,
+        E: ExternalStorage<L::Ok> + ExternalStorage<R::Ok> {
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Fuse<stream::IntoStream<R>>,
+    definition: D,
+    storage: E,
+    config: GraceConfig,
+    state: State<L::Ok, R::Ok, E>,
+    output_buffer: VecDeque<D::Output>,
+}
+
+enum State<L, R, E: ExternalStorage<L> + ExternalStorage<R>> {
+    PartitionPhase {
+        left: Partitioner<L, E>,
+        right: Partitioner<R, E>,
+    },
+    JoinPhase(PartitionJoin<L, R, E>),
+    Tmp,
+}
+
+/// Maps a hash value to one of `partitions` partitions.
+///
+/// Different seeds yield independent partitionings of the same hash values.
+pub(crate) fn partition_index(hash: u64, seed: u64, partitions: usize) -> usize {
+    (mix_hash(hash, seed) % partitions as u64) as usize
+}
+
+pub(crate) struct Partition<L, R, E: ExternalStorage<L> + ExternalStorage<R>> {
+    pub(crate) left: Vec<<E as ExternalStorage<L>>::External>,
+    pub(crate) right: Vec<<E as ExternalStorage<R>>::External>,
+    pub(crate) left_len: usize,
+    pub(crate) right_len: usize,
+    pub(crate) left_size: usize,
+    pub(crate) depth: usize,
+}
+
+/// Runs, tuple count and size in bytes of a partition of one input.
+type Finished<X> = (Vec<X>, usize, usize);
+
+/// Distributes tuples of one input into partition buffers that are spilled as runs.
+pub(crate) struct Partitioner<T, E: ExternalStorage<T>> {
+    buffers: Vec<Vec<T>>,
+    runs: Vec<Vec<E::External>>,
+    lens: Vec<usize>,
+    // bytes added to each partition so far
+    sizes: Vec<usize>,
+    // bytes held by each buffer
+    buffer_sizes: Vec<usize>,
+    buffered: usize,
+    seed: u64,
+}
+impl<T: HeapSize, E: ExternalStorage<T>> Partitioner<T, E> {
+    pub(crate) fn new(num_partitions: usize, seed: u64) -> Self {
+        let mut x = Partitioner {
+            buffers: Vec::new(),
+            runs: Vec::new(),
+            lens: vec![0; num_partitions],
+            sizes: vec![0; num_partitions],
+            buffer_sizes: vec![0; num_partitions],
+            buffered: 0,
+            seed,
+        };
+        x.buffers.resize_with(num_partitions, Default::default);
+        x.runs.resize_with(num_partitions, Default::default);
+        x
+    }
+    pub(crate) fn partition_of(&self, hash: u64) -> usize {
+        partition_index(hash, self.seed, self.buffers.len())
+    }
+    pub(crate) fn insert(&mut self, hash: u64, item: T) {
+        let partition = self.partition_of(hash);
+        self.push(partition, item);
+    }
+    pub(crate) fn push(&mut self, partition: usize, item: T) {
+        let size = item.memory_size();
+        self.buffers[partition].push(item);
+        self.lens[partition] += 1;
+        self.sizes[partition] += size;
+        self.buffer_sizes[partition] += size;
+        self.buffered += size;
+    }
+    /// Number of tuples that have been added to `partition` so far.
+    pub(crate) fn len(&self, partition: usize) -> usize {
+        self.lens[partition]
+    }
+    /// Bytes currently held by all buffers.
+    pub(crate) fn buffered(&self) -> usize {
+        self.buffered
+    }
+    /// Returns `(buffered bytes, partition)` of the largest buffer.
+    pub(crate) fn largest(&self) -> (usize, usize) {
+        self.buffer_sizes.iter().copied().enumerate().map(|(i, b)| (b, i)).max().unwrap_or((0, 0))
+    }
+    pub(crate) fn flush(&mut self, partition: usize, storage: &mut E) -> Result<(), E::Error> {
+        let buffer = mem::take(&mut self.buffers[partition]);
+        if !buffer.is_empty() {
+            self.buffered -= mem::take(&mut self.buffer_sizes[partition]);
+            self.runs[partition].push(storage.store(buffer)?);
+        }
+        Ok(())
+    }
+    pub(crate) fn flush_all(&mut self, storage: &mut E) -> Result<(), E::Error> {
+        for i in 0..self.buffers.len() {
+            self.flush(i, storage)?;
+        }
+        Ok(())
+    }
+    /// Flushes all buffers, returning the runs, tuple count and size of every partition.
+    pub(crate) fn finish(mut self, storage: &mut E) -> Result<impl Iterator<Item=Finished<E::External>>, E::Error> {
+        self.flush_all(storage)?;
+        Ok(self.runs.into_iter().zip(self.lens).zip(self.sizes).map(|((runs, len), size)| (runs, len, size)))
+    }
+}
+
+/// Spills the largest partition buffers until the buffered tuples fit into `memory_limit` bytes.
+pub(crate) fn spill<L, R, E>(left: &mut Partitioner<L, E>, right: &mut Partitioner<R, E>, storage: &mut E, memory_limit: usize) -> Result<(), <E as Storage>::Error>
+    where
+        L: HeapSize,
+        R: HeapSize,
+        E: ExternalStorage<L> + ExternalStorage<R> {
+    while left.buffered() + right.buffered() > memory_limit {
+        let (l, l_index) = left.largest();
+        let (r, r_index) = right.largest();
+        if l >= r {
+            left.flush(l_index, storage)?;
+        } else {
+            right.flush(r_index, storage)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn finish_partitions<L, R, E>(left: Partitioner<L, E>, right: Partitioner<R, E>, storage: &mut E, depth: usize) -> Result<Vec<Partition<L, R, E>>, <E as Storage>::Error>
+    where
+        L: HeapSize,
+        R: HeapSize,
+        E: ExternalStorage<L> + ExternalStorage<R> {
+    let left: Vec<_> = left.finish(storage)?.collect();
+    let right: Vec<_> = right.finish(storage)?.collect();
+    Ok(left.into_iter().zip(right)
+        .map(|((left, left_len, left_size), (right, right_len, _))| Partition { left, right, left_len, right_len, left_size, depth })
+        .collect())
+}
+
+/// Splits an overflowing partition using the seeded hash functions of the next recursion level.
+pub(crate) fn repartition<L, R, D, E>(partition: Partition<L, R, E>, definition: &D, storage: &mut E, config: &GraceConfig) -> Result<Vec<Partition<L, R, E>>, <E as Storage>::Error>
+    where
+        L: HeapSize,
+        R: HeapSize,
+        D: HashPredicate<Left=L, Right=R>,
+        E: ExternalStorage<L> + ExternalStorage<R> {
+    let depth = partition.depth + 1;
+    let left_len = partition.left_len;
+    let mut left = Partitioner::new(config.num_partitions, depth as u64);
+    let mut right = Partitioner::new(config.num_partitions, depth as u64);
+    for l in Runs::new(partition.left) {
+        let l = l?;
+        left.insert(definition.hash_left_seeded(&l, depth as u64), l);
+        spill(&mut left, &mut right, storage, config.memory_limit)?;
+    }
+    for r in Runs::new(partition.right) {
+        let r = r?;
+        right.insert(definition.hash_right_seeded(&r, depth as u64), r);
+        spill(&mut left, &mut right, storage, config.memory_limit)?;
+    }
+    let mut children = finish_partitions(left, right, storage, depth)?;
+    for child in &mut children {
+        if child.left_len == left_len {
+            // nothing was split off, so further re-partitioning is pointless
+            child.depth = config.max_depth;
+        }
+    }
+    Ok(children)
+}
+
+/// Iterates over a sequence of runs, fetching them one by one.
+pub(crate) struct Runs<T, X: External<T>> {
+    runs: Vec<X>,
+    next_run: usize,
+    current: Option<X::Iter>,
+    _item: PhantomData<fn() -> T>,
+}
+impl<T, X: External<T>> Runs<T, X> {
+    pub(crate) fn new(runs: Vec<X>) -> Self {
+        Runs { runs, next_run: 0, current: None, _item: PhantomData }
+    }
+    pub(crate) fn rewind(&mut self) {
+        self.next_run = 0;
+        self.current = None;
+    }
+}
+impl<T, X: External<T>> Iterator for Runs<T, X> {
+    type Item = Result<T, X::Error>;
+
+    fn next(&mut self) -> Option<Result<T, X::Error>> {
+        loop {
+            if let Some(item) = self.current.as_mut().and_then(Iterator::next) {
+                return Some(item);
+            }
+            let run = self.runs.get(self.next_run)?;
+            self.next_run += 1;
+            match run.fetch() {
+                Ok(iter) => self.current = Some(iter),
+                Err(e) => {
+                    self.current = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Joins spilled partitions pair by pair.
+pub(crate) struct PartitionJoin<L, R, E: ExternalStorage<L> + ExternalStorage<R>> {
+    pending: Vec<Partition<L, R, E>>,
+    probe: Option<Probe<L, R, E>>,
+}
+impl<L: HeapSize, R: HeapSize, E: ExternalStorage<L> + ExternalStorage<R>> PartitionJoin<L, R, E> {
+    pub(crate) fn new(pending: Vec<Partition<L, R, E>>) -> Self {
+        PartitionJoin { pending, probe: None }
+    }
+    /// Advances the join by one step, returning `false` once all partitions are joined.
+    pub(crate) fn step<D, O>(&mut self, definition: &D, storage: &mut E, config: &GraceConfig, output: &mut O) -> Result<bool, <E as Storage>::Error>
+        where
+            D: InnerJoinPredicate + HashPredicate<Left=L, Right=R>,
+            O: Extend<D::Output> {
+        if let Some(p) = &mut self.probe {
+            if !p.probe(definition, output)? && !p.build(definition, config.memory_limit)? {
+                // partition complete
+                self.probe = None;
+            }
+        } else if let Some(partition) = self.pending.pop() {
+            if partition.left_len == 0 || partition.right_len == 0 {
+                return Ok(true);
+            }
+            if partition.left_size <= config.memory_limit || partition.depth >= config.max_depth {
+                let mut p = Probe::new(partition);
+                p.build(definition, config.memory_limit)?;
+                self.probe = Some(p);
+            } else {
+                self.pending.extend(repartition(partition, definition, storage, config)?);
+            }
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+/// Joins a single partition by building on memory-fulls of its left side.
+pub(crate) struct Probe<L, R, E: ExternalStorage<L> + ExternalStorage<R>> {
+    table: MultiMap<u64, L>,
+    left: Runs<L, <E as ExternalStorage<L>>::External>,
+    right: Runs<R, <E as ExternalStorage<R>>::External>,
+}
+impl<L: HeapSize, R, E: ExternalStorage<L> + ExternalStorage<R>> Probe<L, R, E> {
+    pub(crate) fn new(partition: Partition<L, R, E>) -> Self {
+        Probe {
+            table: MultiMap::new(),
+            left: Runs::new(partition.left),
+            right: Runs::new(partition.right),
+        }
+    }
+    /// Replaces the table by the next memory-full of left tuples.
+    ///
+    /// The table always holds at least one tuple, even if it alone exceeds `memory_limit` bytes.
+    /// Returns `false` if the left side is exhausted.
+    pub(crate) fn build<D: HashPredicate<Left=L>>(&mut self, definition: &D, memory_limit: usize) -> Result<bool, <E as Storage>::Error> {
+        self.table.clear();
+        let mut size = 0;
+        while size < memory_limit {
+            let Some(l) = self.left.next().transpose()? else { break };
+            size += l.memory_size();
+            self.table.insert(definition.hash_left(&l), l);
+        }
+        self.right.rewind();
+        Ok(!self.table.is_empty())
+    }
+    /// Probes the next right tuple, returning `false` once the right side is exhausted.
+    pub(crate) fn probe<D, O>(&mut self, definition: &D, output: &mut O) -> Result<bool, <E as Storage>::Error>
+        where
+            D: InnerJoinPredicate + HashPredicate<Left=L, Right=R>,
+            O: Extend<D::Output> {
+        match self.right.next().transpose()? {
+            Some(right) => {
+                output.extend(
+                    self.table.get_vec(&definition.hash_right(&right)).into_iter()
+                        .flatten().filter_map(|left| definition.eq(left, &right)));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl<L, R, D, E> Stream for GraceHashJoin<L, R, D, E>
+    where
+        L: TryStream,
+        L::Ok: HeapSize,
+        R: TryStream<Error=L::Error>,
+        R::Ok: HeapSize,
+        D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok>,
+        E: ExternalStorage<L::Ok> + ExternalStorage<R::Ok> {
+    type Item = Result<D::Output, JoinError<L::Error, <E as Storage>::Error>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            if let Some(buffered) = this.output_buffer.pop_front() {
+                return Poll::Ready(Some(Ok(buffered)));
+            }
+            match this.state {
+                State::PartitionPhase { left, right } => {
+                    let l = this.left.as_mut().try_poll_next(cx)?;
+                    let r = this.right.as_mut().try_poll_next(cx)?;
+
+                    match (l, r) {
+                        (Poll::Ready(None), Poll::Ready(None)) => {
+                            // fall out of the match in order to replace the state
+                        }
+                        (Poll::Pending, Poll::Pending) | (Poll::Ready(None), Poll::Pending) | (Poll::Pending, Poll::Ready(None)) => return Poll::Pending,
+                        (l, r) => {
+                            if let Poll::Ready(Some(l)) = l {
+                                left.insert(this.definition.hash_left(&l), l);
+                            }
+                            if let Poll::Ready(Some(r)) = r {
+                                right.insert(this.definition.hash_right(&r), r);
+                            }
+                            spill(left, right, this.storage, this.config.memory_limit).map_err(JoinError::Storage)?;
+                            continue;
+                        }
+                    }
+
+                    if let State::PartitionPhase { left, right } = mem::replace(this.state, State::Tmp) {
+                        let pending = finish_partitions(left, right, this.storage, 0).map_err(JoinError::Storage)?;
+                        *this.state = State::JoinPhase(PartitionJoin::new(pending));
+                    }
+                }
+                State::JoinPhase(partitions) => {
+                    if !partitions.step(&*this.definition, this.storage, this.config, this.output_buffer).map_err(JoinError::Storage)? {
+                        return Poll::Ready(None);
+                    }
+                }
+                // only left behind if spilling the partitions failed
+                State::Tmp => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<L, R, D, E> Join<L, R, D, E, GraceConfig> for GraceHashJoin<L, R, D, E>
+    where
+        L: TryStream,
+        L::Ok: HeapSize,
+        R: TryStream<Error=L::Error>,
+        R::Ok: HeapSize,
+        D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok>,
+        E: ExternalStorage<L::Ok> + ExternalStorage<R::Ok> {
+    fn build(left: L, right: R, definition: D, storage: E, config: GraceConfig) -> Self {
+        assert!(config.num_partitions > 1);
+        assert!(config.memory_limit > 0);
+
+        GraceHashJoin {
+            left: left.into_stream().fuse(),
+            right: right.into_stream().fuse(),
+            state: State::PartitionPhase {
+                left: Partitioner::new(config.num_partitions, 0),
+                right: Partitioner::new(config.num_partitions, 0),
+            },
+            definition,
+            storage,
+            config,
+            output_buffer: VecDeque::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+    use crate::{EquiJoin, GraceConfig, GraceHashJoin, JoinInMemory};
+
+    fn grace_join(left: Vec<i32>, right: Vec<i32>, memory_limit: usize) -> Vec<(i32, i32)> {
+        let join = GraceHashJoin::build_in_memory(
+            left,
+            right,
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            GraceConfig { memory_limit, num_partitions: 4, max_depth: 3 },
+        );
+        let mut results: Vec<_> = join.collect();
+        results.sort_unstable();
+        results
+    }
+
+    #[test]
+    fn grace_hash() {
+        let left: Vec<_> = (0..1000).map(|x| x % 300).collect();
+        let right: Vec<_> = (0..500).rev().collect();
+        let mut expected: Vec<_> = left.iter().flat_map(|&l| right.iter().filter(move |&&r| l == r).map(move |&r| (l, r))).collect();
+        expected.sort_unstable();
+        assert_eq!(expected, grace_join(left, right, 16 * mem::size_of::<i32>()));
+    }
+
+    #[test]
+    fn grace_hash_skew() {
+        // a single key can't be split by re-partitioning
+        let results = grace_join(vec![7; 100], vec![7; 10], 8 * mem::size_of::<i32>());
+        assert_eq!(vec![(7, 7); 1000], results);
+    }
+}
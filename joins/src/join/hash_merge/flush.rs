@@ -1,4 +1,3 @@
-use std::usize;
 use itertools::{Itertools, MinMaxResult};
 use either::Either;
 
@@ -59,7 +58,7 @@ impl Adaptive {
 fn sum_tuples<'a, I: IntoIterator<Item=&'a PartitionStats>>(iter: I) -> (usize, usize) {
     iter.into_iter().fold((0, 0), |(l, r), &PartitionStats { left, right }| (l + left, r + right))
 }
-fn try_filter<I: Iterator + Clone, P: for<'a> Fn(&'a I::Item) -> bool>(iterator: I, predicate: P) -> impl Iterator<Item=I::Item> + Clone where I::Item: Clone, P: Clone {
+fn try_filter<I: Iterator + Clone, P: for<'a> Fn(&'a I::Item) -> bool + Clone>(iterator: I, predicate: P) -> impl Iterator<Item=I::Item> + Clone where I::Item: Clone {
     let mut peek = iterator.clone().filter(predicate).peekable();
     if peek.peek().is_some() {
         Either::Left(peek)
@@ -1,81 +1,102 @@
 use std::{cmp, mem};
-use std::rc::Rc;
+use std::sync::Arc;
 use std::collections::VecDeque;
-use futures::{Stream, Poll, Async, stream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Future, Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
 use named_type::NamedType;
 use named_type_derive::*;
+use pin_project::pin_project;
 use itertools::Itertools;
-use crate::InnerJoinPredicate;
+use crate::{HeapSize, InnerJoinPredicate, MemoryPool, MemoryReservation};
 
-use super::{Join, ExternalStorage, OrderedMergeJoin};
-use super::sort_merge::SortMerger;
-use super::progressive_merge::IgnoreIndexPredicate;
-use crate::predicate::{JoinPredicate, HashPredicate, MergePredicate, SwapPredicate};
+use super::{Join, JoinError, AsyncExternalStorage, Storage};
+use super::progressive_merge::{MergePass, Run};
+use super::sort_merge::ChainedRun;
+use crate::predicate::{JoinPredicate, HashPredicate, MergePredicate};
 
 pub mod flush;
 use self::flush::{FlushingPolicy, PartitionStats};
-use crate::value_skimmer::{ValueSink, ValueSinkRecv};
 
+#[pin_project]
 #[derive(NamedType)]
 pub struct HashMergeJoin<L, R, D, E, F>
     where
-        L: Stream,
-        R: Stream,
-        D: InnerJoinPredicate + MergePredicate<Left=L::Item, Right=R::Item>,
-        E: ExternalStorage<L::Item> + ExternalStorage<R::Item> {
-    left: stream::Fuse<L>,
-    right: stream::Fuse<R>,
-    parts_l: Partitions<L::Item, E>,
-    parts_r: Partitions<R::Item, E>,
-    definition: Rc<D>,
+        L: TryStream,
+        R: TryStream,
+        D: InnerJoinPredicate + MergePredicate<Left=L::Ok, Right=R::Ok>,
+        E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Fuse<stream::IntoStream<R>>,
+    parts_l: Partitions<L::Ok, E>,
+    parts_r: Partitions<R::Ok, E>,
+    definition: Arc<D>,
     common: Common<D::Output, E, F>,
 
     // is there currently a merge going on?
-    merge: Option<MergePhase<L, R, D, E>>,
+    merge: Option<MergePhase<D, E>>,
 }
-type Merger<D, E> = ValueSink<SortMerger<D, <E as ExternalStorage<<D as JoinPredicate>::Left>>::External>>;
 
-pub struct MergePhase<L, R, D, E>
+pub struct MergePhase<D, E>
     where
-        L: Stream,
-        R: Stream,
-        D: InnerJoinPredicate + MergePredicate<Left=L::Item, Right=R::Item>,
-        E: ExternalStorage<L::Item> + ExternalStorage<R::Item> {
-    omj: OrderedMergeJoin<Merger<Rc<D>, E>, Merger<SwapPredicate<Rc<D>>, E>, IgnoreIndexPredicate<Rc<D>>>,
-    recv_left: ValueSinkRecv<(usize, L::Item), ()>,
-    recv_right: ValueSinkRecv<(usize, R::Item), ()>,
+        D: InnerJoinPredicate + MergePredicate,
+        E: AsyncExternalStorage<D::Left> + AsyncExternalStorage<D::Right> {
+    pass: MergePass<D, E>,
     disk_partition: usize,
 }
 
 struct Common<O, E, F> {
     storage: E,
-    total_inmemory: usize,
+    // bytes held by all in-memory partitions
+    inmemory_size: usize,
+    reservation: Option<MemoryReservation>,
     config: HMJConfig<F>,
     output_buffer: VecDeque<O>,
 }
 
-struct Partitions<T, E: ExternalStorage<T>> {
+struct Partitions<T, E: AsyncExternalStorage<T>> {
     mem: Vec<Vec<T>>,
     in_memory_tuples: Vec<usize>,
-    disk: Vec<Vec<E::External>>,
+    disk: Vec<Vec<Run<T, E>>>,
+    // runs on their way to the disk partitions, in the order they were evicted
+    stores: VecDeque<(usize, Pin<Box<E::Store>>)>,
 }
-impl<T, E: ExternalStorage<T>> Partitions<T, E> {
+impl<T: HeapSize, E: AsyncExternalStorage<T>> Partitions<T, E> {
     fn new<F>(config: &HMJConfig<F>) -> Self {
-       let mut x = Partitions { mem: Vec::new(), disk: Vec::new(), in_memory_tuples: vec![0; config.num_partitions / config.mem_parts_per_disk_part] };
+       let mut x = Partitions { mem: Vec::new(), disk: Vec::new(), in_memory_tuples: vec![0; config.num_partitions / config.mem_parts_per_disk_part], stores: VecDeque::new() };
        x.mem.resize_with(config.num_partitions, Default::default);
        x.disk.resize_with(config.num_partitions / config.mem_parts_per_disk_part, Default::default);
        x
     }
+    fn store(&mut self, disk_partition: usize, tuples: Vec<T>, storage: &mut E) {
+        self.stores.push_back((disk_partition, Box::pin(storage.store(tuples))));
+    }
+
+    /// Moves the stored runs to their disk partitions.
+    ///
+    /// Left and right runs are evicted in pairs, so completing the stores in order keeps the
+    /// runs of both sides of a partition at the same indices.
+    fn poll_stores(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), E::Error>> {
+        while let Some((disk_partition, store)) = self.stores.front_mut() {
+            let run = ready!(store.as_mut().poll(cx))?;
+            self.disk[*disk_partition].push(ChainedRun::new(vec![run]));
+            self.stores.pop_front();
+        }
+        Poll::Ready(Ok(()))
+    }
+
     fn evict<D: InnerJoinPredicate + MergePredicate<Left=T>, F: FlushingPolicy>(&mut self, partition_to_evict: usize, definition: &D, common: &mut Common<D::Output, E, F>) {
         let mut eviction: Vec<_> = self.mem.iter_mut().enumerate()
             .filter(|(i, _)| (i / common.config.mem_parts_per_disk_part) == partition_to_evict)
-            .flat_map(|(_, x)| mem::replace(x, Vec::new())).collect();
+            .flat_map(|(_, x)| mem::take(x)).collect();
         //println!("evicting {} tuples", eviction.len());
         eviction.sort_by(|a, b| definition.cmp_left(a, b));
-        common.total_inmemory -= eviction.len();
+        common.inmemory_size -= eviction.iter().map(HeapSize::memory_size).sum::<usize>();
         self.in_memory_tuples[partition_to_evict] -= eviction.len();
         assert_eq!(0, self.in_memory_tuples[partition_to_evict]);
-        self.disk[partition_to_evict].push(common.storage.store(eviction));
+        self.store(partition_to_evict, eviction, &mut common.storage);
     }
 
     fn insert<D, F: FlushingPolicy>(
@@ -86,36 +107,25 @@ impl<T, E: ExternalStorage<T>> Partitions<T, E> {
         common: &mut Common<D::Output, E, F>)
     where
         D: InnerJoinPredicate + MergePredicate + HashPredicate<Left=T>,
-        E: ExternalStorage<D::Right>
+        E: AsyncExternalStorage<D::Right>
     {
         let hash = (definition.hash_left(&item) % (self.mem.len() as u64)) as usize;
         //println!("hash({:?}) = {}", item.debug(), hash);
         //println!("disks = {}", self.disk.len());
         common.output_buffer.extend(other.mem[hash].iter().filter_map(|x| definition.eq(&item, x)));
+        common.inmemory_size += item.memory_size();
         self.mem[hash].push(item);
-        common.total_inmemory += 1;
         self.in_memory_tuples[hash / common.config.mem_parts_per_disk_part] += 1;
     }
 }
 
 impl<L, R, D, E, F> HashMergeJoin<L, R, D, E, F>
     where
-        L: Stream,
-        R: Stream<Error=L::Error>,
-        D: InnerJoinPredicate + HashPredicate<Left=L::Item, Right=R::Item> + MergePredicate,
+        L: TryStream,
+        R: TryStream<Error=L::Error>,
+        D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok> + MergePredicate,
         F: FlushingPolicy,
-        E: ExternalStorage<L::Item> + ExternalStorage<R::Item> {
-    fn check_eviction(&mut self) {
-        if self.common.total_inmemory >= self.common.config.memory_limit {
-            // if out of space, go evict
-            let memory_table: Vec<_> = self.parts_l.in_memory_tuples.iter().zip(&self.parts_r.in_memory_tuples)
-                .map(|(l, r)| PartitionStats { left: *l, right: *r }).collect();
-            let partition_to_evict = self.common.config.flushing_policy.flush(&memory_table); // FIXME dont hardcode
-            //println!("EVICTING {} because of {:?}", partition_to_evict, memory_table);
-            self.parts_l.evict(partition_to_evict, &self.definition, &mut self.common);
-            self.parts_r.evict(partition_to_evict, &self.definition.by_ref().swap(), &mut self.common);
-        }
-    }
+        E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
 
     #[cfg(feature = "debug")]
     #[allow(dead_code)]
@@ -132,60 +142,97 @@ impl<L, R, D, E, F> HashMergeJoin<L, R, D, E, F>
         }
     }
 }
+fn check_eviction<L, R, D, E, F>(parts_l: &mut Partitions<L, E>, parts_r: &mut Partitions<R, E>, definition: &D, common: &mut Common<D::Output, E, F>)
+    where
+        L: HeapSize,
+        R: HeapSize,
+        D: InnerJoinPredicate + MergePredicate<Left=L, Right=R>,
+        F: FlushingPolicy,
+        E: AsyncExternalStorage<L> + AsyncExternalStorage<R> {
+    // the pool may deny more memory or want some back
+    let granted = match &mut common.reservation {
+        Some(r) => !r.shrink_requested() && r.try_resize(common.inmemory_size),
+        None => true,
+    };
+    if common.inmemory_size >= common.config.memory_limit || (!granted && common.inmemory_size > 0) {
+        // if out of space, go evict
+        let memory_table: Vec<_> = parts_l.in_memory_tuples.iter().zip(&parts_r.in_memory_tuples)
+            .map(|(l, r)| PartitionStats { left: *l, right: *r }).collect();
+        let partition_to_evict = common.config.flushing_policy.flush(&memory_table); // FIXME dont hardcode
+        //println!("EVICTING {} because of {:?}", partition_to_evict, memory_table);
+        parts_l.evict(partition_to_evict, definition, common);
+        parts_r.evict(partition_to_evict, &definition.swap(), common);
+        if let Some(r) = &mut common.reservation {
+            r.shrink_to(common.inmemory_size.min(r.size()));
+        }
+    }
+}
 impl<L, R, D, E, F> Stream for HashMergeJoin<L, R, D, E, F>
     where
-        L: Stream,
-        R: Stream<Error=L::Error>,
-        D: InnerJoinPredicate + HashPredicate<Left=L::Item, Right=R::Item> + MergePredicate,
+        L: TryStream,
+        L::Ok: HeapSize,
+        R: TryStream<Error=L::Error>,
+        R::Ok: HeapSize,
+        D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok> + MergePredicate,
         F: FlushingPolicy,
-        E: ExternalStorage<L::Item> + ExternalStorage<R::Item> {
-    type Item = D::Output;
-    type Error = L::Error;
+        E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    type Item = Result<D::Output, JoinError<L::Error, <E as Storage>::Error>>;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
         loop {
-            if let Some(x) = self.common.output_buffer.pop_front() {
-                return Ok(Async::Ready(Some(x)));
+            if let Some(x) = this.common.output_buffer.pop_front() {
+                return Poll::Ready(Some(Ok(x)));
             }
 
+            // runs must reach their disk partitions before any of them is merged
+            ready!(this.parts_l.poll_stores(cx)).map_err(JoinError::Storage)?;
+            ready!(this.parts_r.poll_stores(cx)).map_err(JoinError::Storage)?;
+
             // PAPER UNCLEAR: do we finish the merge first? or poll more input asap?
-            if let Some(mut merge) = self.merge.take() {
-                match merge.omj.poll().unwrap() {
-                    Async::Ready(Some(x)) => {
+            if let Some(mut merge) = this.merge.take() {
+                match merge.pass.poll_next(&mut this.common.storage, cx) {
+                    Poll::Ready(Some(Ok(x))) => {
                         // merge ongoing, yield tuple
-                        self.merge = Some(merge);
-                        return Ok(Async::Ready(Some(x)));
+                        *this.merge = Some(merge);
+                        return Poll::Ready(Some(Ok(x)));
                     }
-                    Async::Ready(None) => {
-                        // merge complete, write merged partitions back to disk
-                        drop(merge.omj);
-
-                        if !self.left.is_done() || !self.right.is_done() {
-                            self.parts_l.disk[merge.disk_partition].push(self.common.storage.store(merge.recv_left.unpack().into_iter().map(|(_, x)| x).collect()));
-                            self.parts_r.disk[merge.disk_partition].push(self.common.storage.store(merge.recv_right.unpack().into_iter().map(|(_, x)| x).collect()));
+                    Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(JoinError::Storage(e)))),
+                    Poll::Ready(None) => {
+                        // merge complete, the merged partitions are back on disk
+                        if let Some((left, right)) = merge.pass.into_runs() {
+                            this.parts_l.disk[merge.disk_partition].push(left);
+                            this.parts_r.disk[merge.disk_partition].push(right);
                         }
+                        continue;
+                    }
+                    Poll::Pending => {
+                        *this.merge = Some(merge);
+                        return Poll::Pending;
                     }
-                    Async::NotReady => unreachable!(),
                 }
             }
 
-            match (self.left.poll()?, self.right.poll()?) {
-                (l @ Async::Ready(Some(_)), r) | (l, r @ Async::Ready(Some(_))) => {
+            match (this.left.as_mut().try_poll_next(cx)?, this.right.as_mut().try_poll_next(cx)?) {
+                (l @ Poll::Ready(Some(_)), r) | (l, r @ Poll::Ready(Some(_))) => {
                     // we have inputs => hash phase
-                    if let Async::Ready(Some(l)) = l {
-                        self.check_eviction();
-                        self.parts_l.insert(l, &mut self.parts_r, &self.definition, &mut self.common);
+                    if let Poll::Ready(Some(l)) = l {
+                        check_eviction(this.parts_l, this.parts_r, &**this.definition, this.common);
+                        this.parts_l.insert(l, this.parts_r, &**this.definition, this.common);
                     }
-                    if let Async::Ready(Some(r)) = r {
-                        self.check_eviction();
-                        self.parts_r.insert(r, &mut self.parts_l, &self.definition.by_ref().swap(), &mut self.common);
+                    if let Poll::Ready(Some(r)) = r {
+                        check_eviction(this.parts_l, this.parts_r, &**this.definition, this.common);
+                        this.parts_r.insert(r, this.parts_l, &this.definition.by_ref().swap(), this.common);
                     }
                 }
-                (Async::Ready(None), Async::Ready(None)) if self.common.total_inmemory != 0 => {
+                (Poll::Ready(None), Poll::Ready(None)) if this.parts_l.in_memory_tuples.iter().chain(&this.parts_r.in_memory_tuples).any(|&n| n != 0) => {
                     // inputs complete => flush all
-                    for i in 0..self.parts_l.disk.len() {
-                        self.parts_l.evict(i, &self.definition, &mut self.common);
-                        self.parts_r.evict(i, &self.definition.by_ref().swap(), &mut self.common);
+                    for i in 0..this.parts_l.disk.len() {
+                        this.parts_l.evict(i, &**this.definition, this.common);
+                        this.parts_r.evict(i, &this.definition.by_ref().swap(), this.common);
+                    }
+                    if let Some(r) = &mut this.common.reservation {
+                        r.shrink_to(0);
                     }
                 }
                 (l, r) => {
@@ -194,30 +241,25 @@ impl<L, R, D, E, F> Stream for HashMergeJoin<L, R, D, E, F>
                     // what to pick ??
                     // for now: find the biggest, then merge up to the given fan-in
                     // PAPER UNCLEAR: perhaps we should decline to merge unless we're in cleanup phase?
-                    let fan_in = self.common.config.fan_in;
-                    let merge = self.parts_l.disk.iter_mut().zip(self.parts_r.disk.iter_mut())
+                    let fan_in = this.common.config.fan_in;
+                    let merge = this.parts_l.disk.iter_mut().zip(this.parts_r.disk.iter_mut())
                         .enumerate().filter(|(_, (l, _))| l.len() > 1)
                         .sorted_by_key(|(_, (l, _))| l.len()).rev()
-                        .map(|(i, (l, r))| (i, l.drain(..cmp::min(l.len(), fan_in)).collect(), r.drain(..cmp::min(r.len(), fan_in))
-                        .collect())).next();
+                        .map(|(i, (l, r))| (i, l.drain(..cmp::min(l.len(), fan_in)).collect(), r.drain(..cmp::min(r.len(), fan_in)).collect()))
+                        .next();
                     if let Some((i, l, r)) = merge {
-                        let (send_left, recv_left) = ValueSink::new(SortMerger::new(l, Rc::clone(&self.definition)));
-                        let (send_right, recv_right) = ValueSink::new(SortMerger::new(r, Rc::clone(&self.definition).swap()));
-
-                        self.merge = Some(MergePhase {
-                            disk_partition: i,
-                            recv_left,
-                            recv_right,
-                            omj: OrderedMergeJoin::new(send_left, send_right, IgnoreIndexPredicate(Rc::clone(&self.definition))),
-                        });
+                        // the merged runs are still needed unless this is the partition's final merge
+                        let keep = !this.left.is_done() || !this.right.is_done() || !this.parts_l.disk[i].is_empty();
+                        let pass = MergePass::new(l, r, this.definition, keep, this.common.config.memory_limit / 2);
+                        *this.merge = Some(MergePhase { pass, disk_partition: i });
                     } else {
                         // none found, nothing to do!
                         match (l, r) {
-                            (Async::Ready(None), Async::Ready(None)) => {
-                                assert_eq!(0, self.common.total_inmemory);
-                                return Ok(Async::Ready(None));
+                            (Poll::Ready(None), Poll::Ready(None)) => {
+                                assert_eq!(0, this.common.inmemory_size);
+                                return Poll::Ready(None);
                             }
-                            _ => return Ok(Async::NotReady),
+                            _ => return Poll::Pending,
                         }
                     }
                 }
@@ -227,6 +269,9 @@ impl<L, R, D, E, F> Stream for HashMergeJoin<L, R, D, E, F>
 }
 
 pub struct HMJConfig<F> {
+    /// Maximum size of all in-memory partitions in bytes.
+    ///
+    /// Merges of disk partitions buffer up to as much again while writing the merged runs back.
     pub memory_limit: usize,
     pub num_partitions: usize, // paper has no idea regarding memory_limit vs num_partitions
 
@@ -238,16 +283,23 @@ pub struct HMJConfig<F> {
     pub fan_in: usize,
 
     pub flushing_policy: F,
+
+    /// Shared budget to reserve in-memory partitions from, in addition to `memory_limit`.
+    ///
+    /// Partitions are evicted when the pool is exhausted or another join asks for memory.
+    pub pool: Option<MemoryPool>,
 }
 
 
 impl<L, R, D, E, F> Join<L, R, D, E, HMJConfig<F>> for HashMergeJoin<L, R, D, E, F>
     where
-        L: Stream,
-        R: Stream<Error=L::Error>,
-        D: InnerJoinPredicate + HashPredicate<Left=L::Item, Right=R::Item> + MergePredicate,
+        L: TryStream,
+        L::Ok: HeapSize,
+        R: TryStream<Error=L::Error>,
+        R::Ok: HeapSize,
+        D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok> + MergePredicate,
         F: FlushingPolicy,
-        E: ExternalStorage<L::Item> + ExternalStorage<R::Item> {
+        E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
     fn build(left: L, right: R, definition: D, storage: E, config: HMJConfig<F>) -> Self {
         // assert!(config.num_partitions <= (config.memory_limit / 2)); // not sure if this /actually/ must hold?
 
@@ -257,20 +309,89 @@ impl<L, R, D, E, F> Join<L, R, D, E, HMJConfig<F>> for HashMergeJoin<L, R, D, E,
         assert!(config.fan_in > 1);
 
         HashMergeJoin {
-            definition: Rc::new(definition),
+            definition: Arc::new(definition),
             parts_l: Partitions::new(&config),
             parts_r: Partitions::new(&config),
             common: Common {
                 storage,
+                reservation: config.pool.as_ref().map(MemoryPool::reserve),
                 config,
-                total_inmemory: 0,
+                inmemory_size: 0,
                 output_buffer: VecDeque::new(),
             },
-            left: left.fuse(),
-            right: right.fuse(),
+            left: left.into_stream().fuse(),
+            right: right.into_stream().fuse(),
 
             merge: None,
         }
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+    use futures::TryStreamExt;
+    use futures::executor::block_on;
+    use crate::{EquiJoin, HashMergeJoin, IterSource, Join, JoinInMemory, MemoryPool};
+    use crate::join::sort_merge::test::SlowStorage;
+    use super::HMJConfig;
+    use super::flush::FlushLargest;
+
+    #[test]
+    fn hash_merge_shared_pool() {
+        let pool = MemoryPool::new(64 * mem::size_of::<i32>());
+        let build = |left: Vec<i32>, right: Vec<i32>| HashMergeJoin::build_in_memory(
+            left,
+            right,
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            HMJConfig { memory_limit: usize::MAX, num_partitions: 8, mem_parts_per_disk_part: 2, fan_in: 4, flushing_policy: FlushLargest, pool: Some(pool.clone()) },
+        );
+        let mut a = build((0..300).collect(), (0..300).rev().collect());
+        let mut b = build((0..200).map(|x| x % 100).collect(), (50..150).collect());
+
+        let (mut results_a, mut results_b) = (Vec::new(), Vec::new());
+        loop {
+            let (x, y) = (a.next(), b.next());
+            assert!(pool.used() <= pool.capacity());
+            if x.is_none() && y.is_none() {
+                break;
+            }
+            results_a.extend(x);
+            results_b.extend(y);
+        }
+        results_a.sort_unstable();
+        results_b.sort_unstable();
+        assert_eq!((0..300).map(|x| (x, x)).collect::<Vec<_>>(), results_a);
+        assert_eq!((50..100).flat_map(|x| [(x, x), (x, x)]).collect::<Vec<_>>(), results_b);
+        drop((a, b));
+        assert_eq!(0, pool.used());
+    }
+
+    #[test]
+    fn hash_merge_send() {
+        let join = HashMergeJoin::build_in_memory(
+            (0..300).collect::<Vec<i32>>(),
+            (0..300).rev().collect::<Vec<i32>>(),
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            HMJConfig { memory_limit: 64 * mem::size_of::<i32>(), num_partitions: 8, mem_parts_per_disk_part: 2, fan_in: 4, flushing_policy: FlushLargest, pool: None },
+        );
+        let mut results = std::thread::spawn(move || join.collect::<Vec<_>>()).join().unwrap();
+        results.sort_unstable();
+        assert_eq!((0..300).map(|x| (x, x)).collect::<Vec<_>>(), results);
+    }
+
+    #[test]
+    fn hash_merge_async_storage() {
+        let join = HashMergeJoin::build(
+            IterSource::new((0..400).map(|x| x % 200)),
+            IterSource::new(100..300),
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            SlowStorage,
+            HMJConfig { memory_limit: 64 * mem::size_of::<i32>(), num_partitions: 8, mem_parts_per_disk_part: 2, fan_in: 2, flushing_policy: FlushLargest, pool: None },
+        );
+        let mut results: Vec<_> = block_on(join.try_collect()).unwrap();
+        results.sort_unstable();
+        assert_eq!((100..200).flat_map(|x| [(x, x), (x, x)]).collect::<Vec<_>>(), results);
+    }
+}
@@ -0,0 +1,232 @@
+use std::mem;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
+use multimap::MultiMap;
+use named_type::NamedType;
+use named_type_derive::*;
+use pin_project::pin_project;
+use crate::{HeapSize, InnerJoinPredicate};
+
+use super::{Join, JoinError, ExternalStorage, Storage};
+use super::grace_hash::{GraceConfig, Partitioner, PartitionJoin, finish_partitions};
+use crate::predicate::HashPredicate;
+
+/// Configuration of a `HybridHashJoin`.
+#[derive(Clone, Copy, Debug)]
+pub struct HybridConfig {
+    /// Maximum size in bytes of the tuples held in memory at once, including the resident partition.
+    pub memory_limit: usize,
+    /// Number of partitions, including the resident partition.
+    pub num_partitions: usize,
+    /// How often a spilled partition may be re-partitioned, see `GraceConfig::max_depth`.
+    pub max_depth: usize,
+}
+
+/// A blocking hash join that keeps the first partition's hash table in memory.
+///
+/// The left input is read completely first. Tuples of partition 0 are inserted into an
+/// in-memory hash table while all other partitions are spilled through `ExternalStorage`.
+/// Right tuples of partition 0 are probed against this table immediately, the others are spilled
+/// as well (unless their left partition is empty) and joined like in a `GraceHashJoin`
+/// once the right input is exhausted.
+///
+/// Should the resident partition outgrow the memory limit, it is spilled and handled like
+/// every other partition.
+#[pin_project]
+#[derive(NamedType)]
+pub struct HybridHashJoin<L, R, D, E>
+    where
+        L: TryStream,
+        R: TryStream,
+        D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok>,
+        E: ExternalStorage<L::Ok> + ExternalStorage<R::Ok> {
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Fuse<stream::IntoStream<R>>,
+    definition: D,
+    storage: E,
+    config: GraceConfig,
+    state: State<L::Ok, R::Ok, E>,
+    output_buffer: VecDeque<D::Output>,
+}
+
+struct Resident<L> {
+    table: MultiMap<u64, L>,
+    // bytes held by the table
+    size: usize,
+}
+
+enum State<L, R, E: ExternalStorage<L> + ExternalStorage<R>> {
+    BuildPhase {
+        resident: Option<Resident<L>>,
+        left: Partitioner<L, E>,
+        right: Partitioner<R, E>,
+    },
+    ProbePhase {
+        resident: Option<Resident<L>>,
+        left: Partitioner<L, E>,
+        right: Partitioner<R, E>,
+    },
+    JoinPhase(PartitionJoin<L, R, E>),
+    Tmp,
+}
+
+fn resident_size<L>(resident: &Option<Resident<L>>) -> usize {
+    resident.as_ref().map_or(0, |r| r.size)
+}
+
+impl<L, R, D, E> Stream for HybridHashJoin<L, R, D, E>
+    where
+        L: TryStream,
+        L::Ok: HeapSize,
+        R: TryStream<Error=L::Error>,
+        R::Ok: HeapSize,
+        D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok>,
+        E: ExternalStorage<L::Ok> + ExternalStorage<R::Ok> {
+    type Item = Result<D::Output, JoinError<L::Error, <E as Storage>::Error>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            if let Some(buffered) = this.output_buffer.pop_front() {
+                return Poll::Ready(Some(Ok(buffered)));
+            }
+            let definition = &*this.definition;
+            let memory_limit = this.config.memory_limit;
+            match this.state {
+                State::BuildPhase { resident, left, .. } => {
+                    if let Some(l) = ready!(this.left.as_mut().try_poll_next(cx)?) {
+                        let hash = definition.hash_left(&l);
+                        let partition = left.partition_of(hash);
+                        match resident {
+                            Some(resident) if partition == 0 => {
+                                resident.size += l.memory_size();
+                                resident.table.insert(hash, l);
+                            }
+                            _ => left.push(partition, l),
+                        }
+
+                        while resident_size(resident) + left.buffered() > memory_limit {
+                            if left.buffered() > 0 {
+                                let (_, largest) = left.largest();
+                                left.flush(largest, this.storage).map_err(JoinError::Storage)?;
+                            } else if let Some(demoted) = resident.take() {
+                                // the resident partition doesn't fit after all
+                                for l in demoted.table.into_iter().flat_map(|(_, v)| v) {
+                                    left.push(0, l);
+                                }
+                                left.flush(0, this.storage).map_err(JoinError::Storage)?;
+                            }
+                        }
+                        continue;
+                    }
+
+                    if let State::BuildPhase { resident, mut left, right } = mem::replace(this.state, State::Tmp) {
+                        left.flush_all(this.storage).map_err(JoinError::Storage)?;
+                        *this.state = State::ProbePhase { resident, left, right };
+                    }
+                }
+                State::ProbePhase { resident, left, right } => {
+                    if let Some(r) = ready!(this.right.as_mut().try_poll_next(cx)?) {
+                        let hash = definition.hash_right(&r);
+                        let partition = right.partition_of(hash);
+                        match resident {
+                            Some(resident) if partition == 0 => {
+                                this.output_buffer.extend(
+                                    resident.table.get_vec(&hash).into_iter()
+                                        .flatten().filter_map(|left| definition.eq(left, &r)));
+                            }
+                            // no join partners, don't bother spilling
+                            _ if left.len(partition) == 0 => {}
+                            _ => {
+                                right.push(partition, r);
+                                while resident_size(resident) + right.buffered() > memory_limit && right.buffered() > 0 {
+                                    let (_, largest) = right.largest();
+                                    right.flush(largest, this.storage).map_err(JoinError::Storage)?;
+                                }
+                            }
+                        }
+                        continue;
+                    }
+
+                    if let State::ProbePhase { left, right, .. } = mem::replace(this.state, State::Tmp) {
+                        let pending = finish_partitions(left, right, this.storage, 0).map_err(JoinError::Storage)?;
+                        *this.state = State::JoinPhase(PartitionJoin::new(pending));
+                    }
+                }
+                State::JoinPhase(partitions) => {
+                    if !partitions.step(definition, this.storage, this.config, this.output_buffer).map_err(JoinError::Storage)? {
+                        return Poll::Ready(None);
+                    }
+                }
+                // only left behind if spilling the partitions failed
+                State::Tmp => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<L, R, D, E> Join<L, R, D, E, HybridConfig> for HybridHashJoin<L, R, D, E>
+    where
+        L: TryStream,
+        L::Ok: HeapSize,
+        R: TryStream<Error=L::Error>,
+        R::Ok: HeapSize,
+        D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok>,
+        E: ExternalStorage<L::Ok> + ExternalStorage<R::Ok> {
+    fn build(left: L, right: R, definition: D, storage: E, config: HybridConfig) -> Self {
+        assert!(config.num_partitions > 1);
+        assert!(config.memory_limit > 0);
+
+        let config = GraceConfig {
+            memory_limit: config.memory_limit,
+            num_partitions: config.num_partitions,
+            max_depth: config.max_depth,
+        };
+        HybridHashJoin {
+            left: left.into_stream().fuse(),
+            right: right.into_stream().fuse(),
+            state: State::BuildPhase {
+                resident: Some(Resident { table: MultiMap::new(), size: 0 }),
+                left: Partitioner::new(config.num_partitions, 0),
+                right: Partitioner::new(config.num_partitions, 0),
+            },
+            definition,
+            storage,
+            config,
+            output_buffer: VecDeque::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+    use crate::{EquiJoin, HybridConfig, HybridHashJoin, JoinInMemory};
+
+    fn hybrid_join(left: Vec<i32>, right: Vec<i32>, memory_limit: usize) -> Vec<(i32, i32)> {
+        let join = HybridHashJoin::build_in_memory(
+            left,
+            right,
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            HybridConfig { memory_limit, num_partitions: 4, max_depth: 3 },
+        );
+        let mut results: Vec<_> = join.collect();
+        results.sort_unstable();
+        results
+    }
+
+    #[test]
+    fn hybrid_hash() {
+        let left: Vec<_> = (0..1000).map(|x| x % 300).collect();
+        let right: Vec<_> = (0..500).rev().collect();
+        let mut expected: Vec<_> = left.iter().flat_map(|&l| right.iter().filter(move |&&r| l == r).map(move |&r| (l, r))).collect();
+        expected.sort_unstable();
+        assert_eq!(expected, hybrid_join(left.clone(), right.clone(), 64 * mem::size_of::<i32>()));
+        // resident partition gets demoted
+        assert_eq!(expected, hybrid_join(left, right, 8 * mem::size_of::<i32>()));
+    }
+}
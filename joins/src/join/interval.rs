@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+use std::iter::Peekable;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::cmp::Ordering;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
+use named_type::NamedType;
+use named_type_derive::*;
+use pin_project::pin_project;
+use crate::{HeapSize, InnerJoinPredicate};
+use crate::in_memory::TryIterReady;
+
+use super::{Blocking, Join, JoinError, ExternalStorage, SortMergeConfig, Storage};
+use super::sort_merge::{Merger, SortPhase};
+use crate::predicate::{IntervalPredicate, SwapPredicate};
+
+/// Plane-sweep join of overlapping intervals.
+///
+/// Both inputs are sorted by interval start through `ExternalStorage` first, just like in a
+/// `SortMergeJoin`. The sorted inputs are then swept in order of their start points, keeping
+/// the set of active (not yet ended) intervals of each side. Each tuple is joined with the
+/// active intervals of the other side, so every overlapping pair is emitted exactly once.
+#[pin_project]
+#[derive(NamedType)]
+pub struct IntervalJoin<L: TryStream, R: TryStream, D: InnerJoinPredicate + IntervalPredicate<Left=L::Ok, Right=R::Ok>, E>
+    where
+        E: ExternalStorage<L::Ok> + ExternalStorage<R::Ok> {
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Fuse<stream::IntoStream<R>>,
+    state: State<D, E>,
+}
+
+enum State<D: InnerJoinPredicate + IntervalPredicate, E>
+    where
+        E: ExternalStorage<D::Left> + ExternalStorage<D::Right> {
+    InputPhase(SortPhase<D, Blocking<E>>),
+    SweepPhase(Sweep<D, E>),
+    Tmp,
+}
+
+/// The merged runs of one side, which never block as they're fetched synchronously.
+type Sorted<D, E> = TryIterReady<Merger<D, Blocking<E>>>;
+
+struct Sweep<D: InnerJoinPredicate + IntervalPredicate, E>
+    where
+        E: ExternalStorage<D::Left> + ExternalStorage<D::Right> {
+    definition: Arc<D>,
+    left: Peekable<Sorted<Arc<D>, E>>,
+    right: Peekable<Sorted<SwapPredicate<Arc<D>>, E>>,
+    active_left: Vec<D::Left>,
+    active_right: Vec<D::Right>,
+    output_buffer: VecDeque<D::Output>,
+}
+
+impl<D, E> Sweep<D, E>
+    where
+        D: InnerJoinPredicate + IntervalPredicate,
+        E: ExternalStorage<D::Left> + ExternalStorage<D::Right> {
+    /// Processes the tuple with the next start point, returning `false` once no more output is possible.
+    fn step(&mut self) -> Result<bool, <E as Storage>::Error> {
+        let definition = &*self.definition;
+        let take_left = match (peek_ok(&mut self.left)?, peek_ok(&mut self.right)?) {
+            // ties are broken in favor of the left side
+            (Some(l), Some(r)) => definition.cmp(l, r).unwrap() != Ordering::Greater,
+            (Some(_), None) if !self.active_right.is_empty() => true,
+            (None, Some(_)) if !self.active_left.is_empty() => false,
+            // no more join partners for the remaining tuples
+            _ => return Ok(false),
+        };
+        if take_left {
+            let l = take_peeked(&mut self.left);
+            self.active_right.retain(|r| !definition.right_ends_before(&l, r));
+            self.output_buffer.extend(self.active_right.iter().filter_map(|r| definition.eq(&l, r)));
+            self.active_left.push(l);
+        } else {
+            let r = take_peeked(&mut self.right);
+            self.active_left.retain(|l| !definition.left_ends_before(l, &r));
+            self.output_buffer.extend(self.active_left.iter().filter_map(|l| definition.eq(l, &r)));
+            self.active_right.push(r);
+        }
+        Ok(true)
+    }
+}
+
+/// Peeks at the next tuple, taking errors out of the iterator right away.
+fn peek_ok<'a, T: 'a, X: 'a, I: Iterator<Item=Result<T, X>>>(iter: &'a mut Peekable<I>) -> Result<Option<&'a T>, X> {
+    if iter.peek().is_some_and(Result::is_err) {
+        return Err(iter.next().and_then(Result::err).unwrap());
+    }
+    Ok(iter.peek().and_then(|x| x.as_ref().ok()))
+}
+
+/// Takes the tuple that was previously peeked at using `peek_ok`.
+fn take_peeked<T, X, I: Iterator<Item=Result<T, X>>>(iter: &mut Peekable<I>) -> T {
+    iter.next().and_then(Result::ok).unwrap()
+}
+
+impl<L, R, D, E> Stream for IntervalJoin<L, R, D, E>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: InnerJoinPredicate + IntervalPredicate<Left=L::Ok, Right=R::Ok>,
+          E: ExternalStorage<L::Ok> + ExternalStorage<R::Ok> {
+    type Item = Result<D::Output, JoinError<L::Error, <E as Storage>::Error>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            match this.state {
+                State::InputPhase(input) => {
+                    ready!(input.poll_input(Some(this.left.as_mut()), Some(this.right.as_mut()), cx))?;
+                    ready!(input.poll_finish(cx)).map_err(JoinError::Storage)?;
+                }
+                State::SweepPhase(sweep) => {
+                    if let Some(buffered) = sweep.output_buffer.pop_front() {
+                        return Poll::Ready(Some(Ok(buffered)));
+                    }
+                    if !sweep.step().map_err(JoinError::Storage)? {
+                        return Poll::Ready(None);
+                    }
+                    continue;
+                }
+                State::Tmp => unreachable!(),
+            }
+
+            *this.state = match std::mem::replace(this.state, State::Tmp) {
+                State::InputPhase(input) => {
+                    let (definition, left, right) = input.into_sorted();
+                    State::SweepPhase(Sweep {
+                        definition,
+                        left: TryIterReady(left).peekable(),
+                        right: TryIterReady(right).peekable(),
+                        active_left: Vec::new(),
+                        active_right: Vec::new(),
+                        output_buffer: VecDeque::new(),
+                    })
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+impl<L, R, D, E> Join<L, R, D, E, usize> for IntervalJoin<L, R, D, E>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: InnerJoinPredicate + IntervalPredicate<Left=L::Ok, Right=R::Ok>,
+          E: ExternalStorage<L::Ok> + ExternalStorage<R::Ok> {
+    fn build(left: L, right: R, definition: D, storage: E, main_memory: usize) -> Self {
+        IntervalJoin {
+            left: left.into_stream().fuse(),
+            right: right.into_stream().fuse(),
+            state: State::InputPhase(SortPhase::new(definition, Blocking(storage), SortMergeConfig::from(main_memory))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{IntervalJoin, IntervalOverlap, JoinInMemory};
+
+    #[test]
+    fn interval_overlap() {
+        let left = vec![(5, 9), (0, 3), (2, 6), (10, 11), (7, 8), (25, 26), (30, 31)];
+        let right = vec![(3, 5), (1, 2), (8, 12), (6, 7), (0, 20)];
+        let join = IntervalJoin::build_in_memory(
+            left.clone(),
+            right.clone(),
+            IntervalOverlap::new(|&l: &(i32, i32)| l, |&r: &(i32, i32)| r),
+            4,
+        );
+        let mut results: Vec<_> = join.collect();
+        results.sort_unstable();
+        let mut expected: Vec<_> = left.iter()
+            .flat_map(|&l| right.iter().filter(move |&&r| l.0 < r.1 && r.0 < l.1).map(move |&r| (l, r)))
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(expected, results);
+    }
+}
@@ -1,6 +1,11 @@
 use std::borrow::Borrow;
 use std::convert::Infallible;
-use futures::Stream;
+use std::error::Error;
+use std::fmt;
+use std::iter;
+use std::pin::Pin;
+use either::Either;
+use futures::{Future, Stream, TryStream, future, stream};
 use crate::{InnerJoinPredicate, IntoIterReady, IterReady, IterSource, OuterJoinPredicate};
 
 mod nested_loop;
@@ -8,19 +13,45 @@ pub use self::nested_loop::NestedLoopJoin;
 mod block_nested_loop;
 pub use self::block_nested_loop::BlockNestedLoopJoin;
 mod ordered_merge;
-pub use self::ordered_merge::OrderedMergeJoin;
+pub use self::ordered_merge::{OrderedMergeJoin, CheckedMergeJoin, OrderError, Side};
+mod ordered_merge_semi;
+pub use self::ordered_merge_semi::OrderedMergeSemiJoin;
+mod ordered_merge_anti;
+pub use self::ordered_merge_anti::OrderedMergeAntiJoin;
+mod sweep_band;
+pub use self::sweep_band::SweepBandJoin;
+mod interval;
+pub use self::interval::IntervalJoin;
 mod sort_merge;
-pub use self::sort_merge::SortMergeJoin;
+pub use self::sort_merge::{Presorted, SortMergeJoin, SortMergeConfig};
+mod sort_merge_anti;
+pub use self::sort_merge_anti::SortMergeAntiJoin;
+mod parallel_sort;
+pub use self::parallel_sort::ParallelSortConfig;
 mod simple_hash;
 pub use self::simple_hash::SimpleHashJoin;
 mod simple_anti_hash;
-pub use self::simple_anti_hash::SimpleHashAntiJoin;
+pub use self::simple_anti_hash::{SimpleHashAntiJoin, AntiJoinConfig, AntiJoinMode};
+mod simple_semi_hash;
+pub use self::simple_semi_hash::SimpleHashSemiJoin;
+mod grace_hash;
+pub use self::grace_hash::{GraceHashJoin, GraceConfig};
+mod hybrid_hash;
+pub use self::hybrid_hash::{HybridHashJoin, HybridConfig};
+mod parallel_hash;
+pub use self::parallel_hash::{ParallelHashJoin, ParallelConfig};
+mod outer;
+pub use self::outer::{OuterJoinKind, LeftOuter, RightOuter, FullOuter};
+mod simple_outer_hash;
+pub use self::simple_outer_hash::SimpleHashOuterJoin;
 mod symmetric_hash;
 pub use self::symmetric_hash::SymmetricHashJoin;
+mod symmetric_outer_hash;
+pub use self::symmetric_outer_hash::SymmetricHashOuterJoin;
 mod progressive_merge;
 pub use self::progressive_merge::ProgressiveMergeJoin;
 mod xjoin;
-pub use self::xjoin::XJoin;
+pub use self::xjoin::{XJoin, XJoinConfig};
 pub mod hash_merge;
 pub use self::hash_merge::HashMergeJoin;
 
@@ -28,14 +59,14 @@ pub use self::hash_merge::HashMergeJoin;
 use crate::predicate::JoinPredicate;
 
 pub trait Rescan: Stream {
-    fn rescan(&mut self);
+    fn rescan(self: Pin<&mut Self>);
 }
 
-pub trait Join<Left, Right, Definition, ExtStorage, Config>: Stream
-    where Left: Stream,
-          Right: Stream<Error=Left::Error>,
-          Left::Item: Borrow<Definition::Left>,
-          Right::Item: Borrow<Definition::Right>,
+pub trait Join<Left, Right, Definition, ExtStorage, Config>: TryStream
+    where Left: TryStream,
+          Right: TryStream<Error=Left::Error>,
+          Left::Ok: Borrow<Definition::Left>,
+          Right::Ok: Borrow<Definition::Right>,
           Definition: JoinPredicate {
     fn build(
         left: Left,
@@ -46,43 +77,43 @@ pub trait Join<Left, Right, Definition, ExtStorage, Config>: Stream
 }
 
 pub trait InnerJoin<Left, Right, Definition, ExtStorage, Config>:
-    Join<Left, Right, Definition, ExtStorage, Config> + Stream<Item = Definition::Output>
+    Join<Left, Right, Definition, ExtStorage, Config> + TryStream<Ok = Definition::Output>
 where
-    Left: Stream,
-    Right: Stream<Error=Left::Error>,
-    Left::Item: Borrow<Definition::Left>,
-    Right::Item: Borrow<Definition::Right>,
+    Left: TryStream,
+    Right: TryStream<Error=Left::Error>,
+    Left::Ok: Borrow<Definition::Left>,
+    Right::Ok: Borrow<Definition::Right>,
     Definition: InnerJoinPredicate,
 {}
 
 impl<J, Left, Right, Definition, ExtStorage, Config> InnerJoin<Left, Right, Definition, ExtStorage, Config> for J
 where
     J: Join<Left, Right, Definition, ExtStorage, Config>,
-    J: Stream<Item = Definition::Output>,
-    Left: Stream,
-    Right: Stream<Error=Left::Error>,
-    Left::Item: Borrow<Definition::Left>,
-    Right::Item: Borrow<Definition::Right>,
+    J: TryStream<Ok = Definition::Output>,
+    Left: TryStream,
+    Right: TryStream<Error=Left::Error>,
+    Left::Ok: Borrow<Definition::Left>,
+    Right::Ok: Borrow<Definition::Right>,
     Definition: InnerJoinPredicate,
 {}
 
 pub trait LeftOuterJoin<Left, Right, Definition, ExtStorage, Config>:
-    Join<Left, Right, Definition, ExtStorage, Config> + Stream<Item = Left::Item>
+    Join<Left, Right, Definition, ExtStorage, Config> + TryStream<Ok = Left::Ok>
 where
-    Left: Stream,
-    Right: Stream<Error=Left::Error>,
-    Left::Item: Borrow<Definition::Left>,
-    Right::Item: Borrow<Definition::Right>,
+    Left: TryStream,
+    Right: TryStream<Error=Left::Error>,
+    Left::Ok: Borrow<Definition::Left>,
+    Right::Ok: Borrow<Definition::Right>,
     Definition: OuterJoinPredicate,
 {}
 impl<J, Left, Right, Definition, ExtStorage, Config> LeftOuterJoin<Left, Right, Definition, ExtStorage, Config> for J
 where
     J: Join<Left, Right, Definition, ExtStorage, Config>,
-    J: Stream<Item = Left::Item>,
-    Left: Stream,
-    Right: Stream<Error=Left::Error>,
-    Left::Item: Borrow<Definition::Left>,
-    Right::Item: Borrow<Definition::Right>,
+    J: TryStream<Ok = Left::Ok>,
+    Left: TryStream,
+    Right: TryStream<Error=Left::Error>,
+    Left::Ok: Borrow<Definition::Left>,
+    Right::Ok: Borrow<Definition::Right>,
     Definition: OuterJoinPredicate,
 {}
 
@@ -96,7 +127,8 @@ where
     Right::Item: Borrow<Definition::Right>,
     Definition: JoinPredicate,
     Self: Join<IterSource<Left::IntoIter>, IterSource<Right::IntoIter>, Definition, (), Config>,
-    Self: Stream<Error = Infallible>,
+    Self: TryStream + Unpin,
+    Self::Error: Into<Infallible>,
     Self: Sized,
 {
     fn build_in_memory(left: Left, right: Right, definition: Definition, config: Config,) -> IterReady<Self>{
@@ -106,7 +138,8 @@ where
 impl<J, Left, Right, Definition, Config> JoinInMemory<Left, Right, Definition, Config> for J
 where
     J: Join<IterSource<Left::IntoIter>, IterSource<Right::IntoIter>, Definition, (), Config>,
-    J: Stream<Error = Infallible>,
+    J: TryStream + Unpin,
+    J::Error: Into<Infallible>,
     Left: IntoIterator,
     Left::IntoIter: Clone,
     Right: IntoIterator,
@@ -116,12 +149,109 @@ where
     Definition: JoinPredicate
 {}
 
-pub trait ExternalStorage<T> {
-    type External: External<T>;
-    fn store(&mut self, tuples: Vec<T>) -> Self::External;
+/// The error type shared by all `ExternalStorage` implementations of a storage.
+pub trait Storage {
+    type Error;
+}
+pub trait ExternalStorage<T>: Storage {
+    type External: External<T, Error=Self::Error>;
+    fn store(&mut self, tuples: Vec<T>) -> Result<Self::External, Self::Error>;
 }
 pub trait External<T> {
-    type Iter: Iterator<Item=T>;
-    fn fetch(&self) -> Self::Iter;
+    type Error;
+    type Iter: Iterator<Item=Result<T, Self::Error>>;
+    fn fetch(&self) -> Result<Self::Iter, Self::Error>;
+}
+
+impl<T, X: External<T>> External<T> for &X {
+    type Error = X::Error;
+    type Iter = X::Iter;
+    fn fetch(&self) -> Result<X::Iter, X::Error> {
+        (**self).fetch()
+    }
+}
+
+/// Like `ExternalStorage`, but stores runs asynchronously.
+///
+/// Joins built on this trait return `Poll::Pending` while a run is being written or read
+/// instead of blocking the executor. Synchronous storages can be used through `Blocking`.
+pub trait AsyncExternalStorage<T>: Storage {
+    type External: AsyncExternal<T, Error=Self::Error>;
+    type Store: Future<Output=Result<Self::External, Self::Error>>;
+    fn store(&mut self, tuples: Vec<T>) -> Self::Store;
+}
+/// Like `External`, but streams the tuples of a run asynchronously.
+pub trait AsyncExternal<T> {
+    type Error;
+    type Fetch: Stream<Item=Result<T, Self::Error>>;
+    fn fetch(&self) -> Self::Fetch;
+}
+
+/// Adapts an `ExternalStorage` (or an `External` run) to the asynchronous traits.
+///
+/// All futures and streams complete immediately, i.e. the I/O still blocks the executor.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Blocking<S>(pub S);
+
+impl<S: Storage> Storage for Blocking<S> {
+    type Error = S::Error;
+}
+impl<T, E: ExternalStorage<T>> AsyncExternalStorage<T> for Blocking<E> {
+    type External = Blocking<E::External>;
+    type Store = future::Ready<Result<Blocking<E::External>, E::Error>>;
+    fn store(&mut self, tuples: Vec<T>) -> Self::Store {
+        future::ready(self.0.store(tuples).map(Blocking))
+    }
+}
+impl<T, X: External<T>> AsyncExternal<T> for Blocking<X> {
+    type Error = X::Error;
+    type Fetch = stream::Iter<Either<X::Iter, iter::Once<Result<T, X::Error>>>>;
+    fn fetch(&self) -> Self::Fetch {
+        stream::iter(fetch_iter(&self.0))
+    }
+}
+
+fn fetch_iter<T, X: External<T>>(run: &X) -> Either<X::Iter, iter::Once<Result<T, X::Error>>> {
+    match run.fetch() {
+        Ok(iter) => Either::Left(iter),
+        Err(e) => Either::Right(iter::once(Err(e))),
+    }
+}
+
+/// Error of joins that use `ExternalStorage`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinError<I, S> {
+    /// One of the input streams failed.
+    Input(I),
+    /// Storing or fetching a run failed.
+    Storage(S),
+}
+impl<I, S> From<I> for JoinError<I, S> {
+    fn from(e: I) -> JoinError<I, S> {
+        JoinError::Input(e)
+    }
+}
+impl From<JoinError<Infallible, Infallible>> for Infallible {
+    fn from(e: JoinError<Infallible, Infallible>) -> Infallible {
+        match e {
+            JoinError::Input(e) | JoinError::Storage(e) => e,
+        }
+    }
+}
+impl<I: fmt::Display, S: fmt::Display> fmt::Display for JoinError<I, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Input(e) => write!(f, "input error: {}", e),
+            JoinError::Storage(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+impl<I: Error + 'static, S: Error + 'static> Error for JoinError<I, S> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            JoinError::Input(e) => Some(e),
+            JoinError::Storage(e) => Some(e),
+        }
+    }
 }
 
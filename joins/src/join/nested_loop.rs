@@ -1,43 +1,49 @@
-use futures::{Stream, Poll, try_ready, Async, stream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
 use named_type::NamedType;
 use named_type_derive::*;
+use pin_project::pin_project;
 use crate::InnerJoinPredicate;
 
 use super::{Join, Rescan};
 use crate::predicate::JoinPredicate;
 
+#[pin_project]
 #[derive(NamedType)]
-pub struct NestedLoopJoin<L: Stream, R: Stream, D> {
-    left: stream::Peekable<L>,
+pub struct NestedLoopJoin<L: TryStream, R: TryStream, D> {
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    current: Option<L::Ok>,
+    #[pin]
     right: R,
     definition: D,
 }
 
 impl<L, R, D> Stream for NestedLoopJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error> + Rescan,
-          D: InnerJoinPredicate + JoinPredicate<Left=L::Item, Right=R::Item> {
-    type Item = D::Output;
-    type Error = L::Error;
+    where L: TryStream,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: InnerJoinPredicate + JoinPredicate<Left=L::Ok, Right=R::Ok> {
+    type Item = Result<D::Output, L::Error>;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
         loop {
-            match try_ready!(self.left.peek()) {
-                None => return Ok(Async::Ready(None)),
-                Some(left) => {
-                    match try_ready!(self.right.poll()) {
-                        None => {
-                            match self.left.poll()? {
-                                Async::Ready(Some(_)) => (),
-                                _ => unreachable!(),
-                            }
-                            self.right.rescan();
-                        }
-                        Some(right) => {
-                            if let Some(out) = self.definition.eq(left, &right) {
-                                return Ok(Async::Ready(Some(out)));
-                            }
-                        }
+            let left = match this.current {
+                Some(left) => left,
+                None => match ready!(this.left.as_mut().try_poll_next(cx)?) {
+                    None => return Poll::Ready(None),
+                    Some(left) => this.current.insert(left),
+                }
+            };
+            match ready!(this.right.as_mut().try_poll_next(cx)?) {
+                None => {
+                    *this.current = None;
+                    this.right.as_mut().rescan();
+                }
+                Some(right) => {
+                    if let Some(out) = this.definition.eq(left, &right) {
+                        return Poll::Ready(Some(Ok(out)));
                     }
                 }
             }
@@ -45,23 +51,23 @@ impl<L, R, D> Stream for NestedLoopJoin<L, R, D>
     }
 }
 // TODO: we /could/ support rescan ourselves iff our left side ALSO has rescan (need it for right side anyways)
-// however, Peekable<S> does not offer a way to access the underlying stream.
-// we would have to implement our own peek adapter probably - which is trivial but meh
+// however, Fuse<S> does not offer a way to reset the underlying stream.
+// we would have to implement our own fuse adapter probably - which is trivial but meh
 
 
 impl<L, R, D> NestedLoopJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error> + Rescan,
-          D: JoinPredicate<Left=L::Item, Right=R::Item> {
+    where L: TryStream,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: JoinPredicate<Left=L::Ok, Right=R::Ok> {
     pub fn new(left: L, right: R, definition: D) -> Self {
-        NestedLoopJoin { left: left.peekable(), right, definition }
+        NestedLoopJoin { left: left.into_stream().fuse(), current: None, right, definition }
     }
 }
 
 impl<L, R, D, E> Join<L, R, D, E, ()> for NestedLoopJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error> + Rescan,
-          D: InnerJoinPredicate + JoinPredicate<Left=L::Item, Right=R::Item> {
+    where L: TryStream,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: InnerJoinPredicate + JoinPredicate<Left=L::Ok, Right=R::Ok> {
     fn build(left: L, right: R, definition: D, _: E, _: ()) -> Self {
         NestedLoopJoin::new(left, right, definition)
     }
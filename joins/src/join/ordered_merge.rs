@@ -1,9 +1,14 @@
 //use debug_everything::Debuggable;
 use std::borrow::Borrow;
 use std::cmp::Ordering;
-use futures::{Stream, Poll, try_ready, Async, stream};
+use std::error::Error;
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
 use named_type::NamedType;
 use named_type_derive::*;
+use pin_project::pin_project;
 use crate::InnerJoinPredicate;
 
 use super::Join;
@@ -12,112 +17,410 @@ use crate::predicate::MergePredicate;
 // TODO: make this a proper plane-sweep merge in order to exploit speed difference between left and right sources
 //       (implement sweep area from progressive merge join paper)
 
+#[pin_project]
 #[derive(NamedType)]
-pub struct OrderedMergeJoin<L: Stream, R: Stream, D> {
-    left: stream::Peekable<L>,
-    right: stream::Peekable<R>,
+pub struct OrderedMergeJoin<L: TryStream, R: TryStream, D> {
+    #[pin]
+    left: stream::Peekable<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Peekable<stream::IntoStream<R>>,
     definition: D,
-    eq_buffer: Vec<L::Item>,
+    eq_buffer: Vec<L::Ok>,
     eq_cursor: usize,
     replay_mode: bool,
+    // only set for a `CheckedMergeJoin`
+    check: Option<Box<OrderCheck<L::Ok, R::Ok>>>,
+}
+
+/// An `OrderedMergeJoin` that validates the order of its inputs.
+///
+/// Instead of producing wrong results for unsorted inputs or panicking on tuples that
+/// `MergePredicate::cmp` can't compare, this join fails with an `OrderError`. Afterwards, it
+/// yields no more tuples.
+#[pin_project]
+#[derive(NamedType)]
+pub struct CheckedMergeJoin<L: TryStream, R: TryStream, D> {
+    #[pin]
+    join: OrderedMergeJoin<L, R, D>,
+}
+
+/// Identifies one of the two inputs of a join.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// Error of a `CheckedMergeJoin`.
+///
+/// Positions count the tuples of an input starting at 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderError<E> {
+    /// One of the input streams failed.
+    Input(E),
+    /// The tuple at `position` of the `side` input sorts before its predecessor.
+    OutOfOrder { side: Side, position: usize },
+    /// `MergePredicate::cmp` returned `None` for the tuples at these positions.
+    Incomparable { left: usize, right: usize },
+}
+impl<E> From<E> for OrderError<E> {
+    fn from(e: E) -> OrderError<E> {
+        OrderError::Input(e)
+    }
+}
+impl<E: fmt::Display> fmt::Display for OrderError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderError::Input(e) => write!(f, "input error: {}", e),
+            OrderError::OutOfOrder { side, position } => write!(f, "{:?} input is out of order at position {}", side, position),
+            OrderError::Incomparable { left, right } => write!(f, "left tuple {} and right tuple {} are incomparable", left, right),
+        }
+    }
+}
+impl<E: Error + 'static> Error for OrderError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OrderError::Input(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+type CheckedItem<D, E> = Result<<D as InnerJoinPredicate>::Output, OrderError<E>>;
+
+struct OrderCheck<L, R> {
+    left: InputCheck<L>,
+    right: InputCheck<R>,
+    failed: bool,
+}
+
+/// Tracks the tuples taken from one input.
+struct InputCheck<T> {
+    taken: usize,
+    // the last tuple taken, unless it's still held elsewhere
+    last: Option<T>,
+    head_checked: bool,
+}
+
+impl<T> InputCheck<T> {
+    fn new() -> Self {
+        InputCheck { taken: 0, last: None, head_checked: false }
+    }
+
+    /// Fails if `head`, the next tuple of the input, sorts before the last tuple taken.
+    ///
+    /// `held` is the last tuple taken if it isn't kept as `last`.
+    fn check_head<E>(&mut self, side: Side, held: Option<&T>, head: &T, cmp: impl FnOnce(&T, &T) -> Ordering) -> Result<(), OrderError<E>> {
+        if self.head_checked {
+            return Ok(());
+        }
+        self.head_checked = true;
+        match self.last.as_ref().or(held) {
+            Some(previous) if cmp(previous, head) == Ordering::Greater => Err(OrderError::OutOfOrder { side, position: self.taken }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records that the head was taken, keeping it as `last` unless it's held elsewhere.
+    fn take(&mut self, last: Option<T>) {
+        self.taken += 1;
+        self.last = last;
+        self.head_checked = false;
+    }
+}
+
+/// Peeks at the next tuple of a fallible stream.
+///
+/// Errors are not peeked at but taken out of the stream right away.
+pub(crate) fn poll_peek_ok<'a, S: TryStream>(mut s: Pin<&'a mut stream::Peekable<stream::IntoStream<S>>>, cx: &mut Context<'_>) -> Poll<Result<Option<&'a S::Ok>, S::Error>> {
+    if let Poll::Ready(Some(Err(_))) = s.as_mut().poll_peek(cx) {
+        match s.poll_next(cx) {
+            Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+            _ => unreachable!(),
+        }
+    }
+    s.poll_peek(cx).map(|x| Ok(x.map(|x| match x {
+        Ok(x) => x,
+        Err(_) => unreachable!(),
+    })))
+}
+
+/// Takes the tuple that was previously peeked at using `poll_peek_ok`.
+pub(crate) fn take_peeked<S: TryStream>(s: Pin<&mut stream::Peekable<stream::IntoStream<S>>>, cx: &mut Context<'_>) -> S::Ok {
+    match s.poll_next(cx) {
+        Poll::Ready(Some(Ok(x))) => x,
+        _ => unreachable!(),
+    }
 }
 
 impl<L, R, D> Stream for OrderedMergeJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error>,
-          L::Item: Borrow<D::Left>,
-          R::Item: Borrow<D::Right>,
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
           D: InnerJoinPredicate + MergePredicate {
-    type Item = D::Output;
-    type Error = L::Error;
+    type Item = Result<D::Output, L::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // order errors only occur with checks enabled by `check_order`
+        self.poll_join(cx).map_err(|e| match e {
+            OrderError::Input(e) => e,
+            OrderError::OutOfOrder { side, position } => panic!("{:?} input is out of order at position {}", side, position),
+            OrderError::Incomparable { .. } => panic!("`MergePredicate::cmp` found tuples incomparable"),
+        })
+    }
+}
+
+impl<L, R, D> Stream for CheckedMergeJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: InnerJoinPredicate + MergePredicate {
+    type Item = CheckedItem<D, L::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().join.poll_join(cx)
+    }
+}
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+impl<L, R, D> OrderedMergeJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: InnerJoinPredicate + MergePredicate {
+    fn poll_join(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<CheckedItem<D, L::Error>>> {
+        let mut this = self.project();
+        if this.check.as_ref().is_some_and(|check| check.failed) {
+            return Poll::Ready(None);
+        }
         let mut ret = None;
         while ret.is_none() {
             let order = {
-                let right = try_ready!(self.right.peek());
-                let left = if self.replay_mode {
-                    let x = &self.eq_buffer[self.eq_cursor];
-                    self.eq_cursor += 1;
+                let right = ready!(poll_peek_ok(this.right.as_mut(), cx))?;
+                let left = if *this.replay_mode {
+                    let x = &this.eq_buffer[*this.eq_cursor];
+                    *this.eq_cursor += 1;
                     Some(x)
                 } else {
-                    try_ready!(self.left.peek())
+                    ready!(poll_peek_ok(this.left.as_mut(), cx))?
                 };
+                if let Some(check) = this.check.as_mut() {
+                    let definition = &*this.definition;
+                    let mut result = Ok(());
+                    if let Some(r) = right {
+                        result = check.right.check_head(Side::Right, None, r, |a, b| definition.cmp_right(a.borrow(), b.borrow()));
+                    }
+                    if let (Ok(()), Some(l), false) = (&result, left, *this.replay_mode) {
+                        result = check.left.check_head(Side::Left, this.eq_buffer.last(), l, |a, b| definition.cmp_left(a.borrow(), b.borrow()));
+                    }
+                    if let Err(e) = result {
+                        check.failed = true;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                }
                 //println!("matching {:?} vs {:?}", left.debug(), right.debug());
                 match (left, right) {
                     (Some(l), Some(r)) => {
-                        ret = self.definition.eq(l.borrow(), r.borrow());
-                        self.definition.cmp(l.borrow(), r.borrow()).unwrap()
+                        ret = this.definition.eq(l.borrow(), r.borrow());
+                        match (this.definition.cmp(l.borrow(), r.borrow()), this.check.as_mut()) {
+                            (Some(order), _) => order,
+                            (None, Some(check)) => {
+                                check.failed = true;
+                                // in replay mode, the left tuple is one of the last ones taken
+                                let left = if *this.replay_mode {
+                                    check.left.taken - (this.eq_buffer.len() - (*this.eq_cursor - 1))
+                                } else {
+                                    check.left.taken
+                                };
+                                return Poll::Ready(Some(Err(OrderError::Incomparable { left, right: check.right.taken })));
+                            }
+                            (None, None) => panic!("`MergePredicate::cmp` found tuples incomparable"),
+                        }
                     }
-                    (None, _) if !self.eq_buffer.is_empty() => Ordering::Greater,
+                    (None, _) if !this.eq_buffer.is_empty() => Ordering::Greater,
                     _ => break,
                 }
             };
 
             match order {
                 Ordering::Less => {
-                    if self.replay_mode {
-                        self.eq_buffer.clear();
-                        self.eq_cursor = 0;
-                        self.replay_mode = false;
+                    if *this.replay_mode {
+                        if let Some(check) = this.check.as_mut() {
+                            check.left.last = this.eq_buffer.pop();
+                        }
+                        this.eq_buffer.clear();
+                        *this.eq_cursor = 0;
+                        *this.replay_mode = false;
                     } else {
-                        if let Async::NotReady = self.left.poll()? {
-                            unreachable!();
+                        let left = take_peeked(this.left.as_mut(), cx);
+                        if let Some(check) = this.check.as_mut() {
+                            check.left.take(Some(left));
                         }
                     }
                 }
                 Ordering::Greater => {
-                    assert!(!self.replay_mode);
-                    if !self.eq_buffer.is_empty() {
+                    assert!(!*this.replay_mode);
+                    if !this.eq_buffer.is_empty() {
                         //println!("entering replay mode with {:?}", self.eq_buffer);
-                        self.replay_mode = true;
+                        *this.replay_mode = true;
                     }
 
-                    if let Async::NotReady = self.right.poll()? {
-                        unreachable!();
+                    let right = take_peeked(this.right.as_mut(), cx);
+                    if let Some(check) = this.check.as_mut() {
+                        check.right.take(Some(right));
                     }
                 }
                 Ordering::Equal => {
-                    if self.replay_mode {
-                        if self.eq_cursor >= self.eq_buffer.len() {
-                            if let Async::NotReady = self.right.poll()? {
-                                unreachable!();
+                    if *this.replay_mode {
+                        if *this.eq_cursor >= this.eq_buffer.len() {
+                            let right = take_peeked(this.right.as_mut(), cx);
+                            if let Some(check) = this.check.as_mut() {
+                                check.right.take(Some(right));
                             }
-                            self.eq_cursor = 0;
+                            *this.eq_cursor = 0;
                         }
                     } else {
-                        match self.left.poll()? {
-                            Async::Ready(Some(left)) => self.eq_buffer.push(left),
-                            _ => unreachable!(),
+                        let left = take_peeked(this.left.as_mut(), cx);
+                        this.eq_buffer.push(left);
+                        if let Some(check) = this.check.as_mut() {
+                            check.left.take(None);
                         }
                     }
                 }
             }
         }
-        Ok(Async::Ready(ret))
+        Poll::Ready(ret.map(Ok))
     }
 }
 
 
 impl<L, R, D> OrderedMergeJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error>,
-          L::Item: Borrow<D::Left>,
-          R::Item: Borrow<D::Right>,
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: MergePredicate {
+    pub fn new(left: L, right: R, definition: D) -> Self {
+        OrderedMergeJoin { left: left.into_stream().peekable(), right: right.into_stream().peekable(), definition, eq_buffer: Vec::new(), eq_cursor: 0, replay_mode: false, check: None }
+    }
+
+    /// Hands back the inputs, dropping the tuples the join still holds.
+    pub(crate) fn into_inputs(self) -> (L, R) {
+        (self.left.into_inner().into_inner(), self.right.into_inner().into_inner())
+    }
+
+    /// The inputs, whose next tuples the join may have peeked at already.
+    pub(crate) fn inputs_mut(&mut self) -> (&mut L, &mut R) {
+        (self.left.get_mut().get_mut(), self.right.get_mut().get_mut())
+    }
+
+    /// Panics once either input turns out to be out of order.
+    pub(crate) fn check_order(mut self) -> Self {
+        self.check = Some(Box::new(OrderCheck { left: InputCheck::new(), right: InputCheck::new(), failed: false }));
+        self
+    }
+}
+
+impl<L, R, D> CheckedMergeJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
           D: MergePredicate {
     pub fn new(left: L, right: R, definition: D) -> Self {
-        OrderedMergeJoin { left: left.peekable(), right: right.peekable(), definition, eq_buffer: Vec::new(), eq_cursor: 0, replay_mode: false }
+        CheckedMergeJoin { join: OrderedMergeJoin::new(left, right, definition).check_order() }
     }
 }
 
 impl<L, R, D, E> Join<L, R, D, E, ()> for OrderedMergeJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error>,
-          L::Item: Borrow<D::Left>,
-          R::Item: Borrow<D::Right>,
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
           D: InnerJoinPredicate + MergePredicate {
     fn build(left: L, right: R, definition: D, _: E, _: ()) -> Self {
         OrderedMergeJoin::new(left, right, definition)
     }
 }
 
+
+impl<L, R, D, E> Join<L, R, D, E, ()> for CheckedMergeJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: InnerJoinPredicate + MergePredicate {
+    fn build(left: L, right: R, definition: D, _: E, _: ()) -> Self {
+        CheckedMergeJoin::new(left, right, definition)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Ordering;
+    use std::convert::Infallible;
+    use futures::TryStreamExt;
+    use futures::executor::block_on;
+    use crate::{CheckedMergeJoin, EquiJoin, IterSource, OrderError, Side};
+
+    fn checked_join(left: Vec<u32>, right: Vec<u32>) -> (Vec<(u32, u32)>, Option<OrderError<Infallible>>) {
+        let mut join = CheckedMergeJoin::new(
+            IterSource::new(left),
+            IterSource::new(right),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+        );
+        let mut results = Vec::new();
+        loop {
+            match block_on(join.try_next()) {
+                Ok(Some(x)) => results.push(x),
+                Ok(None) => return (results, None),
+                Err(e) => {
+                    assert_eq!(None, block_on(join.try_next()).unwrap());
+                    return (results, Some(e));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn checked_merge_out_of_order() {
+        let (results, error) = checked_join(vec![1, 2, 2, 3, 5], vec![0, 2, 2, 4, 5]);
+        assert_eq!(vec![(2, 2), (2, 2), (2, 2), (2, 2), (5, 5)], results);
+        assert_eq!(None, error);
+
+        let (_, error) = checked_join(vec![1, 2, 2, 3, 1], vec![0, 2, 2, 4, 5]);
+        assert_eq!(Some(OrderError::OutOfOrder { side: Side::Left, position: 4 }), error);
+        // detected while replaying the equal left tuples
+        let (_, error) = checked_join(vec![1, 2, 2, 3], vec![0, 2, 1, 4]);
+        assert_eq!(Some(OrderError::OutOfOrder { side: Side::Right, position: 2 }), error);
+    }
+
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct Key(u32);
+    /// Only comparable to keys that aren't 13.
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct Unlucky(u32);
+    impl PartialEq<Unlucky> for Key {
+        fn eq(&self, other: &Unlucky) -> bool {
+            self.0 == other.0
+        }
+    }
+    impl PartialOrd<Unlucky> for Key {
+        fn partial_cmp(&self, other: &Unlucky) -> Option<Ordering> {
+            (other.0 != 13).then(|| self.0.cmp(&other.0))
+        }
+    }
+
+    #[test]
+    fn checked_merge_incomparable() {
+        let join = CheckedMergeJoin::new(
+            IterSource::new(vec![1u32, 2, 2, 13]),
+            IterSource::new(vec![2u32, 13]),
+            EquiJoin::new(|&l: &u32| Key(l), |&r: &u32| Unlucky(r)),
+        );
+        // the left tuples equal to 2 are replayed against 13
+        assert_eq!(Err(OrderError::Incomparable { left: 1, right: 1 }), block_on(join.try_collect::<Vec<_>>()));
+    }
+}
@@ -0,0 +1,80 @@
+use std::borrow::Borrow;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, stream};
+use named_type::NamedType;
+use named_type_derive::*;
+use pin_project::pin_project;
+use crate::OuterJoinPredicate;
+
+use super::Join;
+use super::ordered_merge_semi::poll_filter_by_group;
+use crate::predicate::MergePredicate;
+
+/// Anti-join of two sorted inputs.
+///
+/// Yields every left tuple that has no join partner (`NOT EXISTS`), in input order.
+/// Both inputs are read exactly once, only right tuples comparing equal to the current
+/// left tuple are buffered.
+#[pin_project]
+#[derive(NamedType)]
+pub struct OrderedMergeAntiJoin<L: TryStream, R: TryStream, D> {
+    #[pin]
+    left: stream::Peekable<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Peekable<stream::IntoStream<R>>,
+    definition: D,
+    group: Vec<R::Ok>,
+}
+
+impl<L, R, D> Stream for OrderedMergeAntiJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: OuterJoinPredicate + MergePredicate {
+    type Item = Result<L::Ok, L::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        poll_filter_by_group(this.left, this.right, &*this.definition, this.group, false, cx)
+    }
+}
+
+impl<L, R, D> OrderedMergeAntiJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: MergePredicate {
+    pub fn new(left: L, right: R, definition: D) -> Self {
+        OrderedMergeAntiJoin { left: left.into_stream().peekable(), right: right.into_stream().peekable(), definition, group: Vec::new() }
+    }
+}
+
+impl<L, R, D, E> Join<L, R, D, E, ()> for OrderedMergeAntiJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: OuterJoinPredicate + MergePredicate {
+    fn build(left: L, right: R, definition: D, _: E, _: ()) -> Self {
+        OrderedMergeAntiJoin::new(left, right, definition)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{EquiJoin, JoinInMemory, OrderedMergeAntiJoin};
+
+    #[test]
+    fn ordered_merge_anti() {
+        let join = OrderedMergeAntiJoin::build_in_memory(
+            vec![1, 2, 2, 3, 5, 5, 8, 10],
+            vec![2, 2, 2, 4, 5, 8, 8, 9],
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            (),
+        );
+        assert_eq!(vec![1, 3, 10], join.collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,135 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
+use named_type::NamedType;
+use named_type_derive::*;
+use pin_project::pin_project;
+use crate::OuterJoinPredicate;
+
+use super::Join;
+use super::ordered_merge::{poll_peek_ok, take_peeked};
+use crate::predicate::MergePredicate;
+
+/// Semi-join of two sorted inputs.
+///
+/// Yields every left tuple that has at least one join partner, in input order.
+/// The right tuples comparing equal to the current left tuple are buffered, so duplicate
+/// keys on either side are handled without emitting a left tuple twice.
+#[pin_project]
+#[derive(NamedType)]
+pub struct OrderedMergeSemiJoin<L: TryStream, R: TryStream, D> {
+    #[pin]
+    left: stream::Peekable<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Peekable<stream::IntoStream<R>>,
+    definition: D,
+    group: Vec<R::Ok>,
+}
+
+impl<L, R, D> Stream for OrderedMergeSemiJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: OuterJoinPredicate + MergePredicate {
+    type Item = Result<L::Ok, L::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        poll_filter_by_group(this.left, this.right, &*this.definition, this.group, true, cx)
+    }
+}
+
+/// Yields the next left tuple that has (`exists`) or lacks (`!exists`) a join partner.
+///
+/// `group` buffers the right tuples comparing equal to the current left tuple.
+pub(crate) fn poll_filter_by_group<L, R, D>(
+    mut left: Pin<&mut stream::Peekable<stream::IntoStream<L>>>,
+    mut right: Pin<&mut stream::Peekable<stream::IntoStream<R>>>,
+    definition: &D,
+    group: &mut Vec<R::Ok>,
+    exists: bool,
+    cx: &mut Context<'_>) -> Poll<Option<Result<L::Ok, L::Error>>>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: OuterJoinPredicate + MergePredicate {
+    loop {
+        let order = {
+            let l = match ready!(poll_peek_ok(left.as_mut(), cx))? {
+                Some(l) => l.borrow(),
+                None => return Poll::Ready(None),
+            };
+            if let Some(r) = group.first() {
+                if definition.cmp(l, r.borrow()).unwrap() != Ordering::Equal {
+                    // left moved past the buffered group
+                    group.clear();
+                }
+            }
+            match ready!(poll_peek_ok(right.as_mut(), cx))? {
+                Some(r) => definition.cmp(l, r.borrow()).unwrap(),
+                // no more join partners for any left tuple
+                None if exists && group.is_empty() => return Poll::Ready(None),
+                None => Ordering::Less,
+            }
+        };
+
+        match order {
+            Ordering::Less => {
+                // the group of join partners is complete
+                let l = take_peeked(left.as_mut(), cx);
+                if group.iter().any(|r| definition.eq(l.borrow(), r.borrow())) == exists {
+                    return Poll::Ready(Some(Ok(l)));
+                }
+            }
+            Ordering::Equal => {
+                let r = take_peeked(right.as_mut(), cx);
+                group.push(r);
+            }
+            Ordering::Greater => {
+                take_peeked(right.as_mut(), cx);
+            }
+        }
+    }
+}
+
+impl<L, R, D> OrderedMergeSemiJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: MergePredicate {
+    pub fn new(left: L, right: R, definition: D) -> Self {
+        OrderedMergeSemiJoin { left: left.into_stream().peekable(), right: right.into_stream().peekable(), definition, group: Vec::new() }
+    }
+}
+
+impl<L, R, D, E> Join<L, R, D, E, ()> for OrderedMergeSemiJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: OuterJoinPredicate + MergePredicate {
+    fn build(left: L, right: R, definition: D, _: E, _: ()) -> Self {
+        OrderedMergeSemiJoin::new(left, right, definition)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{EquiJoin, JoinInMemory, OrderedMergeSemiJoin};
+
+    #[test]
+    fn ordered_merge_semi() {
+        let join = OrderedMergeSemiJoin::build_in_memory(
+            vec![1, 2, 2, 3, 5, 5, 8],
+            vec![2, 2, 2, 4, 5, 8, 8, 9],
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            (),
+        );
+        assert_eq!(vec![2, 2, 5, 5, 8], join.collect::<Vec<_>>());
+    }
+}
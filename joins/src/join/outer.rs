@@ -0,0 +1,55 @@
+use crate::predicate::{InnerJoinPredicate, PadLeftPredicate, PadRightPredicate};
+
+/// Selects which unmatched tuples an outer join emits.
+///
+/// Implemented by the marker types `LeftOuter`, `RightOuter` and `FullOuter`.
+pub trait OuterJoinKind<D: InnerJoinPredicate> {
+    /// Whether left tuples without join partner are emitted.
+    const LEFT: bool;
+    /// Whether right tuples without join partner are emitted.
+    const RIGHT: bool;
+
+    fn pad_right(definition: &D, left: &D::Left) -> Option<D::Output>;
+    fn pad_left(definition: &D, right: &D::Right) -> Option<D::Output>;
+}
+
+/// Emits unmatched left tuples, requires a `PadRightPredicate`.
+pub struct LeftOuter;
+/// Emits unmatched right tuples, requires a `PadLeftPredicate`.
+pub struct RightOuter;
+/// Emits unmatched tuples of both sides, requires both `PadLeftPredicate` and `PadRightPredicate`.
+pub struct FullOuter;
+
+impl<D: PadRightPredicate> OuterJoinKind<D> for LeftOuter {
+    const LEFT: bool = true;
+    const RIGHT: bool = false;
+
+    fn pad_right(definition: &D, left: &D::Left) -> Option<D::Output> {
+        Some(definition.pad_right(left))
+    }
+    fn pad_left(_: &D, _: &D::Right) -> Option<D::Output> {
+        None
+    }
+}
+impl<D: PadLeftPredicate> OuterJoinKind<D> for RightOuter {
+    const LEFT: bool = false;
+    const RIGHT: bool = true;
+
+    fn pad_right(_: &D, _: &D::Left) -> Option<D::Output> {
+        None
+    }
+    fn pad_left(definition: &D, right: &D::Right) -> Option<D::Output> {
+        Some(definition.pad_left(right))
+    }
+}
+impl<D: PadLeftPredicate + PadRightPredicate> OuterJoinKind<D> for FullOuter {
+    const LEFT: bool = true;
+    const RIGHT: bool = true;
+
+    fn pad_right(definition: &D, left: &D::Left) -> Option<D::Output> {
+        Some(definition.pad_right(left))
+    }
+    fn pad_left(definition: &D, right: &D::Right) -> Option<D::Output> {
+        Some(definition.pad_left(right))
+    }
+}
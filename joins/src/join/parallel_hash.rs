@@ -0,0 +1,357 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::panic;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+use futures::{SinkExt, Stream, TryStream, StreamExt, TryStreamExt, executor, ready, stream};
+use futures::channel::mpsc;
+use multimap::MultiMap;
+use named_type::NamedType;
+use named_type_derive::*;
+use pin_project::pin_project;
+use crate::{HeapSize, InnerJoinPredicate};
+
+use super::{Join, Rescan};
+use super::grace_hash::partition_index;
+use crate::predicate::HashPredicate;
+
+/// Batches per shard that exist at once: one being filled, one queued and one being joined.
+const IN_FLIGHT: usize = 3;
+
+/// Configuration of a `ParallelHashJoin`.
+#[derive(Clone, Copy, Debug)]
+pub struct ParallelConfig {
+    /// Maximum size in bytes of the tuples held by the join.
+    ///
+    /// Half of it goes to the hash tables of all shards together, the other half to the batches
+    /// on their way to the workers.
+    pub memory_limit: usize,
+    /// Number of shards, each of which is joined by its own worker thread.
+    pub num_shards: usize,
+}
+
+/// A hash join that spreads the work across `num_shards` threads.
+///
+/// Both inputs are hash-partitioned into shards by the calling task. Every shard's worker
+/// builds a hash table from its left tuples and probes its right tuples against it, sending
+/// the results back to the stream. Like in a `SimpleHashJoin`, the right input is rescanned
+/// whenever the left input doesn't fit into the tables. Reading the inputs waits while a worker
+/// falls behind, and workers wait while their results aren't consumed.
+///
+/// Results are emitted in no particular order.
+#[pin_project]
+#[derive(NamedType)]
+pub struct ParallelHashJoin<L: TryStream, R: TryStream, D: InnerJoinPredicate + HashPredicate> {
+    definition: Arc<D>,
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: R,
+    // `None` once both inputs are exhausted
+    shards: Option<Vec<Shard<L::Ok, R::Ok>>>,
+    flush: Flush,
+    results: mpsc::Receiver<Vec<D::Output>>,
+    workers: Vec<JoinHandle<()>>,
+    // bytes sent to the workers' tables during the current pass
+    table_size: usize,
+    table_limit: usize,
+    batch_limit: usize,
+    output_buffer: VecDeque<D::Output>,
+}
+
+/// Shards whose buffered tuples have to be handed to the workers before reading on.
+#[derive(Clone, Copy)]
+enum Flush {
+    None,
+    /// The shard's buffer is full.
+    Full(usize),
+    /// The right input is exhausted, the shards from this one on have yet to be reset.
+    Reset(usize),
+    /// Both inputs are exhausted, the shards from this one on have yet to be flushed.
+    Close(usize),
+}
+
+enum Batch<L, R> {
+    Left(Vec<(u64, L)>),
+    Right(Vec<(u64, R)>),
+    /// Start over with an empty table.
+    Reset,
+}
+
+struct Shard<L, R> {
+    sender: mpsc::Sender<Batch<L, R>>,
+    left: Vec<(u64, L)>,
+    right: Vec<(u64, R)>,
+    // bytes of the buffered tuples
+    size: usize,
+}
+impl<L, R> Shard<L, R> {
+    fn new(sender: mpsc::Sender<Batch<L, R>>) -> Self {
+        Shard { sender, left: Vec::new(), right: Vec::new(), size: 0 }
+    }
+    fn push_left(&mut self, hash: u64, item: L, size: usize) {
+        self.left.push((hash, item));
+        self.size += size;
+    }
+    fn push_right(&mut self, hash: u64, item: R, size: usize) {
+        self.right.push((hash, item));
+        self.size += size;
+    }
+    /// Hands all buffered tuples to the worker, left ones first.
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.left.is_empty() {
+            ready!(self.poll_ready(cx));
+            let _ = self.sender.start_send(Batch::Left(mem::take(&mut self.left)));
+        }
+        if !self.right.is_empty() {
+            ready!(self.poll_ready(cx));
+            let _ = self.sender.start_send(Batch::Right(mem::take(&mut self.right)));
+        }
+        self.size = 0;
+        Poll::Ready(())
+    }
+    fn poll_reset(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        ready!(self.poll_flush(cx));
+        ready!(self.poll_ready(cx));
+        let _ = self.sender.start_send(Batch::Reset);
+        Poll::Ready(())
+    }
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        // sending fails only if the worker panicked, which the stream reports once all results are in
+        let _ = ready!(self.sender.poll_ready(cx));
+        Poll::Ready(())
+    }
+}
+
+fn worker<D: InnerJoinPredicate>(definition: Arc<D>, batches: mpsc::Receiver<Batch<D::Left, D::Right>>, mut results: mpsc::Sender<Vec<D::Output>>) {
+    let definition = &*definition;
+    let mut table = MultiMap::new();
+    for batch in executor::block_on_stream(batches) {
+        match batch {
+            Batch::Left(tuples) => table.extend(tuples),
+            Batch::Right(tuples) => {
+                let output: Vec<_> = tuples.iter()
+                    .flat_map(|(hash, right)| table.get_vec(hash).into_iter().flatten().filter_map(move |left| definition.eq(left, right)))
+                    .collect();
+                if !output.is_empty() && executor::block_on(results.send(output)).is_err() {
+                    // nobody is listening anymore
+                    return;
+                }
+            }
+            Batch::Reset => table.clear(),
+        }
+    }
+}
+
+impl<L, R, D> Stream for ParallelHashJoin<L, R, D>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error> + Rescan,
+          R::Ok: HeapSize,
+          D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok> {
+    type Item = Result<D::Output, L::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            if let Some(buffered) = this.output_buffer.pop_front() {
+                return Poll::Ready(Some(Ok(buffered)));
+            }
+            match this.results.poll_next_unpin(cx) {
+                Poll::Ready(Some(results)) => {
+                    this.output_buffer.extend(results);
+                    continue;
+                }
+                Poll::Ready(None) => {
+                    // all workers are done, surface their panics
+                    for worker in this.workers.drain(..) {
+                        if let Err(panic) = worker.join() {
+                            panic::resume_unwind(panic);
+                        }
+                    }
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => {}
+            }
+            let shards = match this.shards {
+                Some(shards) => shards,
+                // waiting for the workers to finish
+                None => return Poll::Pending,
+            };
+
+            // while waiting for a worker, its results are still being received
+            match *this.flush {
+                Flush::None => {}
+                Flush::Full(i) => {
+                    ready!(shards[i].poll_flush(cx));
+                    *this.flush = Flush::None;
+                    continue;
+                }
+                Flush::Reset(i) if i < shards.len() => {
+                    ready!(shards[i].poll_reset(cx));
+                    *this.flush = Flush::Reset(i + 1);
+                    continue;
+                }
+                Flush::Reset(_) => {
+                    // probe phase complete, return to build phase
+                    this.right.as_mut().rescan();
+                    *this.table_size = 0;
+                    *this.flush = Flush::None;
+                    continue;
+                }
+                Flush::Close(i) if i < shards.len() => {
+                    ready!(shards[i].poll_flush(cx));
+                    *this.flush = Flush::Close(i + 1);
+                    continue;
+                }
+                Flush::Close(_) => {
+                    // all input complete, closing the channels lets the workers finish
+                    *this.shards = None;
+                    continue;
+                }
+            }
+
+            let definition = &**this.definition;
+            let shard = if (*this.table_size < *this.table_limit) && !this.left.is_done() {
+                // build phase
+                match ready!(this.left.as_mut().try_poll_next(cx)?) {
+                    Some(left) => {
+                        let size = left.memory_size();
+                        *this.table_size += size;
+                        let hash = definition.hash_left(&left);
+                        let shard = partition_index(hash, 0, shards.len());
+                        shards[shard].push_left(hash, left, size);
+                        shard
+                    }
+                    None => continue,
+                }
+            } else if let Some(right) = ready!(this.right.as_mut().try_poll_next(cx)?) {
+                // probe phase
+                let size = right.memory_size();
+                let hash = definition.hash_right(&right);
+                let shard = partition_index(hash, 0, shards.len());
+                shards[shard].push_right(hash, right, size);
+                shard
+            } else {
+                *this.flush = if this.left.is_done() { Flush::Close(0) } else { Flush::Reset(0) };
+                continue;
+            };
+            if shards[shard].size >= *this.batch_limit {
+                *this.flush = Flush::Full(shard);
+            }
+        }
+    }
+}
+impl<L, R, D, E> Join<L, R, D, E, ParallelConfig> for ParallelHashJoin<L, R, D>
+    where L: TryStream,
+          L::Ok: HeapSize + Send + 'static,
+          R: TryStream<Error=L::Error> + Rescan,
+          R::Ok: HeapSize + Send + 'static,
+          D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok> + Send + Sync + 'static,
+          D::Output: Send + 'static {
+    fn build(left: L, right: R, definition: D, _: E, config: ParallelConfig) -> Self {
+        assert!(config.num_shards > 0);
+        assert!(config.memory_limit > 0);
+
+        let definition = Arc::new(definition);
+        // every worker can queue one vector of results
+        let (results_tx, results) = mpsc::channel(0);
+        let mut shards = Vec::new();
+        let mut workers = Vec::new();
+        for _ in 0..config.num_shards {
+            // with its one sender, the channel holds a single batch
+            let (sender, batches) = mpsc::channel(0);
+            let definition = Arc::clone(&definition);
+            let results_tx = results_tx.clone();
+            shards.push(Shard::new(sender));
+            workers.push(thread::spawn(move || worker(definition, batches, results_tx)));
+        }
+
+        ParallelHashJoin {
+            definition,
+            left: left.into_stream().fuse(),
+            right,
+            shards: Some(shards),
+            flush: Flush::None,
+            results,
+            workers,
+            table_size: 0,
+            table_limit: config.memory_limit / 2,
+            batch_limit: config.memory_limit / 2 / config.num_shards / IN_FLIGHT,
+            output_buffer: VecDeque::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+    use std::pin::Pin;
+    use std::sync::{Arc, RwLock};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Context;
+    use std::thread;
+    use futures::{Stream, TryStreamExt};
+    use futures::executor::block_on;
+    use futures::task::noop_waker_ref;
+    use crate::{EquiJoin, IterSource, Join, ParallelConfig, ParallelHashJoin};
+
+    fn parallel_join(left: Vec<i32>, right: Vec<i32>, memory_limit: usize) -> Vec<(i32, i32)> {
+        // the workers' results arrive asynchronously, so `JoinInMemory` won't do
+        let join = ParallelHashJoin::build(
+            IterSource::new(left),
+            IterSource::new(right),
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            (),
+            ParallelConfig { memory_limit, num_shards: 4 },
+        );
+        let mut results: Vec<_> = block_on(join.try_collect()).unwrap();
+        results.sort_unstable();
+        results
+    }
+
+    #[test]
+    fn parallel_hash() {
+        let left: Vec<_> = (0..5000).map(|x| x % 3000).collect();
+        let right: Vec<_> = (0..4000).rev().collect();
+        let mut expected: Vec<_> = left.iter().filter(|&&l| l < 4000).map(|&l| (l, l)).collect();
+        expected.sort_unstable();
+        assert_eq!(expected, parallel_join(left.clone(), right.clone(), usize::MAX));
+        // several passes over the right input
+        assert_eq!(expected, parallel_join(left, right, 700 * mem::size_of::<i32>()));
+    }
+
+    #[test]
+    fn parallel_hash_backpressure() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&read);
+        let right = (0..100_000).inspect(move |_| { counter.fetch_add(1, Ordering::Relaxed); });
+        // the workers block on their first right tuple until the gate opens
+        let gate = Arc::new(RwLock::new(()));
+        let closed = gate.write().unwrap();
+        let main = thread::current().id();
+        let worker_gate = Arc::clone(&gate);
+        let mut join = ParallelHashJoin::build(
+            IterSource::new(0..100),
+            IterSource::new(right),
+            EquiJoin::new(|&l: &i32| l, move |&r: &i32| {
+                if thread::current().id() != main {
+                    drop(worker_gate.read().unwrap());
+                }
+                r % 100
+            }),
+            (),
+            ParallelConfig { memory_limit: 2048, num_shards: 4 },
+        );
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(Pin::new(&mut join).poll_next(&mut cx).is_pending());
+        // a few batches per shard, rather than the whole right input
+        assert!(read.load(Ordering::Relaxed) < 1000);
+
+        drop(closed);
+        let results: Vec<_> = block_on(join.try_collect()).unwrap();
+        assert_eq!(100_000, results.len());
+    }
+}
@@ -0,0 +1,358 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc as std_mpsc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::vec;
+use futures::{SinkExt, Stream, StreamExt, ready, stream};
+use futures::channel::{mpsc, oneshot};
+use futures::executor::{block_on, block_on_stream};
+use pin_project::pin_project;
+
+use super::AsyncExternal;
+use super::sort_merge::StreamMerger;
+use crate::HeapSize;
+use crate::predicate::MergePredicate;
+
+/// Batches per run or merging thread that exist at once: one being filled, one queued and one
+/// being merged.
+const IN_FLIGHT: usize = 3;
+
+/// Configuration of a `SortMergeJoin` or `SortMergeAntiJoin` that sorts on background threads.
+#[derive(Clone, Copy, Debug)]
+pub struct ParallelSortConfig {
+    /// Maximum size in bytes of the tuples buffered for sorting, shared by all runs being
+    /// sorted at the same time.
+    ///
+    /// When merging, it also bounds the batches of tuples on their way to and from the
+    /// merging threads.
+    pub memory_limit: usize,
+    /// Number of threads per input, sorting as many runs or merging as many groups of runs at
+    /// the same time.
+    pub threads: usize,
+    /// Maximum number of runs per input merged at once, like `SortMergeConfig::fan_in`.
+    ///
+    /// Intermediate merge passes run on the executor, only the final merge uses the threads.
+    pub fan_in: usize,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads running jobs in the order they were submitted.
+///
+/// The threads exit once the pool is dropped and all submitted jobs are done.
+struct Pool {
+    jobs: std_mpsc::Sender<Job>,
+}
+
+impl Pool {
+    fn new(size: usize) -> Self {
+        let (jobs, queue) = std_mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..size {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || loop {
+                let job = queue.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(std_mpsc::RecvError) => return,
+                }
+            });
+        }
+        Pool { jobs }
+    }
+
+    /// Runs `job` on the next free thread. Jobs must not panic.
+    fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        self.jobs.send(Box::new(job)).expect("pool threads outlive the pool");
+    }
+
+    /// Runs `job` on the next free thread, passing its result or panic to the receiver.
+    fn run<R: Send + 'static>(&self, job: impl FnOnce() -> R + Send + 'static) -> oneshot::Receiver<thread::Result<R>> {
+        let (sender, receiver) = oneshot::channel();
+        self.spawn(move || {
+            let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(job)));
+        });
+        receiver
+    }
+}
+
+type SortFn<T> = dyn Fn(&Pool, Vec<T>) -> SortTask<T> + Send + Sync;
+type MergeFn<T> = dyn Fn(&Pool, Vec<(usize, mpsc::Receiver<Vec<T>>)>, mpsc::Sender<Vec<(usize, T)>>, usize) -> MergeTask + Send + Sync;
+type MergeTask = oneshot::Receiver<thread::Result<()>>;
+
+/// Sorts and merges the runs of one input on a pool of background threads.
+///
+/// The thread bodies are set up when the join is built, which is the only place where the
+/// predicate and the tuples are known to be `Send`.
+pub(crate) struct Threads<T> {
+    count: usize,
+    pool: Pool,
+    // bytes of the batches handed to and from the merging threads
+    memory_limit: usize,
+    sort: Box<SortFn<T>>,
+    merge: Box<MergeFn<T>>,
+}
+
+impl<T: HeapSize + Send + 'static> Threads<T> {
+    pub(crate) fn new<P>(count: usize, predicate: P, memory_limit: usize) -> Self
+        where P: MergePredicate<Left=T> + Send + Sync + 'static {
+        assert!(count > 0);
+        let predicate = Arc::new(predicate);
+        let sort_predicate = Arc::clone(&predicate);
+        Threads {
+            count,
+            pool: Pool::new(count),
+            memory_limit,
+            sort: Box::new(move |pool, mut run| {
+                let predicate = Arc::clone(&sort_predicate);
+                SortTask(pool.run(move || {
+                    run.sort_by(|a, b| predicate.cmp_left(a, b));
+                    run
+                }))
+            }),
+            merge: Box::new(move |pool, runs, output, batch_limit| {
+                let predicate = Arc::clone(&predicate);
+                pool.run(move || merge_group(predicate, runs, output, batch_limit))
+            }),
+        }
+    }
+}
+
+impl<T: HeapSize> Threads<T> {
+    /// Sorts `run` on the next free thread.
+    pub(crate) fn sort(&self, run: Vec<T>) -> SortTask<T> {
+        (self.sort)(&self.pool, run)
+    }
+
+    /// Number of threads worth merging `runs` runs with, each of them merging at least two.
+    pub(crate) fn merge_groups(&self, runs: usize) -> usize {
+        self.count.min(runs / 2)
+    }
+
+    /// Merges `runs`, which are sorted according to `predicate`, on `merge_groups` threads.
+    ///
+    /// Half of the memory limit goes to the batches of the runs, the other half to the batches
+    /// merged by the threads.
+    pub(crate) fn merge<P, X>(&self, runs: Vec<X>, predicate: P) -> ParallelMerger<P, X>
+        where P: MergePredicate<Left=T>,
+              X: AsyncExternal<T> {
+        let num_groups = self.merge_groups(runs.len()).max(1);
+        let feeder_limit = self.memory_limit / 2 / IN_FLIGHT / runs.len().max(1);
+        let group_limit = self.memory_limit / 2 / IN_FLIGHT / num_groups;
+        let mut inputs: Vec<_> = (0..num_groups).map(|_| Vec::new()).collect();
+        let mut feeders = Vec::with_capacity(runs.len());
+        for (id, run) in runs.iter().enumerate() {
+            let (sender, receiver) = mpsc::channel(0);
+            feeders.push(Feeder { fetch: Box::pin(run.fetch()), sender, batch: Vec::new(), batch_size: 0, batch_limit: feeder_limit, size_of: T::memory_size, done: false });
+            inputs[id % num_groups].push((id, receiver));
+        }
+        let groups = inputs.into_iter()
+            .map(|runs| {
+                let (sender, output) = mpsc::channel(0);
+                let task = (self.merge)(&self.pool, runs, sender, group_limit);
+                Group { output, batch: Vec::new().into_iter(), head: None, task: Some(task) }
+            })
+            .collect();
+        ParallelMerger { feeders, groups, predicate }
+    }
+}
+
+/// A run being sorted by the pool, resolving to the sorted tuples.
+pub(crate) struct SortTask<T>(oneshot::Receiver<thread::Result<Vec<T>>>);
+
+impl<T> Future for SortTask<T> {
+    type Output = Vec<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Vec<T>> {
+        match ready!(Pin::new(&mut self.0).poll(cx)) {
+            Ok(Ok(run)) => Poll::Ready(run),
+            Ok(Err(panic)) => panic::resume_unwind(panic),
+            Err(oneshot::Canceled) => unreachable!("sorting threads always report back"),
+        }
+    }
+}
+
+/// Body of a merging thread: merges the runs received through `runs` into `output`, in batches
+/// of `batch_limit` bytes.
+fn merge_group<P: MergePredicate>(predicate: P, runs: Vec<(usize, mpsc::Receiver<Vec<P::Left>>)>, mut output: mpsc::Sender<Vec<(usize, P::Left)>>, batch_limit: usize)
+    where P::Left: HeapSize {
+    let (ids, runs): (Vec<_>, Vec<_>) = runs.into_iter().unzip();
+    let runs = runs.into_iter().map(|run| run.flat_map(stream::iter).map(Ok::<_, Infallible>));
+    let mut batch = Vec::new();
+    let mut batch_size = 0;
+    for next in block_on_stream(StreamMerger::new(runs, predicate)) {
+        let Ok((i, item)) = next;
+        batch_size += item.memory_size();
+        batch.push((ids[i], item));
+        if batch_size >= batch_limit {
+            batch_size = 0;
+            if block_on(output.send(mem::take(&mut batch))).is_err() {
+                // the merger was dropped
+                return;
+            }
+        }
+    }
+    if !batch.is_empty() {
+        let _ = block_on(output.send(batch));
+    }
+}
+
+/// Fetches a run on behalf of its merging thread.
+struct Feeder<T, X: AsyncExternal<T>> {
+    fetch: Pin<Box<X::Fetch>>,
+    sender: mpsc::Sender<Vec<T>>,
+    batch: Vec<T>,
+    // bytes held by `batch`
+    batch_size: usize,
+    batch_limit: usize,
+    // `HeapSize::memory_size`, which the merger's `Stream` impl doesn't require
+    size_of: fn(&T) -> usize,
+    done: bool,
+}
+
+impl<T, X: AsyncExternal<T>> Feeder<T, X> {
+    /// Hands the run's tuples to the merging thread, completing once all of them are handed over.
+    fn poll_feed(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), X::Error>> {
+        loop {
+            if !self.batch.is_empty() && (self.batch_size >= self.batch_limit || self.done) {
+                ready!(self.poll_send(cx));
+            }
+            if self.done {
+                return Poll::Ready(Ok(()));
+            }
+            match self.fetch.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(item))) => {
+                    self.batch_size += (self.size_of)(&item);
+                    self.batch.push(item);
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => self.done = true,
+                Poll::Pending if self.batch.is_empty() => return Poll::Pending,
+                Poll::Pending => {
+                    // the thread may be waiting for the tuples fetched so far
+                    ready!(self.poll_send(cx));
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match ready!(self.sender.poll_ready(cx)) {
+            Ok(()) => {
+                let _ = self.sender.start_send(mem::take(&mut self.batch));
+                self.batch_size = 0;
+            }
+            Err(_) => {
+                // the thread is gone, its panic surfaces through the merger's output
+                self.batch.clear();
+                self.done = true;
+            }
+        }
+        Poll::Ready(())
+    }
+}
+
+/// The output of a merging thread.
+struct Group<T> {
+    output: mpsc::Receiver<Vec<(usize, T)>>,
+    batch: vec::IntoIter<(usize, T)>,
+    head: Option<(usize, T)>,
+    // reports whether the merging thread panicked
+    task: Option<MergeTask>,
+}
+
+impl<T> Group<T> {
+    /// Completes once `head` holds the group's next tuple or the group is exhausted.
+    fn poll_head(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        while self.head.is_none() {
+            if let Some(next) = self.batch.next() {
+                self.head = Some(next);
+            } else if let Some(batch) = ready!(self.output.poll_next_unpin(cx)) {
+                self.batch = batch.into_iter();
+            } else {
+                if let Some(task) = &mut self.task {
+                    if let Ok(Err(panic)) = ready!(Pin::new(task).poll(cx)) {
+                        panic::resume_unwind(panic);
+                    }
+                    self.task = None;
+                }
+                break;
+            }
+        }
+        Poll::Ready(())
+    }
+}
+
+/// Merges sorted runs on background threads, yielding every tuple along with the index of its run.
+///
+/// The runs are split into groups that are merged by one thread each. The runs are still fetched
+/// by this stream, which hands their tuples to the threads and merges the groups' outputs.
+#[pin_project]
+pub struct ParallelMerger<D: MergePredicate, X: AsyncExternal<D::Left>> {
+    feeders: Vec<Feeder<D::Left, X>>,
+    groups: Vec<Group<D::Left>>,
+    predicate: D,
+}
+
+impl<D: MergePredicate, X: AsyncExternal<D::Left>> Stream for ParallelMerger<D, X> {
+    type Item = Result<(usize, D::Left), X::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let mut i = 0;
+        while i < this.feeders.len() {
+            match this.feeders[i].poll_feed(cx)? {
+                Poll::Ready(()) => drop(this.feeders.swap_remove(i)),
+                Poll::Pending => i += 1,
+            }
+        }
+
+        let mut pending = false;
+        for group in this.groups.iter_mut() {
+            pending |= group.poll_head(cx).is_pending();
+        }
+        if pending {
+            return Poll::Pending;
+        }
+
+        let predicate = &*this.predicate;
+        let smallest = this.groups.iter_mut()
+            .filter(|group| group.head.is_some())
+            .min_by(|a, b| match (&a.head, &b.head) {
+                (Some((_, a)), Some((_, b))) => predicate.cmp_left(a, b),
+                _ => unreachable!(),
+            });
+        Poll::Ready(smallest.and_then(|group| group.head.take()).map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use futures::executor::block_on;
+    use futures::future;
+    use super::Pool;
+
+    #[test]
+    fn pool_threads() {
+        let pool = Pool::new(2);
+        let seen = Arc::new(Mutex::new(HashSet::new()));
+        let tasks: Vec<_> = (0..16).map(|_| {
+            let seen = Arc::clone(&seen);
+            pool.run(move || seen.lock().unwrap().insert(thread::current().id()))
+        }).collect();
+        drop(pool);
+        for done in block_on(future::join_all(tasks)) {
+            assert!(done.unwrap().is_ok());
+        }
+        assert!(seen.lock().unwrap().len() <= 2);
+    }
+}
@@ -1,56 +1,296 @@
 use std::mem;
 use std::collections::VecDeque;
-use std::rc::Rc;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::Arc;
 use std::cmp::Ordering;
-use futures::{Future, Stream, Poll, Async, stream};
+use std::task::{Context, Poll};
+use futures::{Future, Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
 use named_type::NamedType;
 use named_type_derive::*;
-use crate::InnerJoinPredicate;
+use pin_project::pin_project;
+use crate::{HeapSize, InnerJoinPredicate, IntoIterReady};
 
-use super::{Join, Rescan, OrderedMergeJoin, ExternalStorage};
-use super::sort_merge::SortMerger;
+use super::{Join, JoinError, Rescan, OrderedMergeJoin, AsyncExternalStorage, SortMergeConfig, Storage};
+use super::sort_merge::{ChainedRun, SortMerger, merge_runs};
 use crate::predicate::{JoinPredicate, MergePredicate, SwapPredicate};
 
-pub struct InputPhase<L, R, D, E> 
+pub struct InputPhase<D, E> 
     where
-        L: Stream,
-        R: Stream,
-        D: JoinPredicate<Left=L::Item, Right=R::Item> + MergePredicate + InnerJoinPredicate,
-        E: ExternalStorage<L::Item> + ExternalStorage<R::Item> {
-    definition: D,
+        D: MergePredicate + InnerJoinPredicate,
+        E: AsyncExternalStorage<D::Left> + AsyncExternalStorage<D::Right> {
+    definition: Arc<D>,
     storage: E,
-    left: stream::Fuse<L>,
-    right: stream::Fuse<R>,
-    left_runs: Vec<<E as ExternalStorage<L::Item>>::External>,
-    right_runs: Vec<<E as ExternalStorage<R::Item>>::External>,
-    left_buf: Vec<L::Item>,
-    right_buf: Vec<R::Item>,
+    left_runs: Runs<D::Left, E>,
+    right_runs: Runs<D::Right, E>,
+    // runs on their way to the storage, in order
+    left_stores: Stores<D::Left, E>,
+    right_stores: Stores<D::Right, E>,
+    left_buf: Vec<D::Left>,
+    right_buf: Vec<D::Right>,
+    // bytes held by both buffers
+    buffer_size: usize,
     memory_limit: usize,
+    fan_in: usize,
     output_buffer: VecDeque<D::Output>,
 }
 
+#[pin_project]
 #[derive(NamedType)]
-pub enum ProgressiveMergeJoin<L, R, D, E>
+pub struct ProgressiveMergeJoin<L, R, D, E>
 where
-    L: Stream,
-    R: Stream,
-    D: JoinPredicate<Left=L::Item, Right=R::Item> + MergePredicate + InnerJoinPredicate,
-    E: ExternalStorage<L::Item> + ExternalStorage<R::Item>
+    L: TryStream,
+    R: TryStream,
+    D: JoinPredicate<Left=L::Ok, Right=R::Ok> + MergePredicate + InnerJoinPredicate,
+    E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok>
 {
-    InputPhase(InputPhase<L, R, D, E>),
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Fuse<stream::IntoStream<R>>,
+    state: State<D, E>,
+}
+
+type OutputJoin<D, E> = OrderedMergeJoin<SortMerger<Arc<D>, Run<<D as JoinPredicate>::Left, E>>, SortMerger<SwapPredicate<Arc<D>>, Run<<D as JoinPredicate>::Right, E>>, IgnoreIndexPredicate<Arc<D>>>;
+type PassJoin<D, E> = OrderedMergeJoin<RunWriter<Arc<D>, E>, RunWriter<SwapPredicate<Arc<D>>, E>, IgnoreIndexPredicate<Arc<D>>>;
+
+/// An intermediate merge of run pairs.
+///
+/// Joins the tuples of different pairs, writing the merged runs back as a new pair if they are
+/// to be kept.
+pub struct MergePass<D, E>
+    where
+        D: MergePredicate + InnerJoinPredicate,
+        E: AsyncExternalStorage<D::Left> + AsyncExternalStorage<D::Right> {
+    state: PassState<D, E>,
+    keep: bool,
+}
+enum PassState<D, E>
+    where
+        D: MergePredicate + InnerJoinPredicate,
+        E: AsyncExternalStorage<D::Left> + AsyncExternalStorage<D::Right> {
+    Joining(PassJoin<D, E>),
+    // the join is done, but the merged runs are read to the end to keep them
+    Draining(RunWriter<Arc<D>, E>, RunWriter<SwapPredicate<Arc<D>>, E>),
+    Tmp,
+}
+
+pub(crate) type Run<T, E> = ChainedRun<<E as AsyncExternalStorage<T>>::External>;
+type Runs<T, E> = Vec<Run<T, E>>;
+type MergedRuns<D, E> = (Run<<D as JoinPredicate>::Left, E>, Run<<D as JoinPredicate>::Right, E>);
+type PassMerger<P, E> = stream::Fuse<SortMerger<P, Run<<P as JoinPredicate>::Left, E>>>;
+type Stores<T, E> = VecDeque<Pin<Box<<E as AsyncExternalStorage<T>>::Store>>>;
+
+impl<D, E> MergePass<D, E>
+    where
+        D: MergePredicate + InnerJoinPredicate,
+        D::Left: HeapSize,
+        D::Right: HeapSize,
+        E: AsyncExternalStorage<D::Left> + AsyncExternalStorage<D::Right> {
+    /// Merges the runs, writing them back in chunks of `chunk_limit` bytes per side if `keep`.
+    pub(crate) fn new(left: Runs<D::Left, E>, right: Runs<D::Right, E>, definition: &Arc<D>, keep: bool, chunk_limit: usize) -> Self {
+        let left = RunWriter::new(merge_runs(left, Arc::clone(definition)), keep, chunk_limit);
+        let right = RunWriter::new(merge_runs(right, Arc::clone(definition).swap()), keep, chunk_limit);
+        let omj = OrderedMergeJoin::new(left, right, IgnoreIndexPredicate(Arc::clone(definition)));
+        MergePass { state: PassState::Joining(omj), keep }
+    }
+
+    /// Yields the joined tuples, then reads the rest of the merged runs if they are to be kept.
+    pub(crate) fn poll_next(&mut self, storage: &mut E, cx: &mut Context<'_>) -> Poll<Option<Result<D::Output, <E as Storage>::Error>>> {
+        loop {
+            match &mut self.state {
+                PassState::Joining(omj) => {
+                    let (left, right) = omj.inputs_mut();
+                    ready!(left.poll_write(storage, false, cx))?;
+                    ready!(right.poll_write(storage, false, cx))?;
+                    match Pin::new(&mut *omj).poll_next(cx) {
+                        Poll::Ready(None) if self.keep => (),
+                        Poll::Ready(x) => return Poll::Ready(x),
+                        Poll::Pending => {
+                            // a full chunk holds up the join until it is written
+                            let (left, right) = omj.inputs_mut();
+                            if left.is_full() || right.is_full() {
+                                continue;
+                            }
+                            return Poll::Pending;
+                        }
+                    }
+                }
+                PassState::Draining(left, right) => {
+                    ready!(left.poll_drain(storage, cx))?;
+                    ready!(right.poll_drain(storage, cx))?;
+                    return Poll::Ready(None);
+                }
+                PassState::Tmp => unreachable!(),
+            }
+            if let PassState::Joining(omj) = mem::replace(&mut self.state, PassState::Tmp) {
+                let (left, right) = omj.into_inputs();
+                self.state = PassState::Draining(left, right);
+            }
+        }
+    }
+
+    /// The merged runs, once written back by `poll_next`, or `None` if they weren't kept.
+    pub(crate) fn into_runs(self) -> Option<MergedRuns<D, E>> {
+        match self.state {
+            PassState::Draining(left, right) => Some((left.into_run(), right.into_run())),
+            _ => None,
+        }
+    }
+}
+
+/// One side of a `MergePass`: yields the merged tuples to the join while writing them back.
+///
+/// Tuples are kept until `chunk_limit` bytes of them are buffered. The ones the join is done
+/// with are then written to the storage as the next chunk of the merged run.
+pub struct RunWriter<P: MergePredicate, E: AsyncExternalStorage<P::Left>> {
+    merger: Pin<Box<PassMerger<P, E>>>,
+    // merged tuples yet to be written, shared with the join while it holds them
+    chunk: VecDeque<Arc<(usize, P::Left)>>,
+    chunk_size: usize,
+    chunk_limit: usize,
+    size_of: fn(&P::Left) -> usize,
+    keep: bool,
+    store: Option<Pin<Box<E::Store>>>,
+    // the merged run's chunks stored so far
+    chunks: Vec<E::External>,
+}
+
+// no field is ever pinned in place
+impl<P: MergePredicate, E: AsyncExternalStorage<P::Left>> Unpin for RunWriter<P, E> {}
+
+impl<P: MergePredicate, E: AsyncExternalStorage<P::Left>> RunWriter<P, E> {
+    fn new(merger: SortMerger<P, Run<P::Left, E>>, keep: bool, chunk_limit: usize) -> Self where P::Left: HeapSize {
+        RunWriter {
+            merger: Box::pin(merger.fuse()),
+            chunk: VecDeque::new(),
+            chunk_size: 0,
+            chunk_limit,
+            size_of: P::Left::memory_size,
+            keep,
+            store: None,
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Whether the chunk is complete and the join is done with its first tuple.
+    fn is_full(&self) -> bool {
+        self.chunk_size >= self.chunk_limit && self.chunk.front().is_some_and(|x| Arc::strong_count(x) == 1)
+    }
+
+    /// Writes the tuples the join is done with once the chunk is full, or all of them if `done`.
+    fn poll_write(&mut self, storage: &mut E, done: bool, cx: &mut Context<'_>) -> Poll<Result<(), E::Error>> {
+        loop {
+            if let Some(store) = &mut self.store {
+                let chunk = ready!(store.as_mut().poll(cx));
+                self.store = None;
+                self.chunks.push(chunk?);
+            }
+            if !self.is_full() && !done {
+                return Poll::Ready(Ok(()));
+            }
+            let mut tuples = Vec::new();
+            while let Some(x) = self.chunk.pop_front() {
+                match Arc::try_unwrap(x) {
+                    Ok((_, tuple)) => {
+                        self.chunk_size -= (self.size_of)(&tuple);
+                        tuples.push(tuple);
+                    }
+                    Err(x) => {
+                        self.chunk.push_front(x);
+                        break;
+                    }
+                }
+            }
+            if tuples.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            self.store = Some(Box::pin(storage.store(tuples)));
+        }
+    }
+
+    /// Reads the merged runs to the end, once the join is gone, and writes them back.
+    fn poll_drain(&mut self, storage: &mut E, cx: &mut Context<'_>) -> Poll<Result<(), E::Error>> {
+        loop {
+            ready!(self.poll_write(storage, false, cx))?;
+            match ready!(self.poll_next_unpin(cx)) {
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => return self.poll_write(storage, true, cx),
+            }
+        }
+    }
+
+    fn into_run(self) -> Run<P::Left, E> {
+        assert!(self.chunk.is_empty() && self.store.is_none());
+        ChainedRun::new(self.chunks)
+    }
+}
+
+impl<P: MergePredicate, E: AsyncExternalStorage<P::Left>> Stream for RunWriter<P, E> {
+    type Item = Result<Arc<(usize, P::Left)>, E::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.is_full() {
+            // the pass writes the chunk before polling again
+            return Poll::Pending;
+        }
+        match ready!(this.merger.as_mut().poll_next(cx)) {
+            Some(Ok(item)) => {
+                let item = Arc::new(item);
+                if this.keep {
+                    this.chunk_size += (this.size_of)(&item.1);
+                    this.chunk.push_back(Arc::clone(&item));
+                }
+                Poll::Ready(Some(Ok(item)))
+            }
+            Some(Err(e)) => Poll::Ready(Some(Err(e))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+enum State<D, E>
+where
+    D: MergePredicate + InnerJoinPredicate,
+    E: AsyncExternalStorage<D::Left> + AsyncExternalStorage<D::Right>
+{
+    InputPhase(InputPhase<D, E>),
+    MergePhase(InputPhase<D, E>, Box<MergePass<D, E>>),
+    // the runs are being stored before the next pass
+    Storing(InputPhase<D, E>),
     OutputPhase {
         output_buffer: VecDeque<D::Output>,
-        omj: OrderedMergeJoin<SortMerger<Rc<D>, <E as ExternalStorage<L::Item>>::External>, SortMerger<SwapPredicate<Rc<D>>, <E as ExternalStorage<R::Item>>::External>, IgnoreIndexPredicate<Rc<D>>>,
+        omj: OutputJoin<D, E>,
     },
     Tmp,
 }
 
-impl<L, R, D, E> InputPhase<L, R, D, E>
-where L: Stream,
-      R: Stream<Error=L::Error> + Rescan,
-      E: ExternalStorage<L::Item> + ExternalStorage<R::Item>,
-      D: JoinPredicate<Left=L::Item, Right=R::Item> + MergePredicate + InnerJoinPredicate,
+impl<D, E> InputPhase<D, E>
+where E: AsyncExternalStorage<D::Left> + AsyncExternalStorage<D::Right>,
+      D: MergePredicate + InnerJoinPredicate,
+      D::Left: HeapSize,
+      D::Right: HeapSize,
 {
+    fn store(&mut self, left: Vec<D::Left>, right: Vec<D::Right>) {
+        self.left_stores.push_back(Box::pin(self.storage.store(left)));
+        self.right_stores.push_back(Box::pin(self.storage.store(right)));
+    }
+
+    /// Moves the stored runs to `left_runs` and `right_runs`.
+    fn poll_stores(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), <E as Storage>::Error>> {
+        while let Some(store) = self.left_stores.front_mut() {
+            self.left_runs.push(ChainedRun::new(vec![ready!(store.as_mut().poll(cx))?]));
+            self.left_stores.pop_front();
+        }
+        while let Some(store) = self.right_stores.front_mut() {
+            self.right_runs.push(ChainedRun::new(vec![ready!(store.as_mut().poll(cx))?]));
+            self.right_stores.pop_front();
+        }
+        Poll::Ready(Ok(()))
+    }
+
     fn flush_buffers(&mut self) {
         let definition = &self.definition;
         
@@ -59,14 +299,41 @@ where L: Stream,
         self.right_buf.sort_by(|a, b|definition.cmp_right(a, b));
         
         // join
-        match OrderedMergeJoin::new(stream::iter_ok::<_, ()>(&self.left_buf), stream::iter_ok::<_, ()>(&self.right_buf), definition).collect().poll().unwrap() {
-            Async::NotReady => unreachable!(),
-            Async::Ready(v) => self.output_buffer.extend(v.into_iter()),
-        }
+        let left = stream::iter(self.left_buf.iter().map(Ok::<_, Infallible>));
+        let right = stream::iter(self.right_buf.iter().map(Ok::<_, Infallible>));
+        self.output_buffer.extend(OrderedMergeJoin::new(left, right, definition).iter_ready());
         
         // flush
-        self.left_runs.push(self.storage.store(mem::replace(&mut self.left_buf, Vec::new())));
-        self.right_runs.push(self.storage.store(mem::replace(&mut self.right_buf, Vec::new())));
+        let (left, right) = (mem::take(&mut self.left_buf), mem::take(&mut self.right_buf));
+        self.store(left, right);
+        self.buffer_size = 0;
+    }
+
+    /// Starts the next intermediate merge, or the output phase once the runs fit the fan-in.
+    ///
+    /// All runs must be stored.
+    fn next_pass(mut self) -> State<D, E> {
+        assert!(self.left_stores.is_empty() && self.right_stores.is_empty());
+        if self.left_runs.len() > self.fan_in {
+            let left = self.left_runs.drain(..self.fan_in).collect();
+            let right = self.right_runs.drain(..self.fan_in).collect();
+            // the input is not read during the pass, so its memory goes to the merged chunks
+            let pass = MergePass::new(left, right, &self.definition, true, self.memory_limit / 2);
+            return State::MergePhase(self, Box::new(pass));
+        }
+
+        let InputPhase { left_buf, right_buf, definition, left_runs, right_runs, output_buffer, .. } = self;
+        assert!(left_buf.is_empty());
+        assert!(right_buf.is_empty());
+
+        let left = merge_runs(left_runs, definition.clone());
+        let right = merge_runs(right_runs, definition.clone().swap());
+
+        //println!("merge phase!");
+        State::OutputPhase {
+            output_buffer,
+            omj: OrderedMergeJoin::new(left, right, IgnoreIndexPredicate(definition)),
+        }
     }
 }
 
@@ -101,110 +368,231 @@ impl<P: MergePredicate> MergePredicate for IgnoreIndexPredicate<P> {
 }
 
 impl<L, R, D, E> Stream for ProgressiveMergeJoin<L, R, D, E>
-    where L: Stream,
-          R: Stream<Error=L::Error> + Rescan,
-          E: ExternalStorage<L::Item> + ExternalStorage<R::Item>,
-          D: InnerJoinPredicate + MergePredicate<Left=L::Item, Right=R::Item> {
-    type Item = D::Output;
-    type Error = L::Error;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error> + Rescan,
+          R::Ok: HeapSize,
+          E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok>,
+          D: InnerJoinPredicate + MergePredicate<Left=L::Ok, Right=R::Ok> {
+    type Item = Result<D::Output, JoinError<L::Error, <E as Storage>::Error>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
         loop {
-            match self {
-                ProgressiveMergeJoin::InputPhase(i) => {
+            match this.state {
+                State::InputPhase(i) => {
                     if let Some(buffered) = i.output_buffer.pop_front() {
                         //println!("yielding {:?} from buffer", buffered.debug());
                         // pending buffered tuples
-                        return Ok(Async::Ready(Some(buffered)));
+                        return Poll::Ready(Some(Ok(buffered)));
                     }
-                    
-                    match (i.left.poll()?, i.right.poll()?) {
-                        (Async::Ready(None), Async::Ready(None)) => {
+                    // don't read more input while runs are being stored
+                    ready!(i.poll_stores(cx)).map_err(JoinError::Storage)?;
+
+                    match (this.left.as_mut().try_poll_next(cx)?, this.right.as_mut().try_poll_next(cx)?) {
+                        (Poll::Ready(None), Poll::Ready(None)) => {
                             // cleanup phase
-                            // fall through to replace self
+                            // fall through to replace the state
                         }
-                        (Async::NotReady, Async::NotReady)
-                            | (Async::Ready(None), Async::NotReady)
-                            | (Async::NotReady, Async::Ready(None)) => {
-                            return Ok(Async::NotReady);
+                        (Poll::Pending, Poll::Pending)
+                            | (Poll::Ready(None), Poll::Pending)
+                            | (Poll::Pending, Poll::Ready(None)) => {
+                            return Poll::Pending;
                         }
                         (l, r) => {
-                            if let Async::Ready(Some(l)) = l {
+                            if let Poll::Ready(Some(l)) = l {
+                                i.buffer_size += l.memory_size();
                                 i.left_buf.push(l);
                             }
-                            if let Async::Ready(Some(r)) = r {
+                            if let Poll::Ready(Some(r)) = r {
+                                i.buffer_size += r.memory_size();
                                 i.right_buf.push(r);
                             }
                             // *might* be incorrect, depending on how you look at it
                             // (in the face of left/right having different speed)
-                            if i.left_buf.len() + i.right_buf.len() >= i.memory_limit {
+                            if i.buffer_size >= i.memory_limit {
                                 i.flush_buffers();
                             }
                             continue;
                         }
                     }
                 }
-                ProgressiveMergeJoin::OutputPhase { output_buffer, omj } => {
+                State::MergePhase(i, pass) => {
+                    if let Some(buffered) = i.output_buffer.pop_front() {
+                        return Poll::Ready(Some(Ok(buffered)));
+                    }
+                    match pass.poll_next(&mut i.storage, cx) {
+                        Poll::Ready(Some(Ok(item))) => return Poll::Ready(Some(Ok(item))),
+                        Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(JoinError::Storage(e)))),
+                        // the merged runs are written back
+                        Poll::Ready(None) => {}
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                State::Storing(i) => {
+                    if let Some(buffered) = i.output_buffer.pop_front() {
+                        return Poll::Ready(Some(Ok(buffered)));
+                    }
+                    ready!(i.poll_stores(cx)).map_err(JoinError::Storage)?;
+                }
+                State::OutputPhase { output_buffer, omj } => {
                     if let Some(buffered) = output_buffer.pop_front() {
                         // pending buffered tuples
                         //println!("yielding {:?} from buffer 2", buffered.debug());
-                        return Ok(Async::Ready(Some(buffered)));
+                        return Poll::Ready(Some(Ok(buffered)));
                     }
                     
-                    match omj.poll().unwrap() {
-                        Async::Ready(None) => return Ok(Async::Ready(None)),
-                        Async::Ready(Some(item)) => {
+                    match Pin::new(omj).poll_next(cx) {
+                        Poll::Ready(None) => return Poll::Ready(None),
+                        Poll::Ready(Some(Ok(item))) => {
                             //println!("yielding {:?} from MERGE", item.debug());
-                            return Ok(Async::Ready(Some(item)));
+                            return Poll::Ready(Some(Ok(item)));
                         }
-                        Async::NotReady => unreachable!(),
+                        Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(JoinError::Storage(e)))),
+                        Poll::Pending => return Poll::Pending,
                     }
                 }
-                ProgressiveMergeJoin::Tmp => unreachable!(),
+                State::Tmp => unreachable!(),
             }
             
-            *self = match mem::replace(self, ProgressiveMergeJoin::Tmp) {
-                ProgressiveMergeJoin::InputPhase(mut i) => {
+            *this.state = match mem::replace(this.state, State::Tmp) {
+                State::InputPhase(mut i) => {
                     i.flush_buffers();
-                    let InputPhase { left_buf, right_buf, definition, left_runs, right_runs, output_buffer, .. } = i;
-                    assert!(left_buf.is_empty());
-                    assert!(right_buf.is_empty());
-
-                    let definition = Rc::new(definition);
-
-                    let left = SortMerger::new(left_runs, definition.clone());
-                    let right = SortMerger::new(right_runs, definition.clone().swap());
-
-                    //println!("merge phase!");
-                    ProgressiveMergeJoin::OutputPhase {
-                        output_buffer,
-                        omj: OrderedMergeJoin::new(left, right, IgnoreIndexPredicate(definition)),
-                    }
+                    State::Storing(i)
                 }
+                State::MergePhase(mut i, pass) => {
+                    let (left, right) = pass.into_runs().unwrap();
+                    i.left_runs.push(left);
+                    i.right_runs.push(right);
+                    i.next_pass()
+                }
+                State::Storing(i) => i.next_pass(),
                 _ => unreachable!(),
             };
         }
     }
 }
 impl<L, R, D, E> Join<L, R, D, E, usize> for ProgressiveMergeJoin<L, R, D, E>
-where L: Stream,
-      R: Stream<Error=L::Error> + Rescan,
-      E: ExternalStorage<L::Item> + ExternalStorage<R::Item>,
-      D: JoinPredicate<Left=L::Item, Right=R::Item> + MergePredicate + InnerJoinPredicate,
+where L: TryStream,
+      L::Ok: HeapSize,
+      R: TryStream<Error=L::Error> + Rescan,
+      R::Ok: HeapSize,
+      E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok>,
+      D: JoinPredicate<Left=L::Ok, Right=R::Ok> + MergePredicate + InnerJoinPredicate,
 {
     fn build(left: L, right: R, definition: D, storage: E, main_memory: usize) -> Self {
-        ProgressiveMergeJoin::InputPhase(InputPhase {
-            definition,
-            storage,
-            left: left.fuse(),
-            right: right.fuse(),
-            left_runs: Vec::new(),
-            right_runs: Vec::new(),
-            left_buf: Vec::new(),
-            right_buf: Vec::new(),
-            memory_limit: main_memory,
-            output_buffer: VecDeque::new(),
-        })
+        Self::build(left, right, definition, storage, SortMergeConfig::from(main_memory))
+    }
+}
+impl<L, R, D, E> Join<L, R, D, E, SortMergeConfig> for ProgressiveMergeJoin<L, R, D, E>
+where L: TryStream,
+      L::Ok: HeapSize,
+      R: TryStream<Error=L::Error> + Rescan,
+      R::Ok: HeapSize,
+      E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok>,
+      D: JoinPredicate<Left=L::Ok, Right=R::Ok> + MergePredicate + InnerJoinPredicate,
+{
+    fn build(left: L, right: R, definition: D, storage: E, config: SortMergeConfig) -> Self {
+        assert!(config.fan_in > 1);
+        ProgressiveMergeJoin {
+            left: left.into_stream().fuse(),
+            right: right.into_stream().fuse(),
+            state: State::InputPhase(InputPhase {
+                definition: Arc::new(definition),
+                storage,
+                left_runs: Vec::new(),
+                right_runs: Vec::new(),
+                left_stores: VecDeque::new(),
+                right_stores: VecDeque::new(),
+                left_buf: Vec::new(),
+                right_buf: Vec::new(),
+                buffer_size: 0,
+                memory_limit: config.memory_limit,
+                fan_in: config.fan_in,
+                output_buffer: VecDeque::new(),
+            }),
+        }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::convert::Infallible;
+    use std::mem;
+    use std::rc::Rc;
+    use futures::{TryStreamExt, future};
+    use futures::executor::block_on;
+    use crate::{AsyncExternalStorage, Blocking, EquiJoin, IterSource, Join, JoinInMemory, ProgressiveMergeJoin, SortMergeConfig, Storage};
+    use crate::join::sort_merge::test::SlowStorage;
+
+    #[test]
+    fn progressive_merge_fan_in() {
+        let left: Vec<_> = (0..500).map(|x| x % 97).collect();
+        let right: Vec<_> = (0..300).map(|x| x * 7 % 113).collect();
+        let mut expected: Vec<_> = left.iter().flat_map(|&l| right.iter().filter(move |&&r| r == l).map(move |&r| (l, r))).collect();
+        expected.sort_unstable();
+
+        for fan_in in [2, 3, usize::MAX] {
+            let join = ProgressiveMergeJoin::build_in_memory(
+                left.clone(),
+                right.clone(),
+                EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+                SortMergeConfig { memory_limit: 40 * mem::size_of::<i32>(), fan_in },
+            );
+            let mut results: Vec<_> = join.collect();
+            results.sort_unstable();
+            assert_eq!(expected, results, "fan-in {}", fan_in);
+        }
+    }
+
+    #[test]
+    fn progressive_merge_async_storage() {
+        let join = ProgressiveMergeJoin::build(
+            IterSource::new((0..400).map(|x| x % 200)),
+            IterSource::new(100..300),
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            SlowStorage,
+            SortMergeConfig { memory_limit: 40 * mem::size_of::<i32>(), fan_in: 2 },
+        );
+        let mut results: Vec<_> = block_on(join.try_collect()).unwrap();
+        results.sort_unstable();
+        assert_eq!((100..200).flat_map(|x| [(x, x), (x, x)]).collect::<Vec<_>>(), results);
+    }
+
+    /// Tracks the size of the largest run or chunk stored.
+    #[derive(Clone, Default)]
+    struct ChunkStorage {
+        max_size: Rc<Cell<usize>>,
+    }
+    impl Storage for ChunkStorage {
+        type Error = Infallible;
+    }
+    impl AsyncExternalStorage<i32> for ChunkStorage {
+        type External = Blocking<Vec<i32>>;
+        type Store = future::Ready<Result<Blocking<Vec<i32>>, Infallible>>;
+        fn store(&mut self, tuples: Vec<i32>) -> Self::Store {
+            self.max_size.set(self.max_size.get().max(tuples.len() * mem::size_of::<i32>()));
+            future::ok(Blocking(tuples))
+        }
+    }
+
+    #[test]
+    fn progressive_merge_chunked_passes() {
+        // once the right input is exhausted, every run holds a full buffer of left tuples
+        let memory_limit = 40 * mem::size_of::<i32>();
+        let storage = ChunkStorage::default();
+        let join = ProgressiveMergeJoin::build(
+            IterSource::new((0..400).map(|x| x * 7 % 400)),
+            IterSource::new(0..40),
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            storage.clone(),
+            SortMergeConfig { memory_limit, fan_in: 2 },
+        );
+        let mut results: Vec<_> = block_on(join.try_collect()).unwrap();
+        results.sort_unstable();
+        assert_eq!((0..40).map(|x| (x, x)).collect::<Vec<_>>(), results);
+        // merged runs are written in chunks rather than all at once
+        assert!(storage.max_size.get() <= memory_limit);
+    }
+}
@@ -1,105 +1,241 @@
 use std::vec;
-use futures::{Stream, Poll, Async, try_ready, stream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
 use multimap::MultiMap;
 use named_type::NamedType;
 use named_type_derive::*;
-use crate::OuterJoinPredicate;
+use pin_project::pin_project;
+use crate::{HeapSize, OuterJoinPredicate};
 
 use super::{Join, Rescan};
 use crate::predicate::HashPredicate;
 
+/// Semantics of an anti-join in the presence of duplicates and `NULL`s.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AntiJoinMode {
+    /// Set anti-join (SQL `NOT EXISTS`): every matching left tuple is dropped.
+    NotExists,
+    /// Bag difference (SQL `EXCEPT ALL`): every right tuple drops at most one matching left tuple.
+    ExceptAll,
+    /// SQL `NOT IN`: like `NotExists`, but no left tuple qualifies if there's a right tuple for
+    /// which `OuterJoinPredicate::is_null_right` holds. Left tuples for which
+    /// `OuterJoinPredicate::is_null_left` holds only qualify if the right input is empty.
+    NotIn,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AntiJoinConfig {
+    /// Maximum size of the hash table in bytes.
+    pub memory_limit: usize,
+    pub mode: AntiJoinMode,
+}
+
 enum State<T> {
     Join(MultiMap<u64, T>),
     Drain(MultiMap<u64, T>, vec::IntoIter<T>),
 }
 
+#[pin_project]
 #[derive(NamedType)]
-pub struct SimpleHashAntiJoin<L: Stream, R: Stream, D: HashPredicate> {
+pub struct SimpleHashAntiJoin<L: TryStream, R: TryStream, D: HashPredicate> {
     definition: D,
-    left: stream::Fuse<L>,
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
     right: R,
-    state: State<L::Item>,
-    table_entries: usize,
-    memory_limit: usize,
+    state: State<L::Ok>,
+    // bytes held by the table
+    table_size: usize,
+    config: AntiJoinConfig,
+    // seen during the current scan of the right input
+    right_seen: bool,
+    right_null: bool,
+    // `ExceptAll`: whether each right tuple already dropped a left tuple in an earlier scan
+    right_consumed: Vec<bool>,
+    right_index: usize,
 }
 impl<L, R, D> Stream for SimpleHashAntiJoin<L, R, D>
 where
-    L: Stream,
-    R: Stream<Error=L::Error> + Rescan,
-    D: HashPredicate + OuterJoinPredicate<Left=L::Item, Right=R::Item>
+    L: TryStream,
+    L::Ok: HeapSize,
+    R: TryStream<Error=L::Error> + Rescan,
+    D: HashPredicate + OuterJoinPredicate<Left=L::Ok, Right=R::Ok>
 {
-    type Item = L::Item;
-    type Error = L::Error;
+    type Item = Result<L::Ok, L::Error>;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
         loop {
-            let table = match &mut self.state {
+            let table = match &mut this.state {
                 State::Drain(map, iter) => {
                     if let Some(item) = iter.next() {
-                        return Ok(Async::Ready(Some(item)));
+                        return Poll::Ready(Some(Ok(item)));
                     }
                     if let Some(&key) = map.keys().next() {
                         *iter = map.remove(&key).unwrap().into_iter();
                         continue;
                     }
-                    if self.left.is_done() {
-                        return Ok(Async::Ready(None));
+                    if this.left.is_done() {
+                        return Poll::Ready(None);
                     }
                     assert!(map.is_empty());
                     // reuse already allocated map
                     let map = std::mem::replace(map, MultiMap::new());
-                    self.state = State::Join(map);
-                    self.table_entries = 0;
+                    *this.state = State::Join(map);
+                    *this.table_size = 0;
                     continue;
                 }
                 State::Join(table) => table,
             };
 
-            if (self.table_entries < self.memory_limit) && !self.left.is_done() {
+            if (*this.table_size < this.config.memory_limit) && !this.left.is_done() {
                 // build phase
-                if let Some(left) = try_ready!(self.left.poll()) {
-                    table.insert(self.definition.hash_left(&left), left);
-                    self.table_entries += 1;
+                if let Some(left) = ready!(this.left.as_mut().try_poll_next(cx)?) {
+                    *this.table_size += left.memory_size();
+                    table.insert(this.definition.hash_left(&left), left);
                 }
-            } else if let Some(right) = try_ready!(self.right.poll()) {
+            } else if let Some(right) = ready!(this.right.as_mut().try_poll_next(cx)?) {
                 // probe phase
-                let definition = &self.definition;
-                let hash = definition.hash_right(&right);
-                let vec = match table.get_vec_mut(&hash) {
+                let definition = &*this.definition;
+                *this.right_seen = true;
+                if this.config.mode == AntiJoinMode::NotIn && definition.is_null_right(&right) {
+                    *this.right_null = true;
+                    continue;
+                }
+                let consumed = if this.config.mode == AntiJoinMode::ExceptAll {
+                    if *this.right_index == this.right_consumed.len() {
+                        this.right_consumed.push(false);
+                    }
+                    *this.right_index += 1;
+                    Some(&mut this.right_consumed[*this.right_index - 1])
+                } else {
+                    None
+                };
+                let vec = match table.get_vec_mut(&definition.hash_right(&right)) {
                     Some(vec) => vec,
                     None => continue,
                 };
-                if let Some(pos) = vec.iter().position(|left| definition.eq(left, &right)) {
-                    vec.swap_remove(pos);
-                };
+                match consumed {
+                    // the right tuple dropped a left tuple of an earlier memory-full already
+                    Some(true) => {}
+                    Some(consumed) => {
+                        if let Some(pos) = vec.iter().position(|left| definition.eq(left, &right)) {
+                            vec.swap_remove(pos);
+                            *consumed = true;
+                        }
+                    }
+                    None => vec.retain(|left| !definition.eq(left, &right)),
+                }
             } else {
                 // probe phase complete, return to drain phase
-                self.right.rescan();
+                this.right.as_mut().rescan();
+                if this.config.mode == AntiJoinMode::NotIn {
+                    let definition = &*this.definition;
+                    if *this.right_null {
+                        table.clear();
+                    } else if *this.right_seen {
+                        table.retain(|_, left| !definition.is_null_left(left));
+                    }
+                }
+                *this.right_seen = false;
+                *this.right_null = false;
+                *this.right_index = 0;
                 let map = std::mem::replace(table, MultiMap::new());
-                self.state = State::Drain(map, Vec::new().into_iter());
+                *this.state = State::Drain(map, Vec::new().into_iter());
             }
         }
     }
 }
-impl<L, R, D, E> Join<L, R, D, E, usize> for SimpleHashAntiJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error> + Rescan,
-          D: HashPredicate + OuterJoinPredicate<Left=L::Item, Right=R::Item> {
-    fn build(left: L, right: R, definition: D, _: E, main_memory: usize) -> Self {
+impl<L, R, D, E> Join<L, R, D, E, AntiJoinConfig> for SimpleHashAntiJoin<L, R, D>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: HashPredicate + OuterJoinPredicate<Left=L::Ok, Right=R::Ok> {
+    fn build(left: L, right: R, definition: D, _: E, config: AntiJoinConfig) -> Self {
         SimpleHashAntiJoin {
             definition,
-            left: left.fuse(),
+            left: left.into_stream().fuse(),
             right,
             state: State::Join(MultiMap::new()),
-            table_entries: 0,
-            memory_limit: main_memory,
+            table_size: 0,
+            config,
+            right_seen: false,
+            right_null: false,
+            right_consumed: Vec::new(),
+            right_index: 0,
         }
     }
 }
+/// Uses `AntiJoinMode::NotExists`.
+impl<L, R, D, E> Join<L, R, D, E, usize> for SimpleHashAntiJoin<L, R, D>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: HashPredicate + OuterJoinPredicate<Left=L::Ok, Right=R::Ok> {
+    fn build(left: L, right: R, definition: D, storage: E, main_memory: usize) -> Self {
+        Self::build(left, right, definition, storage, AntiJoinConfig { memory_limit: main_memory, mode: AntiJoinMode::NotExists })
+    }
+}
 
 #[cfg(test)]
 mod test {
-    use crate::{EquiJoin, JoinInMemory, SimpleHashAntiJoin};
+    use std::mem;
+    use crate::{AntiJoinConfig, AntiJoinMode, EquiJoin, HashPredicate, JoinInMemory, JoinPredicate, OuterJoinPredicate, SimpleHashAntiJoin};
+
+    /// Equality on nullable keys.
+    struct NullableEq;
+    impl JoinPredicate for NullableEq {
+        type Left = Option<i32>;
+        type Right = Option<i32>;
+    }
+    impl OuterJoinPredicate for NullableEq {
+        fn eq(&self, left: &Option<i32>, right: &Option<i32>) -> bool {
+            left.is_some() && left == right
+        }
+        fn is_null_left(&self, left: &Option<i32>) -> bool {
+            left.is_none()
+        }
+        fn is_null_right(&self, right: &Option<i32>) -> bool {
+            right.is_none()
+        }
+    }
+    impl HashPredicate for NullableEq {
+        fn hash_left(&self, x: &Option<i32>) -> u64 { x.unwrap_or(0) as u64 }
+        fn hash_right(&self, x: &Option<i32>) -> u64 { x.unwrap_or(0) as u64 }
+    }
+
+    fn anti_join(left: Vec<Option<i32>>, right: Vec<Option<i32>>, mode: AntiJoinMode) -> Vec<Option<i32>> {
+        let join = SimpleHashAntiJoin::build_in_memory(left, right, NullableEq, AntiJoinConfig { memory_limit: 3 * mem::size_of::<Option<i32>>(), mode });
+        let mut results: Vec<_> = join.collect();
+        results.sort_unstable();
+        results
+    }
+
+    #[test]
+    fn anti_join_modes() {
+        let left = vec![Some(1), Some(1), Some(2), Some(3), None];
+        let right = vec![Some(1), Some(3)];
+        assert_eq!(vec![None, Some(2)], anti_join(left.clone(), right.clone(), AntiJoinMode::NotExists));
+        assert_eq!(vec![None, Some(1), Some(2)], anti_join(left.clone(), right.clone(), AntiJoinMode::ExceptAll));
+        assert_eq!(vec![Some(2)], anti_join(left.clone(), right.clone(), AntiJoinMode::NotIn));
+        assert_eq!(Vec::<Option<i32>>::new(), anti_join(left.clone(), vec![Some(1), None], AntiJoinMode::NotIn));
+        assert_eq!(vec![None, Some(1), Some(1), Some(2), Some(3)], anti_join(left, vec![], AntiJoinMode::NotIn));
+    }
+
+    #[test]
+    fn except_all_multiple_scans() {
+        // every memory-full holds a single tuple
+        let join = SimpleHashAntiJoin::build_in_memory(vec![1i32, 1], vec![1i32], EquiJoin::new(|&l: &i32| l, |&r: &i32| r), AntiJoinConfig { memory_limit: 4, mode: AntiJoinMode::ExceptAll });
+        assert_eq!(vec![1], join.collect::<Vec<_>>());
+
+        let left = vec![Some(1), Some(1), Some(2), Some(1), Some(3), Some(2), None, Some(1)];
+        let right = vec![Some(2), Some(1), Some(1), None, Some(4), Some(2), Some(2)];
+        let join = SimpleHashAntiJoin::build_in_memory(left, right, NullableEq, AntiJoinConfig { memory_limit: 2 * mem::size_of::<Option<i32>>(), mode: AntiJoinMode::ExceptAll });
+        let mut results: Vec<_> = join.collect();
+        results.sort_unstable();
+        assert_eq!(vec![None, Some(1), Some(1), Some(3)], results);
+    }
 
     #[test]
     fn simple_anti_hash() {
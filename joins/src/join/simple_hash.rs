@@ -1,70 +1,79 @@
 use std::collections::VecDeque;
-use futures::{Stream, Poll, Async, try_ready, stream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
 use multimap::MultiMap;
 use named_type::NamedType;
 use named_type_derive::*;
-use crate::InnerJoinPredicate;
+use pin_project::pin_project;
+use crate::{HeapSize, InnerJoinPredicate};
 
 use super::{Join, Rescan};
 use crate::predicate::HashPredicate;
 
+#[pin_project]
 #[derive(NamedType)]
-pub struct SimpleHashJoin<L: Stream, R: Stream, D: InnerJoinPredicate + HashPredicate> {
+pub struct SimpleHashJoin<L: TryStream, R: TryStream, D: InnerJoinPredicate + HashPredicate> {
     definition: D,
-    left: stream::Fuse<L>,
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
     right: R,
-    table: MultiMap<u64, L::Item>,
-    table_entries: usize,
+    table: MultiMap<u64, L::Ok>,
+    // bytes held by the table
+    table_size: usize,
     memory_limit: usize,
     output_buffer: VecDeque<D::Output>,
 }
 impl<L, R, D> Stream for SimpleHashJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error> + Rescan,
-          D: InnerJoinPredicate + HashPredicate<Left=L::Item, Right=R::Item> {
-    type Item = D::Output;
-    type Error = L::Error;
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok> {
+    type Item = Result<D::Output, L::Error>;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
         loop {
-            if let Some(buffered) = self.output_buffer.pop_front() {
+            if let Some(buffered) = this.output_buffer.pop_front() {
                 // pending buffered tuples
-                return Ok(Async::Ready(Some(buffered)));
-            } else if (self.table_entries < self.memory_limit) && !self.left.is_done() {
+                return Poll::Ready(Some(Ok(buffered)));
+            } else if (*this.table_size < *this.memory_limit) && !this.left.is_done() {
                 // build phase
-                if let Some(left) = try_ready!(self.left.poll()) {
-                    self.table.insert(self.definition.hash_left(&left), left);
-                    self.table_entries += 1;
+                if let Some(left) = ready!(this.left.as_mut().try_poll_next(cx)?) {
+                    *this.table_size += left.memory_size();
+                    this.table.insert(this.definition.hash_left(&left), left);
                 }
-            } else if let Some(right) = try_ready!(self.right.poll()) {
+            } else if let Some(right) = ready!(this.right.as_mut().try_poll_next(cx)?) {
                 // probe phase
-                let definition = &self.definition;
-                self.output_buffer.extend(
-                    self.table.get_vec(&definition.hash_right(&right)).into_iter()
+                let definition = &*this.definition;
+                this.output_buffer.extend(
+                    this.table.get_vec(&definition.hash_right(&right)).into_iter()
                         .flatten().filter_map(|left| definition.eq(left, &right)));
-            } else if self.left.is_done() {
+            } else if this.left.is_done() {
                 // all complete
-                return Ok(Async::Ready(None));
+                return Poll::Ready(None);
             } else {
                 // probe phase complete, return to build phase
-                self.right.rescan();
-                self.table.clear();
-                self.table_entries = 0;
+                this.right.as_mut().rescan();
+                this.table.clear();
+                *this.table_size = 0;
             }
         }
     }
 }
 impl<L, R, D, E> Join<L, R, D, E, usize> for SimpleHashJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error> + Rescan,
-          D: InnerJoinPredicate + HashPredicate<Left=L::Item, Right=R::Item> {
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok> {
     fn build(left: L, right: R, definition: D, _: E, main_memory: usize) -> Self {
         SimpleHashJoin {
             definition,
-            left: left.fuse(),
+            left: left.into_stream().fuse(),
             right,
             table: MultiMap::new(),
-            table_entries: 0,
+            table_size: 0,
             memory_limit: main_memory,
             output_buffer: VecDeque::new(),
         }
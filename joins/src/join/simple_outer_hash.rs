@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
+use multimap::MultiMap;
+use named_type::NamedType;
+use named_type_derive::*;
+use pin_project::pin_project;
+use crate::{HeapSize, InnerJoinPredicate};
+
+use super::{Join, Rescan, OuterJoinKind};
+use crate::predicate::HashPredicate;
+
+/// Outer join variant of `SimpleHashJoin`.
+///
+/// `K` is one of `LeftOuter`, `RightOuter` or `FullOuter`.
+/// Unmatched left tuples are emitted once the right input has been scanned for the
+/// memory-full they belong to. Unmatched right tuples are emitted during the last scan,
+/// which requires remembering one flag per right tuple if the left input doesn't fit into memory.
+#[pin_project]
+#[derive(NamedType)]
+pub struct SimpleHashOuterJoin<L: TryStream, R: TryStream, D: InnerJoinPredicate + HashPredicate, K> {
+    definition: D,
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: R,
+    table: MultiMap<u64, (L::Ok, bool)>,
+    // bytes held by the table
+    table_size: usize,
+    memory_limit: usize,
+    output_buffer: VecDeque<D::Output>,
+    right_matched: Vec<bool>,
+    right_index: usize,
+    finished: bool,
+    _kind: PhantomData<K>,
+}
+impl<L, R, D, K> Stream for SimpleHashOuterJoin<L, R, D, K>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok>,
+          K: OuterJoinKind<D> {
+    type Item = Result<D::Output, L::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            if let Some(buffered) = this.output_buffer.pop_front() {
+                // pending buffered tuples
+                return Poll::Ready(Some(Ok(buffered)));
+            } else if *this.finished {
+                return Poll::Ready(None);
+            } else if (*this.table_size < *this.memory_limit) && !this.left.is_done() {
+                // build phase
+                if let Some(left) = ready!(this.left.as_mut().try_poll_next(cx)?) {
+                    *this.table_size += left.memory_size();
+                    this.table.insert(this.definition.hash_left(&left), (left, false));
+                }
+            } else if let Some(right) = ready!(this.right.as_mut().try_poll_next(cx)?) {
+                // probe phase
+                let definition = &*this.definition;
+                let mut matched = false;
+                for (left, left_matched) in this.table.get_vec_mut(&definition.hash_right(&right)).into_iter().flatten() {
+                    if let Some(output) = definition.eq(left, &right) {
+                        this.output_buffer.push_back(output);
+                        *left_matched = true;
+                        matched = true;
+                    }
+                }
+                if K::RIGHT {
+                    if *this.right_index == this.right_matched.len() {
+                        this.right_matched.push(false);
+                    }
+                    let right_matched = &mut this.right_matched[*this.right_index];
+                    *right_matched |= matched;
+                    *this.right_index += 1;
+                    if this.left.is_done() && !*right_matched {
+                        // last scan of the right input
+                        this.output_buffer.extend(K::pad_left(definition, &right));
+                    }
+                }
+            } else {
+                // probe phase complete
+                if K::LEFT {
+                    let definition = &*this.definition;
+                    this.output_buffer.extend(
+                        this.table.iter_all().flat_map(|(_, v)| v)
+                            .filter(|(_, matched)| !matched)
+                            .filter_map(|(left, _)| K::pad_right(definition, left)));
+                }
+                if this.left.is_done() {
+                    *this.finished = true;
+                } else {
+                    // return to build phase
+                    this.right.as_mut().rescan();
+                    this.table.clear();
+                    *this.table_size = 0;
+                    *this.right_index = 0;
+                }
+            }
+        }
+    }
+}
+impl<L, R, D, E, K> Join<L, R, D, E, usize> for SimpleHashOuterJoin<L, R, D, K>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok>,
+          K: OuterJoinKind<D> {
+    fn build(left: L, right: R, definition: D, _: E, main_memory: usize) -> Self {
+        SimpleHashOuterJoin {
+            definition,
+            left: left.into_stream().fuse(),
+            right,
+            table: MultiMap::new(),
+            table_size: 0,
+            memory_limit: main_memory,
+            output_buffer: VecDeque::new(),
+            right_matched: Vec::new(),
+            right_index: 0,
+            finished: false,
+            _kind: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{EquiJoin, FullOuter, InnerJoinPredicate, JoinInMemory, SimpleHashOuterJoin};
+
+    #[test]
+    fn simple_full_outer_hash() {
+        // a memory limit of 4 forces several scans of the right input
+        let join = SimpleHashOuterJoin::<_, _, _, FullOuter>::build_in_memory(
+            0..10,
+            5..15,
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r).full_outer(),
+            4,
+        );
+        let mut results: Vec<_> = join.collect();
+        results.sort_unstable();
+        let mut expected: Vec<_> = (0..5).map(|l| (Some(l), None))
+            .chain((5..10).map(|x| (Some(x), Some(x))))
+            .chain((10..15).map(|r| (None, Some(r))))
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(expected, results);
+    }
+}
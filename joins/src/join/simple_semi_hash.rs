@@ -0,0 +1,111 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
+use multimap::MultiMap;
+use named_type::NamedType;
+use named_type_derive::*;
+use pin_project::pin_project;
+use crate::{HeapSize, OuterJoinPredicate};
+
+use super::{Join, Rescan};
+use crate::predicate::HashPredicate;
+
+#[pin_project]
+#[derive(NamedType)]
+pub struct SimpleHashSemiJoin<L: TryStream, R: TryStream, D: HashPredicate> {
+    definition: D,
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: R,
+    table: MultiMap<u64, L::Ok>,
+    // bytes held by the table
+    table_size: usize,
+    memory_limit: usize,
+    output_buffer: VecDeque<L::Ok>,
+}
+impl<L, R, D> Stream for SimpleHashSemiJoin<L, R, D>
+where
+    L: TryStream,
+    L::Ok: HeapSize,
+    R: TryStream<Error=L::Error> + Rescan,
+    D: HashPredicate + OuterJoinPredicate<Left=L::Ok, Right=R::Ok>
+{
+    type Item = Result<L::Ok, L::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            if let Some(buffered) = this.output_buffer.pop_front() {
+                // pending buffered tuples
+                return Poll::Ready(Some(Ok(buffered)));
+            } else if (*this.table_size < *this.memory_limit) && !this.left.is_done() {
+                // build phase
+                if let Some(left) = ready!(this.left.as_mut().try_poll_next(cx)?) {
+                    *this.table_size += left.memory_size();
+                    this.table.insert(this.definition.hash_left(&left), left);
+                }
+            } else if let Some(right) = ready!(this.right.as_mut().try_poll_next(cx)?) {
+                // probe phase
+                let definition = &*this.definition;
+                let vec = match this.table.get_vec_mut(&definition.hash_right(&right)) {
+                    Some(vec) => vec,
+                    None => continue,
+                };
+                // matched tuples are removed from the table so they're emitted only once
+                let mut i = 0;
+                while i < vec.len() {
+                    if definition.eq(&vec[i], &right) {
+                        this.output_buffer.push_back(vec.swap_remove(i));
+                    } else {
+                        i += 1;
+                    }
+                }
+            } else if this.left.is_done() {
+                // all complete
+                return Poll::Ready(None);
+            } else {
+                // probe phase complete, return to build phase
+                this.right.as_mut().rescan();
+                this.table.clear();
+                *this.table_size = 0;
+            }
+        }
+    }
+}
+impl<L, R, D, E> Join<L, R, D, E, usize> for SimpleHashSemiJoin<L, R, D>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error> + Rescan,
+          D: HashPredicate + OuterJoinPredicate<Left=L::Ok, Right=R::Ok> {
+    fn build(left: L, right: R, definition: D, _: E, main_memory: usize) -> Self {
+        SimpleHashSemiJoin {
+            definition,
+            left: left.into_stream().fuse(),
+            right,
+            table: MultiMap::new(),
+            table_size: 0,
+            memory_limit: main_memory,
+            output_buffer: VecDeque::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{EquiJoin, JoinInMemory, SimpleHashSemiJoin};
+
+    #[test]
+    fn simple_semi_hash() {
+        let join = SimpleHashSemiJoin::build_in_memory(
+            0..60,
+            (50..70).chain(50..70),
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            7,
+        );
+        let mut results: Vec<_> = join.collect();
+        results.sort_unstable();
+        assert_eq!(vec![50, 51, 52, 53, 54, 55, 56, 57, 58, 59], results);
+    }
+}
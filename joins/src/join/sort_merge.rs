@@ -1,181 +1,710 @@
-use std::rc::Rc;
-use futures::{Stream, Poll, Async, stream};
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use futures::{Future, Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
+use futures::future::Either;
 use named_type::NamedType;
 use named_type_derive::*;
+use pin_project::pin_project;
 
-use super::{Join, OrderedMergeJoin, External, ExternalStorage};
+use super::{Join, JoinError, OrderedMergeJoin, AsyncExternal, AsyncExternalStorage, Storage};
+use super::parallel_sort::{ParallelMerger, ParallelSortConfig, SortTask, Threads};
+use crate::HeapSize;
 use crate::predicate::{JoinPredicate, MergePredicate, SwapPredicate};
 
+/// Sorts both inputs through `AsyncExternalStorage` before merging them.
+///
+/// Inputs marked as sorted through `Presorted` are not sorted but fed straight into the merge.
+#[pin_project]
 #[derive(NamedType)]
-pub enum SortMergeJoin<L: Stream, R: Stream, D: MergePredicate<Left=L::Item, Right=R::Item>, E>
+pub struct SortMergeJoin<L: TryStream, R: TryStream, D: MergePredicate<Left=L::Ok, Right=R::Ok>, E>
     where
-        E: ExternalStorage<L::Item> + ExternalStorage<R::Item> {
-    InputPhase {
-        definition: D,
-        storage: E,
-        left: stream::Fuse<L>,
-        right: stream::Fuse<R>,
-
-        left_buf: Vec<L::Item>,
-        right_buf: Vec<R::Item>,
-        buf_limit: usize,
-        left_blocks: Vec<<E as ExternalStorage<L::Item>>::External>,
-        right_blocks: Vec<<E as ExternalStorage<R::Item>>::External>,
-    },
-    OutputPhase(OrderedMergeJoin<Merger<Rc<D>, E>, Merger<SwapPredicate<Rc<D>>, E>, Rc<D>>),
+        E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    // sorted inputs are moved into the output phase
+    left: Option<Input<L>>,
+    right: Option<Input<R>>,
+    left_sorted: bool,
+    right_sorted: bool,
+    state: State<L, R, D, E>,
+}
+type Input<S> = Pin<Box<stream::Fuse<stream::IntoStream<S>>>>;
+
+/// Configuration of a `SortMergeJoin`, `SortMergeAntiJoin` or `ProgressiveMergeJoin`.
+///
+/// Building these joins with just a `usize` sets the memory limit and merges all runs at once.
+#[derive(Clone, Copy, Debug)]
+pub struct SortMergeConfig {
+    /// Maximum size in bytes of the tuples held in memory.
+    pub memory_limit: usize,
+    /// Maximum number of runs per input merged at once.
+    ///
+    /// While there are more runs, intermediate merge passes write merged runs back to the
+    /// storage. Every run being merged keeps one `External` open.
+    pub fan_in: usize,
+}
+
+impl From<usize> for SortMergeConfig {
+    fn from(memory_limit: usize) -> Self {
+        SortMergeConfig { memory_limit, fan_in: usize::MAX }
+    }
+}
+
+/// Configuration of a `SortMergeJoin` with inputs that already arrive in `MergePredicate` order.
+///
+/// Sorted inputs are merged without being sorted first. Debug builds panic if one of them is out
+/// of order.
+#[derive(Clone, Copy, Debug)]
+pub struct Presorted<C> {
+    /// How the other inputs are sorted, a `SortMergeConfig` or a `ParallelSortConfig`.
+    pub config: C,
+    pub left: bool,
+    pub right: bool,
+}
+
+enum State<L: TryStream, R: TryStream, D: MergePredicate<Left=L::Ok, Right=R::Ok>, E>
+    where
+        E: AsyncExternalStorage<D::Left> + AsyncExternalStorage<D::Right> {
+    InputPhase(SortPhase<D, E>),
+    OutputPhase(OutputJoin<L, R, D, E>),
     Tmp,
 }
-type Merger<D, E> = SortMergerNoIndex<<D as JoinPredicate>::Left, SortMerger<D, <E as ExternalStorage<<D as JoinPredicate>::Left>>::External>>;
+type OutputJoin<L, R, D, E> = OrderedMergeJoin<MergeInput<L, Arc<D>, E>, MergeInput<R, SwapPredicate<Arc<D>>, E>, Arc<D>>;
+/// One input of the output phase: either its merged runs or, if sorted already, the input itself.
+type MergeInput<S, P, E> = Either<
+    stream::MapErr<Merger<P, E>, fn(<E as Storage>::Error) -> SortError<S, E>>,
+    stream::MapErr<Input<S>, fn(<S as TryStream>::Error) -> SortError<S, E>>,
+>;
+/// The shared predicate and the merged left and right runs.
+pub(crate) type SortedInputs<D, E> = (Arc<D>, Merger<Arc<D>, E>, Merger<SwapPredicate<Arc<D>>, E>);
+type SortError<L, E> = JoinError<<L as TryStream>::Error, <E as Storage>::Error>;
+type Run<D, E> = ChainedRun<<E as AsyncExternalStorage<<D as JoinPredicate>::Left>>::External>;
+type RunMerger<D, X> = Either<SortMerger<D, X>, ParallelMerger<D, X>>;
+pub(crate) type Merger<D, E> = SortMergerNoIndex<<D as JoinPredicate>::Left, RunMerger<D, Run<D, E>>>;
 
-fn without_index<T, S: Stream<Item=(usize, T), Error=()>>(s: S) -> SortMergerNoIndex<T, S> {
-    s.map(|(_, x)| x)
+#[define_opaque(SortMergerNoIndex)]
+fn without_index<T, S: TryStream<Ok=(usize, T)>>(s: S) -> SortMergerNoIndex<T, S> {
+    s.map_ok(|(_, x)| x)
 }
-type SortMergerNoIndex<T, S: Stream<Item=(usize, T), Error=()>> = impl Stream<Item=T, Error=()>;
+pub(crate) type SortMergerNoIndex<T, S: TryStream<Ok=(usize, T)>> = impl TryStream<Ok=T, Error=S::Error>;
 
 
 use std::cmp::Ordering;
+use std::collections::BinaryHeap;
 use crate::InnerJoinPredicate;
 
-struct SortMergerItem<D: MergePredicate, E: External<D::Left>> {
+struct SortMergerItem<D: MergePredicate, S> {
     id: usize,
-    iter: E::Iter,
-    item: <E::Iter as Iterator>::Item,
-    predicate: Rc<D>,
-}
-impl<D: MergePredicate, E: External<D::Left>> SortMergerItem<D, E> {
-    fn new(id: usize, e: &E, predicate: &Rc<D>) -> Option<Self> {
-        let mut iter = e.fetch();
-        if let Some(item) = iter.next() {
-            Some(SortMergerItem {
-                id,
-                iter,
-                item,
-                predicate: Rc::clone(&predicate),
-            })
-        } else {
-            None
-        }
-    }
-    fn next(mut self) -> ((usize, <E::Iter as Iterator>::Item), Option<Self>) {
-        let ret = self.item;
-        let me = if let Some(next) = self.iter.next() {
-            Some(SortMergerItem {
-                id: self.id,
-                item: next,
-                iter: self.iter,
-                predicate: self.predicate,
-            })
-        } else {
-            None
-        };
-        ((self.id, ret), me)
-    }
+    fetch: Pin<Box<S>>,
+    item: D::Left,
+    predicate: Arc<D>,
 }
-impl<D: MergePredicate, E: External<D::Left>> PartialEq for SortMergerItem<D, E> {
+impl<D: MergePredicate, S> PartialEq for SortMergerItem<D, S> {
     fn eq(&self, rhs: &Self) -> bool {
         self.cmp(rhs) == Ordering::Equal
     }
 }
-impl<D: MergePredicate, E: External<D::Left>> Eq for SortMergerItem<D, E> {}
-impl<D: MergePredicate, E: External<D::Left>> PartialOrd for SortMergerItem<D, E> {
+impl<D: MergePredicate, S> Eq for SortMergerItem<D, S> {}
+impl<D: MergePredicate, S> PartialOrd for SortMergerItem<D, S> {
     fn partial_cmp(&self, rhs: &Self) -> Option<Ordering> {
         Some(self.cmp(rhs))
     }
 }
-impl<D: MergePredicate, E: External<D::Left>> Ord for SortMergerItem<D, E> {
+impl<D: MergePredicate, S> Ord for SortMergerItem<D, S> {
     fn cmp(&self, rhs: &Self) -> Ordering {
         self.predicate.cmp_left(&self.item, &rhs.item).reverse() // reverse order to get a min-heap
     }
 }
-pub struct SortMerger<D: MergePredicate, E: External<D::Left>> {
-    ways: std::collections::BinaryHeap<SortMergerItem<D, E>>,
+/// Merges sorted streams, yielding every tuple along with the index of its stream.
+#[pin_project]
+pub struct StreamMerger<D: MergePredicate, S> {
+    ways: std::collections::BinaryHeap<SortMergerItem<D, S>>,
+    // streams whose next tuple has yet to be fetched
+    refill: Vec<(usize, Pin<Box<S>>)>,
+    predicate: Arc<D>,
 }
-impl<D: MergePredicate, E: External<D::Left>> SortMerger<D, E> {
-    pub fn new(e: Vec<E>, predicate: D) -> Self {
-        let rc = Rc::new(predicate);
-        let ways = e.into_iter().enumerate().filter_map(|(i, x)| SortMergerItem::new(i, &x, &rc)).collect();
-        SortMerger { ways }
+impl<D: MergePredicate, S: TryStream<Ok=D::Left>> StreamMerger<D, S> {
+    pub fn new<I: IntoIterator<Item=S>>(streams: I, predicate: D) -> Self {
+        let refill: Vec<_> = streams.into_iter().map(Box::pin).enumerate().collect();
+        StreamMerger {
+            ways: std::collections::BinaryHeap::with_capacity(refill.len()),
+            refill,
+            predicate: Arc::new(predicate),
+        }
     }
 }
-impl<D: MergePredicate, E: External<D::Left>> Stream for SortMerger<D, E> {
-    type Item = (usize, D::Left);
-    type Error = ();
+/// Merges sorted runs, yielding every tuple along with the index of its run.
+pub type SortMerger<D, X> = StreamMerger<D, <X as AsyncExternal<<D as JoinPredicate>::Left>>::Fetch>;
+
+pub(crate) fn merge_runs<D: MergePredicate, X: AsyncExternal<D::Left>>(runs: Vec<X>, predicate: D) -> SortMerger<D, X> {
+    StreamMerger::new(runs.iter().map(X::fetch), predicate)
+}
+
+impl<D: MergePredicate, S: TryStream<Ok=D::Left>> Stream for StreamMerger<D, S> {
+    type Item = Result<(usize, D::Left), S::Error>;
 
-    fn poll(&mut self) -> Poll<Option<(usize, D::Left)>, ()> {
-        Ok(Async::Ready(if let Some(x) = self.ways.pop() {
-            let (item, way) = x.next();
-            if let Some(way) = way {
-                self.ways.push(way);
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let mut i = 0;
+        while i < this.refill.len() {
+            match this.refill[i].1.as_mut().try_poll_next(cx) {
+                Poll::Pending => i += 1,
+                Poll::Ready(next) => {
+                    let (id, fetch) = this.refill.swap_remove(i);
+                    match next {
+                        Some(Ok(item)) => this.ways.push(SortMergerItem { id, fetch, item, predicate: Arc::clone(this.predicate) }),
+                        Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                        None => (),
+                    }
+                }
             }
-            Some(item)
-        } else {
-            None
+        }
+        if !this.refill.is_empty() {
+            // the smallest tuple might still be in flight
+            return Poll::Pending;
+        }
+
+        Poll::Ready(this.ways.pop().map(|SortMergerItem { id, fetch, item, .. }| {
+            this.refill.push((id, fetch));
+            Ok((id, item))
         }))
     }
 }
 
-fn manage_buf<T, E: ExternalStorage<T>, F: Fn(&T, &T) -> std::cmp::Ordering>(
-    value: Async<Option<T>>,
-    buffer: &mut Vec<T>,
-    size_limit: usize,
-    storage: &mut E,
-    blocks: &mut Vec<E::External>,
-    sort: F) {
-    if let Async::Ready(Some(v)) = value {
-        buffer.push(v);
+/// A run stored in one or more chunks, which are fetched one after the other.
+pub struct ChainedRun<X>(Arc<Vec<X>>);
+
+impl<X> ChainedRun<X> {
+    pub(crate) fn new(chunks: Vec<X>) -> Self {
+        ChainedRun(Arc::new(chunks))
     }
-    if buffer.len() >= size_limit {
-        buffer.sort_by(sort);
-        //println!("flush");
-        blocks.push(storage.store(std::mem::replace(buffer, Vec::new())));
+}
+
+impl<T, X: AsyncExternal<T>> AsyncExternal<T> for ChainedRun<X> {
+    type Error = X::Error;
+    type Fetch = ChainedFetch<T, X>;
+    fn fetch(&self) -> Self::Fetch {
+        ChainedFetch { chunks: Arc::clone(&self.0), next: 0, current: None }
     }
 }
 
-impl<L, R, D, E> Stream for SortMergeJoin<L, R, D, E>
-    where L: Stream,
-          R: Stream<Error=L::Error>,
-          D: InnerJoinPredicate + MergePredicate<Left=L::Item, Right=R::Item>,
-          E: ExternalStorage<L::Item> + ExternalStorage<R::Item> {
-    type Item = D::Output;
-    type Error = L::Error;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+pub struct ChainedFetch<T, X: AsyncExternal<T>> {
+    chunks: Arc<Vec<X>>,
+    next: usize,
+    current: Option<Pin<Box<X::Fetch>>>,
+}
+
+impl<T, X: AsyncExternal<T>> Stream for ChainedFetch<T, X> {
+    type Item = Result<T, X::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         loop {
-            match self {
-                SortMergeJoin::InputPhase { left, right, left_buf, right_buf, buf_limit, storage, left_blocks, right_blocks, definition, .. } => {
-                    let l = left.poll()?;
-                    let r = right.poll()?;
-
-                    match (l, r) {
-                        (Async::Ready(None), Async::Ready(None)) => {
-                            // fall out of the match in order to replace self
-                        }
-                        (Async::NotReady, Async::NotReady) | (Async::Ready(None), Async::NotReady) | (Async::NotReady, Async::Ready(None)) => return Ok(Async::NotReady),
-                        (l, r) => {
-                            manage_buf(l, left_buf, *buf_limit, storage, left_blocks, |a, b| definition.cmp_left(a, b));
-                            manage_buf(r, right_buf, *buf_limit, storage, right_blocks, |a, b| definition.cmp_right(a, b));
-
-                            continue;
-                        }
+            if let Some(current) = &mut self.current {
+                if let Some(next) = ready!(current.as_mut().poll_next(cx)) {
+                    return Poll::Ready(Some(next));
+                }
+            }
+            // close the previous chunk before opening the next one
+            let this = &mut *self;
+            this.current = None;
+            match this.chunks.get(this.next) {
+                Some(chunk) => this.current = Some(Box::pin(chunk.fetch())),
+                None => return Poll::Ready(None),
+            }
+            this.next += 1;
+        }
+    }
+}
+
+struct SelectionItem<P: MergePredicate> {
+    run: usize,
+    item: P::Left,
+    predicate: Arc<P>,
+}
+impl<P: MergePredicate> PartialEq for SelectionItem<P> {
+    fn eq(&self, rhs: &Self) -> bool {
+        self.cmp(rhs) == Ordering::Equal
+    }
+}
+impl<P: MergePredicate> Eq for SelectionItem<P> {}
+impl<P: MergePredicate> PartialOrd for SelectionItem<P> {
+    fn partial_cmp(&self, rhs: &Self) -> Option<Ordering> {
+        Some(self.cmp(rhs))
+    }
+}
+impl<P: MergePredicate> Ord for SelectionItem<P> {
+    fn cmp(&self, rhs: &Self) -> Ordering {
+        // reverse order to get a min-heap
+        rhs.run.cmp(&self.run).then_with(|| self.predicate.cmp_left(&self.item, &rhs.item).reverse())
+    }
+}
+
+/// Replacement selection: emits sorted runs from a heap of tuples.
+///
+/// A tuple read from the input joins the current run unless it is smaller than the last tuple
+/// emitted. Runs average twice the size of the heap on random input, and nearly sorted input
+/// becomes a single run. The current run is handed out in chunks, the last emitted tuple stays
+/// behind to compare new tuples with.
+struct Selection<P: MergePredicate> {
+    predicate: Arc<P>,
+    heap: BinaryHeap<SelectionItem<P>>,
+    heap_size: usize,
+    heap_limit: usize,
+    run: usize,
+    chunk: Vec<P::Left>,
+    chunk_size: usize,
+    chunk_limit: usize,
+}
+
+impl<P: MergePredicate> Selection<P> where P::Left: HeapSize {
+    /// Keeps at most `memory_limit` bytes in the heap and the current chunk.
+    fn new(predicate: Arc<P>, memory_limit: usize) -> Self {
+        let chunk_limit = memory_limit / 4;
+        Selection {
+            predicate,
+            heap: BinaryHeap::new(),
+            heap_size: 0,
+            heap_limit: memory_limit - chunk_limit,
+            run: 0,
+            chunk: Vec::new(),
+            chunk_size: 0,
+            chunk_limit,
+        }
+    }
+
+    fn push(&mut self, item: P::Left) -> Option<Chunk<P::Left>> {
+        let run = match self.chunk.last() {
+            Some(last) if self.predicate.cmp_left(&item, last) == Ordering::Less => self.run + 1,
+            _ => self.run,
+        };
+        self.heap_size += item.memory_size();
+        self.heap.push(SelectionItem { run, item, predicate: Arc::clone(&self.predicate) });
+        while self.heap_size > self.heap_limit {
+            let chunk = self.pop();
+            if chunk.is_some() {
+                return chunk;
+            }
+        }
+        None
+    }
+
+    /// Moves the smallest tuple of the heap to the current chunk.
+    fn pop(&mut self) -> Option<Chunk<P::Left>> {
+        let SelectionItem { run, item, .. } = self.heap.pop()?;
+        let size = item.memory_size();
+        self.heap_size -= size;
+        if run != self.run {
+            self.run = run;
+            self.chunk_size = size;
+            return Some(Chunk::Sorted(mem::replace(&mut self.chunk, vec![item]), true));
+        }
+        self.chunk_size += size;
+        self.chunk.push(item);
+        if self.chunk_size >= self.chunk_limit && self.chunk.len() > 1 {
+            let last = self.chunk.pop().unwrap();
+            self.chunk_size = last.memory_size();
+            return Some(Chunk::Sorted(mem::replace(&mut self.chunk, vec![last]), false));
+        }
+        None
+    }
+
+    /// Empties the heap chunk by chunk once the input is exhausted.
+    fn finish(&mut self) -> Option<Chunk<P::Left>> {
+        while !self.heap.is_empty() {
+            let chunk = self.pop();
+            if chunk.is_some() {
+                return chunk;
+            }
+        }
+        if self.chunk.is_empty() {
+            return None;
+        }
+        self.chunk_size = 0;
+        Some(Chunk::Sorted(mem::take(&mut self.chunk), true))
+    }
+}
+
+/// Part of a run that is ready to be stored.
+enum Chunk<T> {
+    /// Sorted tuples, along with whether they complete their run.
+    Sorted(Vec<T>, bool),
+    /// A whole run being sorted on a background thread.
+    Sorting(SortTask<T>),
+}
+
+/// How the runs of an input are generated.
+enum Generator<P: MergePredicate> {
+    Selection(Box<Selection<P>>),
+    /// Fills buffers of `limit` bytes, each sorted on a background thread into a run.
+    Threads {
+        threads: Box<Threads<P::Left>>,
+        buffer: Vec<P::Left>,
+        size: usize,
+        limit: usize,
+    },
+}
+
+impl<P: MergePredicate> Generator<P> where P::Left: HeapSize {
+    fn push(&mut self, item: P::Left) -> Option<Chunk<P::Left>> {
+        match self {
+            Generator::Selection(selection) => selection.push(item),
+            Generator::Threads { threads, buffer, size, limit } => {
+                *size += item.memory_size();
+                buffer.push(item);
+                if *size < *limit {
+                    return None;
+                }
+                *size = 0;
+                Some(Chunk::Sorting(threads.sort(mem::take(buffer))))
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Option<Chunk<P::Left>> {
+        match self {
+            Generator::Selection(selection) => selection.finish(),
+            Generator::Threads { buffer, .. } if buffer.is_empty() => None,
+            Generator::Threads { threads, buffer, size, .. } => {
+                *size = 0;
+                Some(Chunk::Sorting(threads.sort(mem::take(buffer))))
+            }
+        }
+    }
+}
+
+/// A chunk on its way to the storage.
+struct Spill<T, E: AsyncExternalStorage<T>> {
+    run: usize,
+    state: SpillState<T, E>,
+}
+enum SpillState<T, E: AsyncExternalStorage<T>> {
+    Sorting(SortTask<T>),
+    Storing(Pin<Box<E::Store>>),
+}
+
+/// An intermediate merge pass, writing the merged run back to the storage chunk by chunk.
+struct MergePass<P: MergePredicate, E: AsyncExternalStorage<P::Left>> {
+    merger: SortMerger<Arc<P>, ChainedRun<E::External>>,
+    chunk: Vec<P::Left>,
+    chunk_size: usize,
+    store: Option<Pin<Box<E::Store>>>,
+    // the merged run's chunks stored so far
+    chunks: Vec<E::External>,
+    done: bool,
+}
+
+impl<P: MergePredicate, E: AsyncExternalStorage<P::Left>> MergePass<P, E> where P::Left: HeapSize {
+    fn poll(&mut self, storage: &mut E, chunk_limit: usize, cx: &mut Context<'_>) -> Poll<Result<(), E::Error>> {
+        loop {
+            if let Some(store) = &mut self.store {
+                let chunk = ready!(store.as_mut().poll(cx));
+                self.store = None;
+                self.chunks.push(chunk?);
+            }
+            if self.done {
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(self.merger.poll_next_unpin(cx)) {
+                Some(Ok((_, item))) => {
+                    self.chunk_size += item.memory_size();
+                    self.chunk.push(item);
+                    if self.chunk_size >= chunk_limit {
+                        self.chunk_size = 0;
+                        self.store = Some(Box::pin(storage.store(mem::take(&mut self.chunk))));
+                    }
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => {
+                    self.done = true;
+                    if !self.chunk.is_empty() {
+                        self.store = Some(Box::pin(storage.store(mem::take(&mut self.chunk))));
                     }
                 }
-                SortMergeJoin::OutputPhase(omj) => return omj.poll().map_err(|_| unreachable!()),
-                SortMergeJoin::Tmp => unreachable!(),
             }
+        }
+    }
+}
 
-            *self = match std::mem::replace(self, SortMergeJoin::Tmp) {
-                SortMergeJoin::InputPhase { mut left_buf, mut right_buf, definition, mut storage, mut left_blocks, mut right_blocks, .. } => {
-                    manage_buf(Async::NotReady, &mut left_buf, 0, &mut storage, &mut left_blocks, |a, b| definition.cmp_left(a, b));
-                    manage_buf(Async::NotReady, &mut right_buf, 0, &mut storage, &mut right_blocks, |a, b| definition.cmp_right(a, b));
-                    assert!(left_buf.is_empty());
-                    assert!(right_buf.is_empty());
+/// Generates and stores the sorted runs of one input.
+struct RunBuilder<P: MergePredicate, E: AsyncExternalStorage<P::Left>> {
+    predicate: Arc<P>,
+    generator: Generator<P>,
+    spills: Vec<Spill<P::Left, E>>,
+    // the chunks of every run, in order
+    runs: Vec<Vec<E::External>>,
+    // whether the last run has more chunks to come
+    open: bool,
+    fan_in: usize,
+    merge: Option<Box<MergePass<P, E>>>,
+    // bytes per chunk written by merge passes
+    merge_chunk_limit: usize,
+}
 
-                    let definition = Rc::new(definition);
+impl<P: MergePredicate, E: AsyncExternalStorage<P::Left>> RunBuilder<P, E> where P::Left: HeapSize {
+    /// Generates runs by replacement selection, using `memory_limit` bytes.
+    fn selection(predicate: P, memory_limit: usize, fan_in: usize) -> Self {
+        assert!(fan_in > 1);
+        let predicate = Arc::new(predicate);
+        let generator = Generator::Selection(Box::new(Selection::new(Arc::clone(&predicate), memory_limit)));
+        RunBuilder::new(predicate, generator, fan_in, memory_limit)
+    }
 
-                    let left = without_index(SortMerger::new(left_blocks, definition.clone()));
-                    let right = without_index(SortMerger::new(right_blocks, definition.clone().swap()));
+    fn new(predicate: Arc<P>, generator: Generator<P>, fan_in: usize, merge_chunk_limit: usize) -> Self {
+        RunBuilder {
+            predicate,
+            generator,
+            spills: Vec::new(),
+            runs: Vec::new(),
+            open: false,
+            fan_in,
+            merge: None,
+            merge_chunk_limit,
+        }
+    }
+
+    fn push(&mut self, item: P::Left, storage: &mut E) {
+        if let Some(chunk) = self.generator.push(item) {
+            self.spill(chunk, storage);
+        }
+    }
+
+    /// Spills the next chunk left once the input is exhausted, returning whether there was one.
+    fn finish(&mut self, storage: &mut E) -> bool {
+        match self.generator.finish() {
+            Some(chunk) => {
+                self.spill(chunk, storage);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn spill(&mut self, chunk: Chunk<P::Left>, storage: &mut E) {
+        if !self.open {
+            self.runs.push(Vec::new());
+        }
+        let (state, complete) = match chunk {
+            Chunk::Sorted(tuples, complete) => (SpillState::Storing(Box::pin(storage.store(tuples))), complete),
+            Chunk::Sorting(task) => (SpillState::Sorting(task), true),
+        };
+        self.open = !complete;
+        self.spills.push(Spill { run: self.runs.len() - 1, state });
+    }
+
+    /// Drives the pending spills, returning the number still in flight.
+    fn poll_spills(&mut self, storage: &mut E, cx: &mut Context<'_>) -> Result<usize, E::Error> {
+        let mut i = 0;
+        while i < self.spills.len() {
+            match &mut self.spills[i].state {
+                SpillState::Sorting(task) => match Pin::new(task).poll(cx) {
+                    Poll::Ready(run) => self.spills[i].state = SpillState::Storing(Box::pin(storage.store(run))),
+                    Poll::Pending => i += 1,
+                },
+                SpillState::Storing(store) => match store.as_mut().poll(cx) {
+                    Poll::Ready(chunk) => {
+                        let run = self.spills.swap_remove(i).run;
+                        self.runs[run].push(chunk?);
+                    }
+                    Poll::Pending => i += 1,
+                },
+            }
+        }
+        Ok(self.spills.len())
+    }
 
-                    SortMergeJoin::OutputPhase(OrderedMergeJoin::new(left, right, definition))
+    /// Merges stored runs until at most `fan_in` of them are left.
+    fn poll_merge(&mut self, storage: &mut E, cx: &mut Context<'_>) -> Poll<Result<(), E::Error>> {
+        loop {
+            if self.merge.is_none() {
+                if self.runs.len() <= self.fan_in {
+                    return Poll::Ready(Ok(()));
+                }
+                let runs = self.runs.drain(..self.fan_in).map(|chunks| ChainedRun(Arc::new(chunks))).collect();
+                self.merge = Some(Box::new(MergePass {
+                    merger: merge_runs(runs, Arc::clone(&self.predicate)),
+                    chunk: Vec::new(),
+                    chunk_size: 0,
+                    store: None,
+                    chunks: Vec::new(),
+                    done: false,
+                }));
+            }
+            ready!(self.merge.as_mut().unwrap().poll(storage, self.merge_chunk_limit, cx))?;
+            let merged = self.merge.take().unwrap().chunks;
+            self.runs.push(merged);
+        }
+    }
+
+    /// Merges the stored runs, on background threads if there are enough of them.
+    fn into_merger(self, predicate: P) -> RunMerger<P, ChainedRun<E::External>> {
+        assert!(self.spills.is_empty() && !self.open && self.merge.is_none());
+        let runs = self.runs.into_iter().map(|chunks| ChainedRun(Arc::new(chunks))).collect::<Vec<_>>();
+        match self.generator {
+            Generator::Threads { threads, .. } if threads.merge_groups(runs.len()) > 1 => Either::Right(threads.merge(runs, predicate)),
+            _ => Either::Left(merge_runs(runs, predicate)),
+        }
+    }
+}
+
+/// Input phase of a sort-merge join: reads both inputs into sorted runs.
+///
+/// Runs are generated by replacement selection, with at most one chunk per input being stored
+/// at any time. Reading an input pauses until its previous chunk is stored. When sorting on
+/// background threads instead, up to one run per thread and input is being sorted or stored.
+pub(crate) struct SortPhase<D: MergePredicate, E>
+    where
+        E: AsyncExternalStorage<D::Left> + AsyncExternalStorage<D::Right> {
+    definition: Arc<D>,
+    storage: E,
+    left: RunBuilder<Arc<D>, E>,
+    right: RunBuilder<SwapPredicate<Arc<D>>, E>,
+    max_spills: usize,
+}
+impl<D: MergePredicate, E> SortPhase<D, E>
+    where
+        D::Left: HeapSize,
+        D::Right: HeapSize,
+        E: AsyncExternalStorage<D::Left> + AsyncExternalStorage<D::Right> {
+    pub(crate) fn new(definition: D, storage: E, config: SortMergeConfig) -> Self {
+        let definition = Arc::new(definition);
+        let memory_limit = config.memory_limit / 2;
+        let left = RunBuilder::selection(Arc::clone(&definition), memory_limit, config.fan_in);
+        let right = RunBuilder::selection(Arc::clone(&definition).swap(), memory_limit, config.fan_in);
+        SortPhase { definition, storage, left, right, max_spills: 1 }
+    }
+
+    /// Drives the pending spills until fewer than `max` per input are in flight.
+    fn poll_spills(&mut self, max: usize, cx: &mut Context<'_>) -> Poll<Result<(), <E as Storage>::Error>> {
+        let l = self.left.poll_spills(&mut self.storage, cx)?;
+        let r = self.right.poll_spills(&mut self.storage, cx)?;
+        if l >= max || r >= max {
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Consumes both inputs, completing once both of them are exhausted.
+    ///
+    /// An input that is `None` is not read at all.
+    pub(crate) fn poll_input<L, R>(
+        &mut self,
+        mut left: Option<Pin<&mut stream::Fuse<stream::IntoStream<L>>>>,
+        mut right: Option<Pin<&mut stream::Fuse<stream::IntoStream<R>>>>,
+        cx: &mut Context<'_>) -> Poll<Result<(), SortError<L, E>>>
+        where
+            L: TryStream<Ok=D::Left>,
+            R: TryStream<Ok=D::Right, Error=L::Error> {
+        loop {
+            ready!(self.poll_spills(self.max_spills, cx)).map_err(JoinError::Storage)?;
+
+            let l = match &mut left {
+                Some(left) => left.as_mut().try_poll_next(cx)?,
+                None => Poll::Ready(None),
+            };
+            let r = match &mut right {
+                Some(right) => right.as_mut().try_poll_next(cx)?,
+                None => Poll::Ready(None),
+            };
+
+            match (l, r) {
+                (Poll::Ready(None), Poll::Ready(None)) => return Poll::Ready(Ok(())),
+                (Poll::Pending, Poll::Pending) | (Poll::Ready(None), Poll::Pending) | (Poll::Pending, Poll::Ready(None)) => return Poll::Pending,
+                (l, r) => {
+                    if let Poll::Ready(Some(l)) = l {
+                        self.left.push(l, &mut self.storage);
+                    }
+                    if let Poll::Ready(Some(r)) = r {
+                        self.right.push(r, &mut self.storage);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stores what is left of the runs, completing once all of them are stored and merged down
+    /// to the fan-in.
+    pub(crate) fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), <E as Storage>::Error>> {
+        loop {
+            ready!(self.poll_spills(self.max_spills, cx))?;
+            let l = self.left.finish(&mut self.storage);
+            let r = self.right.finish(&mut self.storage);
+            if !l && !r {
+                break;
+            }
+        }
+        ready!(self.poll_spills(1, cx))?;
+        let l = self.left.poll_merge(&mut self.storage, cx)?;
+        let r = self.right.poll_merge(&mut self.storage, cx)?;
+        ready!(l);
+        ready!(r);
+        Poll::Ready(Ok(()))
+    }
+
+    /// Merges the sorted runs of both sides, once `poll_finish` has completed.
+    pub(crate) fn into_sorted(self) -> SortedInputs<D, E> {
+        let SortPhase { definition, left, right, .. } = self;
+        let left = without_index(left.into_merger(definition.clone()));
+        let right = without_index(right.into_merger(definition.clone().swap()));
+        (definition, left, right)
+    }
+}
+impl<D: MergePredicate, E> SortPhase<D, E>
+    where
+        D: Send + Sync + 'static,
+        D::Left: HeapSize + Send + 'static,
+        D::Right: HeapSize + Send + 'static,
+        E: AsyncExternalStorage<D::Left> + AsyncExternalStorage<D::Right> {
+    /// Sorts and merges the runs on a pool of `config.threads` background threads per input.
+    pub(crate) fn parallel(definition: D, storage: E, config: ParallelSortConfig) -> Self {
+        assert!(config.fan_in > 1);
+        let definition = Arc::new(definition);
+        let limit = config.memory_limit / 2 / config.threads.max(1);
+        let left = Arc::new(Arc::clone(&definition));
+        let right = Arc::new(Arc::clone(&definition).swap());
+        let left_threads = Generator::Threads { threads: Box::new(Threads::new(config.threads, Arc::clone(&left), config.memory_limit / 2)), buffer: Vec::new(), size: 0, limit };
+        let right_threads = Generator::Threads { threads: Box::new(Threads::new(config.threads, Arc::clone(&right), config.memory_limit / 2)), buffer: Vec::new(), size: 0, limit };
+        SortPhase {
+            definition,
+            storage,
+            left: RunBuilder::new(left, left_threads, config.fan_in, limit),
+            right: RunBuilder::new(right, right_threads, config.fan_in, limit),
+            max_spills: config.threads,
+        }
+    }
+}
+
+impl<L, R, D, E> Stream for SortMergeJoin<L, R, D, E>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: InnerJoinPredicate + MergePredicate<Left=L::Ok, Right=R::Ok>,
+          E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    type Item = Result<D::Output, JoinError<L::Error, <E as Storage>::Error>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        loop {
+            match this.state {
+                State::InputPhase(input) => {
+                    let (left_sorted, right_sorted) = (*this.left_sorted, *this.right_sorted);
+                    let left = this.left.as_mut().filter(|_| !left_sorted).map(|l| l.as_mut());
+                    let right = this.right.as_mut().filter(|_| !right_sorted).map(|r| r.as_mut());
+                    ready!(input.poll_input(left, right, cx))?;
+                    ready!(input.poll_finish(cx)).map_err(JoinError::Storage)?;
+                }
+                State::OutputPhase(omj) => return Pin::new(omj).poll_next(cx),
+                State::Tmp => unreachable!(),
+            }
+
+            *this.state = match std::mem::replace(this.state, State::Tmp) {
+                State::InputPhase(input) => {
+                    let (definition, left, right) = input.into_sorted();
+                    let left = merge_input::<_, _, E>(left, this.left.take(), *this.left_sorted);
+                    let right = merge_input::<_, _, E>(right, this.right.take(), *this.right_sorted);
+                    let join = OrderedMergeJoin::new(left, right, definition);
+                    let check = cfg!(debug_assertions) && (*this.left_sorted || *this.right_sorted);
+                    State::OutputPhase(if check { join.check_order() } else { join })
                 }
                 _ => unreachable!(),
             }
@@ -183,23 +712,375 @@ impl<L, R, D, E> Stream for SortMergeJoin<L, R, D, E>
     }
 }
 
+fn merge_input<S, P, E>(merger: Merger<P, E>, input: Option<Input<S>>, sorted: bool) -> MergeInput<S, P, E>
+    where S: TryStream<Ok=P::Left>,
+          P: MergePredicate,
+          E: AsyncExternalStorage<P::Left> {
+    match input {
+        Some(input) if sorted => Either::Right(input.map_err(JoinError::Input as fn(_) -> _)),
+        _ => Either::Left(merger.map_err(JoinError::Storage as fn(_) -> _)),
+    }
+}
+
+impl<L, R, D, E> SortMergeJoin<L, R, D, E>
+    where L: TryStream,
+          R: TryStream,
+          D: MergePredicate<Left=L::Ok, Right=R::Ok>,
+          E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    fn new(left: L, right: R, input: SortPhase<D, E>, left_sorted: bool, right_sorted: bool) -> Self {
+        SortMergeJoin {
+            left: Some(Box::pin(left.into_stream().fuse())),
+            right: Some(Box::pin(right.into_stream().fuse())),
+            left_sorted,
+            right_sorted,
+            state: State::InputPhase(input),
+        }
+    }
+}
+
 impl<L, R, D, E> Join<L, R, D, E, usize> for SortMergeJoin<L, R, D, E>
-    where L: Stream,
-          R: Stream<Error=L::Error>,
-          D: InnerJoinPredicate + MergePredicate<Left=L::Item, Right=R::Item>,
-          E: ExternalStorage<L::Item> + ExternalStorage<R::Item> {
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: InnerJoinPredicate + MergePredicate<Left=L::Ok, Right=R::Ok>,
+          E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
     fn build(left: L, right: R, definition: D, storage: E, main_memory: usize) -> Self {
-        SortMergeJoin::InputPhase {
-            left: left.fuse(),
-            right: right.fuse(),
-            left_buf: Vec::new(),
-            right_buf: Vec::new(),
-            left_blocks: Vec::new(),
-            right_blocks: Vec::new(),
-            buf_limit: main_memory / 2,
+        Self::build(left, right, definition, storage, SortMergeConfig::from(main_memory))
+    }
+}
 
-            definition,
-            storage
+impl<L, R, D, E> Join<L, R, D, E, SortMergeConfig> for SortMergeJoin<L, R, D, E>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: InnerJoinPredicate + MergePredicate<Left=L::Ok, Right=R::Ok>,
+          E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    fn build(left: L, right: R, definition: D, storage: E, config: SortMergeConfig) -> Self {
+        SortMergeJoin::new(left, right, SortPhase::new(definition, storage, config), false, false)
+    }
+}
+
+impl<L, R, D, E> Join<L, R, D, E, Presorted<SortMergeConfig>> for SortMergeJoin<L, R, D, E>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: InnerJoinPredicate + MergePredicate<Left=L::Ok, Right=R::Ok>,
+          E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    fn build(left: L, right: R, definition: D, storage: E, sorted: Presorted<SortMergeConfig>) -> Self {
+        SortMergeJoin::new(left, right, SortPhase::new(definition, storage, sorted.config), sorted.left, sorted.right)
+    }
+}
+
+impl<L, R, D, E> Join<L, R, D, E, ParallelSortConfig> for SortMergeJoin<L, R, D, E>
+    where L: TryStream,
+          L::Ok: HeapSize + Send + 'static,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize + Send + 'static,
+          D: InnerJoinPredicate + MergePredicate<Left=L::Ok, Right=R::Ok> + Send + Sync + 'static,
+          E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    fn build(left: L, right: R, definition: D, storage: E, config: ParallelSortConfig) -> Self {
+        SortMergeJoin::new(left, right, SortPhase::parallel(definition, storage, config), false, false)
+    }
+}
+
+impl<L, R, D, E> Join<L, R, D, E, Presorted<ParallelSortConfig>> for SortMergeJoin<L, R, D, E>
+    where L: TryStream,
+          L::Ok: HeapSize + Send + 'static,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize + Send + 'static,
+          D: InnerJoinPredicate + MergePredicate<Left=L::Ok, Right=R::Ok> + Send + Sync + 'static,
+          E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    fn build(left: L, right: R, definition: D, storage: E, sorted: Presorted<ParallelSortConfig>) -> Self {
+        SortMergeJoin::new(left, right, SortPhase::parallel(definition, storage, sorted.config), sorted.left, sorted.right)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::convert::Infallible;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::Poll;
+    use futures::{Stream, StreamExt, TryStreamExt, future, stream};
+    use futures::executor::block_on;
+    use std::cell::Cell;
+    use std::mem;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use crate::{AsyncExternal, AsyncExternalStorage, EquiJoin, IterSource, Join, ParallelSortConfig, SortMergeConfig, Presorted, SortMergeJoin, Storage};
+    use super::{Chunk, Selection};
+
+    /// Returns `Pending` once before completing.
+    pub(crate) fn yield_now() -> impl Future<Output=()> {
+        let mut yielded = false;
+        future::poll_fn(move |cx| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+    }
+
+    /// Keeps runs in memory, but returns `Pending` before storing a run and fetching a tuple.
+    pub(crate) struct SlowStorage;
+    pub(crate) struct SlowRun<T>(Vec<T>);
+    impl Storage for SlowStorage {
+        type Error = Infallible;
+    }
+    impl<T: Clone + 'static> AsyncExternalStorage<T> for SlowStorage {
+        type External = SlowRun<T>;
+        type Store = Pin<Box<dyn Future<Output=Result<SlowRun<T>, Infallible>>>>;
+        fn store(&mut self, tuples: Vec<T>) -> Self::Store {
+            Box::pin(async move {
+                yield_now().await;
+                Ok(SlowRun(tuples))
+            })
+        }
+    }
+    impl<T: Clone + 'static> AsyncExternal<T> for SlowRun<T> {
+        type Error = Infallible;
+        type Fetch = Pin<Box<dyn Stream<Item=Result<T, Infallible>>>>;
+        fn fetch(&self) -> Self::Fetch {
+            Box::pin(stream::iter(self.0.clone()).then(|x| async move {
+                yield_now().await;
+                Ok(x)
+            }))
         }
     }
+
+    #[test]
+    fn sort_merge_async_storage() {
+        let join = SortMergeJoin::build(
+            IterSource::new((0..100).rev()),
+            IterSource::new((50..150).map(|x| x % 120)),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+            SlowStorage,
+            10,
+        );
+        let results: Vec<_> = block_on(join.try_collect()).unwrap();
+        let mut expected: Vec<_> = (50..100).chain(0..30).map(|x| (x, x)).collect();
+        expected.sort_unstable();
+        assert_eq!(expected, results);
+    }
+
+    /// Tracks how many runs are being fetched at once.
+    #[derive(Clone, Default)]
+    struct CountingStorage {
+        open: Rc<Cell<usize>>,
+        max_open: Rc<Cell<usize>>,
+    }
+    struct CountingRun(Vec<u32>, CountingStorage);
+    struct OpenGuard(Rc<Cell<usize>>);
+    impl Drop for OpenGuard {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() - 1);
+        }
+    }
+    impl Storage for CountingStorage {
+        type Error = Infallible;
+    }
+    impl AsyncExternalStorage<u32> for CountingStorage {
+        type External = CountingRun;
+        type Store = future::Ready<Result<CountingRun, Infallible>>;
+        fn store(&mut self, tuples: Vec<u32>) -> Self::Store {
+            future::ok(CountingRun(tuples, self.clone()))
+        }
+    }
+    impl AsyncExternal<u32> for CountingRun {
+        type Error = Infallible;
+        type Fetch = Pin<Box<dyn Stream<Item=Result<u32, Infallible>>>>;
+        fn fetch(&self) -> Self::Fetch {
+            let CountingStorage { open, max_open } = &self.1;
+            open.set(open.get() + 1);
+            max_open.set(max_open.get().max(open.get()));
+            let guard = OpenGuard(Rc::clone(open));
+            Box::pin(stream::iter(self.0.clone()).map(move |x| {
+                let _ = &guard;
+                Ok(x)
+            }))
+        }
+    }
+
+    #[test]
+    fn sort_merge_fan_in() {
+        let join = |storage: CountingStorage, config: SortMergeConfig| {
+            let join = SortMergeJoin::build(
+                IterSource::new((0..1000).rev()),
+                IterSource::new((0..1000).map(|x| x * 7 % 1000)),
+                EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+                storage,
+                config,
+            );
+            block_on(join.try_collect::<Vec<_>>()).unwrap()
+        };
+        let expected: Vec<_> = (0..1000).map(|x| (x, x)).collect();
+        let memory_limit = 32 * mem::size_of::<u32>();
+
+        let unbounded = CountingStorage::default();
+        assert_eq!(expected, join(unbounded.clone(), SortMergeConfig::from(memory_limit)));
+        assert!(unbounded.max_open.get() > 20);
+
+        let bounded = CountingStorage::default();
+        assert_eq!(expected, join(bounded.clone(), SortMergeConfig { memory_limit, fan_in: 3 }));
+        // both inputs are merged at the same time
+        assert!(bounded.max_open.get() <= 2 * 3);
+        assert_eq!(0, bounded.open.get());
+    }
+
+    fn selection_runs<I: IntoIterator<Item=u32>>(input: I, memory_limit: usize) -> Vec<Vec<u32>> {
+        let mut selection = Selection::new(Arc::new(EquiJoin::new(|&l: &u32| l, |&r: &u32| r)), memory_limit);
+        let mut runs = vec![Vec::new()];
+        let mut add = |chunk| match chunk {
+            Chunk::Sorted(tuples, complete) => {
+                runs.last_mut().unwrap().extend(tuples);
+                if complete {
+                    runs.push(Vec::new());
+                }
+            }
+            Chunk::Sorting(_) => unreachable!(),
+        };
+        for x in input {
+            if let Some(chunk) = selection.push(x) {
+                add(chunk);
+            }
+        }
+        while let Some(chunk) = selection.finish() {
+            add(chunk);
+        }
+        runs.pop();
+        runs
+    }
+
+    #[test]
+    fn replacement_selection() {
+        // 300 tuples fit into the heap
+        let memory_limit = 400 * mem::size_of::<u32>();
+        let input: Vec<_> = (0..10007).map(|x| x * 7919 % 10007).collect();
+        let runs = selection_runs(input.clone(), memory_limit);
+        assert!(runs.len() < 10007 / 500, "{} runs", runs.len());
+        assert!(runs.iter().all(|run| run.windows(2).all(|w| w[0] <= w[1])));
+        let mut all = runs.concat();
+        all.sort_unstable();
+        assert_eq!((0..10007).collect::<Vec<_>>(), all);
+
+        let nearly_sorted = (0..10000).map(|x| x ^ 1);
+        assert_eq!(1, selection_runs(nearly_sorted, memory_limit).len());
+    }
+
+    #[test]
+    fn sort_merge_parallel() {
+        let join = SortMergeJoin::build(
+            IterSource::new((0..3000).rev()),
+            IterSource::new((1000..5000).map(|x| x % 3500)),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+            SlowStorage,
+            ParallelSortConfig { memory_limit: 256 * std::mem::size_of::<u32>(), threads: 4, fan_in: usize::MAX },
+        );
+        let results: Vec<_> = block_on(join.try_collect()).unwrap();
+        let mut expected: Vec<_> = (1000..3000).chain(0..1500).map(|x| (x, x)).collect();
+        expected.sort_unstable();
+        assert_eq!(expected, results);
+    }
+
+    #[test]
+    fn sort_merge_parallel_fan_in() {
+        let storage = CountingStorage::default();
+        let join = SortMergeJoin::build(
+            IterSource::new((0..1000).rev()),
+            IterSource::new((0..1000).map(|x| x * 7 % 1000)),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+            storage.clone(),
+            ParallelSortConfig { memory_limit: 64 * mem::size_of::<u32>(), threads: 2, fan_in: 3 },
+        );
+        assert_eq!((0..1000).map(|x| (x, x)).collect::<Vec<_>>(), block_on(join.try_collect::<Vec<_>>()).unwrap());
+        // both inputs are merged at the same time
+        assert!(storage.max_open.get() <= 2 * 3);
+        assert_eq!(0, storage.open.get());
+    }
+
+    #[test]
+    fn sort_merge_sorted_input() {
+        let expected: Vec<_> = (0..1000).map(|x| (x, x)).collect();
+        let config = SortMergeConfig::from(32 * mem::size_of::<u32>());
+        let storage = CountingStorage::default();
+        let join = SortMergeJoin::build(
+            IterSource::new(0..1000),
+            IterSource::new((0..1000).map(|x| x * 7 % 1000)),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+            storage.clone(),
+            Presorted { config, left: true, right: false },
+        );
+        assert_eq!(expected, block_on(join.try_collect::<Vec<_>>()).unwrap());
+        assert!(storage.max_open.get() > 1);
+
+        let storage = CountingStorage::default();
+        let join = SortMergeJoin::build(
+            IterSource::new(0..1000),
+            IterSource::new(0..1000),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+            storage.clone(),
+            Presorted { config, left: true, right: true },
+        );
+        assert_eq!(expected, block_on(join.try_collect::<Vec<_>>()).unwrap());
+        assert_eq!(0, storage.max_open.get());
+    }
+
+    #[test]
+    fn sort_merge_parallel_sorted_input() {
+        let expected: Vec<_> = (0..1000).map(|x| (x, x)).collect();
+        let config = ParallelSortConfig { memory_limit: 64 * mem::size_of::<u32>(), threads: 2, fan_in: usize::MAX };
+        let storage = CountingStorage::default();
+        let join = SortMergeJoin::build(
+            IterSource::new((0..1000).map(|x| x * 7 % 1000)),
+            IterSource::new(0..1000),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+            storage.clone(),
+            Presorted { config, left: false, right: true },
+        );
+        assert_eq!(expected, block_on(join.try_collect::<Vec<_>>()).unwrap());
+        assert!(storage.max_open.get() > 1);
+
+        let storage = CountingStorage::default();
+        let join = SortMergeJoin::build(
+            IterSource::new(0..1000),
+            IterSource::new(0..1000),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+            storage.clone(),
+            Presorted { config, left: true, right: true },
+        );
+        assert_eq!(expected, block_on(join.try_collect::<Vec<_>>()).unwrap());
+        assert_eq!(0, storage.max_open.get());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "out of order")]
+    fn sort_merge_unsorted_input() {
+        let join = SortMergeJoin::build(
+            IterSource::new(0..10u32),
+            IterSource::new((0..10u32).rev()),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+            (),
+            Presorted { config: SortMergeConfig::from(10), left: false, right: true },
+        );
+        let _ = block_on(join.try_collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sort_merge_send() {
+        let join = SortMergeJoin::build(
+            IterSource::new((0..100u32).rev()),
+            IterSource::new(50..150u32),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+            (),
+            10,
+        );
+        let results = std::thread::spawn(move || block_on(join.try_collect::<Vec<_>>())).join().unwrap().unwrap();
+        assert_eq!((50..100).map(|x| (x, x)).collect::<Vec<_>>(), results);
+    }
 }
@@ -0,0 +1,128 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
+use named_type::NamedType;
+use named_type_derive::*;
+use pin_project::pin_project;
+use crate::{HeapSize, OuterJoinPredicate};
+
+use super::{Join, JoinError, AsyncExternalStorage, OrderedMergeAntiJoin, ParallelSortConfig, SortMergeConfig, Storage};
+use super::sort_merge::{Merger, SortPhase};
+use crate::predicate::{MergePredicate, SwapPredicate};
+
+/// Anti-join that sorts both inputs through `ExternalStorage` before merging them.
+///
+/// Like `SortMergeJoin`, this is blocking: output is produced once both inputs are exhausted.
+#[pin_project]
+#[derive(NamedType)]
+pub struct SortMergeAntiJoin<L: TryStream, R: TryStream, D: MergePredicate<Left=L::Ok, Right=R::Ok>, E>
+    where
+        E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Fuse<stream::IntoStream<R>>,
+    state: State<D, E>,
+}
+
+enum State<D: MergePredicate, E>
+    where
+        E: AsyncExternalStorage<D::Left> + AsyncExternalStorage<D::Right> {
+    InputPhase(SortPhase<D, E>),
+    OutputPhase(OutputJoin<D, E>),
+    Tmp,
+}
+type OutputJoin<D, E> = OrderedMergeAntiJoin<Merger<Arc<D>, E>, Merger<SwapPredicate<Arc<D>>, E>, Arc<D>>;
+
+impl<L, R, D, E> Stream for SortMergeAntiJoin<L, R, D, E>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: OuterJoinPredicate + MergePredicate<Left=L::Ok, Right=R::Ok>,
+          E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    type Item = Result<L::Ok, JoinError<L::Error, <E as Storage>::Error>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            match this.state {
+                State::InputPhase(input) => {
+                    ready!(input.poll_input(Some(this.left.as_mut()), Some(this.right.as_mut()), cx))?;
+                    ready!(input.poll_finish(cx)).map_err(JoinError::Storage)?;
+                }
+                State::OutputPhase(anti) => return Pin::new(anti).poll_next(cx).map_err(JoinError::Storage),
+                State::Tmp => unreachable!(),
+            }
+
+            *this.state = match std::mem::replace(this.state, State::Tmp) {
+                State::InputPhase(input) => {
+                    let (definition, left, right) = input.into_sorted();
+                    State::OutputPhase(OrderedMergeAntiJoin::new(left, right, definition))
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+impl<L, R, D, E> Join<L, R, D, E, usize> for SortMergeAntiJoin<L, R, D, E>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: OuterJoinPredicate + MergePredicate<Left=L::Ok, Right=R::Ok>,
+          E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    fn build(left: L, right: R, definition: D, storage: E, main_memory: usize) -> Self {
+        Self::build(left, right, definition, storage, SortMergeConfig::from(main_memory))
+    }
+}
+
+impl<L, R, D, E> Join<L, R, D, E, SortMergeConfig> for SortMergeAntiJoin<L, R, D, E>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: OuterJoinPredicate + MergePredicate<Left=L::Ok, Right=R::Ok>,
+          E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    fn build(left: L, right: R, definition: D, storage: E, config: SortMergeConfig) -> Self {
+        SortMergeAntiJoin {
+            left: left.into_stream().fuse(),
+            right: right.into_stream().fuse(),
+            state: State::InputPhase(SortPhase::new(definition, storage, config)),
+        }
+    }
+}
+
+impl<L, R, D, E> Join<L, R, D, E, ParallelSortConfig> for SortMergeAntiJoin<L, R, D, E>
+    where L: TryStream,
+          L::Ok: HeapSize + Send + 'static,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize + Send + 'static,
+          D: OuterJoinPredicate + MergePredicate<Left=L::Ok, Right=R::Ok> + Send + Sync + 'static,
+          E: AsyncExternalStorage<L::Ok> + AsyncExternalStorage<R::Ok> {
+    fn build(left: L, right: R, definition: D, storage: E, config: ParallelSortConfig) -> Self {
+        SortMergeAntiJoin {
+            left: left.into_stream().fuse(),
+            right: right.into_stream().fuse(),
+            state: State::InputPhase(SortPhase::parallel(definition, storage, config)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{EquiJoin, JoinInMemory, SortMergeAntiJoin};
+
+    #[test]
+    fn sort_merge_anti() {
+        let join = SortMergeAntiJoin::build_in_memory(
+            (0..60).rev(),
+            (0..50).chain(0..50),
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            8,
+        );
+        assert_eq!((50..60).collect::<Vec<_>>(), join.collect::<Vec<_>>());
+    }
+}
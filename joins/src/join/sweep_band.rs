@@ -0,0 +1,146 @@
+use std::borrow::Borrow;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
+use named_type::NamedType;
+use named_type_derive::*;
+use pin_project::pin_project;
+use crate::InnerJoinPredicate;
+
+use super::Join;
+use super::ordered_merge::{poll_peek_ok, take_peeked};
+use crate::predicate::BandPredicate;
+
+/// Band join of two inputs sorted by the predicate's merge order.
+///
+/// Keeps a sliding window of the right tuples that lie within the band of the current
+/// left tuple. Right tuples are added to the window until one lies above the band and evicted
+/// once they fall below the band, so the memory usage is bounded by the widest band.
+#[pin_project]
+#[derive(NamedType)]
+pub struct SweepBandJoin<L: TryStream, R: TryStream, D: InnerJoinPredicate> {
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Peekable<stream::IntoStream<R>>,
+    definition: D,
+    current: Option<L::Ok>,
+    window: VecDeque<R::Ok>,
+    output_buffer: VecDeque<D::Output>,
+}
+
+impl<L, R, D> Stream for SweepBandJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: InnerJoinPredicate + BandPredicate {
+    type Item = Result<D::Output, L::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            if let Some(buffered) = this.output_buffer.pop_front() {
+                return Poll::Ready(Some(Ok(buffered)));
+            }
+            let definition = &*this.definition;
+
+            if this.current.is_none() {
+                match ready!(this.left.as_mut().try_poll_next(cx)?) {
+                    Some(left) => {
+                        // evict tuples that can't match this or any subsequent left tuple
+                        while this.window.front().is_some_and(|r| definition.below_band(left.borrow(), r.borrow())) {
+                            this.window.pop_front();
+                        }
+                        *this.current = Some(left);
+                    }
+                    None => return Poll::Ready(None),
+                }
+            }
+            let left = this.current.as_ref().unwrap().borrow();
+
+            let extend = match ready!(poll_peek_ok(this.right.as_mut(), cx))? {
+                Some(right) => !definition.above_band(left, right.borrow()),
+                None => false,
+            };
+            if extend {
+                let right = take_peeked(this.right.as_mut(), cx);
+                if !definition.below_band(left, right.borrow()) {
+                    this.window.push_back(right);
+                }
+            } else {
+                // the window is complete for this left tuple
+                this.output_buffer.extend(this.window.iter().filter_map(|right| definition.eq(left, right.borrow())));
+                *this.current = None;
+            }
+        }
+    }
+}
+
+impl<L, R, D> SweepBandJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: InnerJoinPredicate + BandPredicate {
+    pub fn new(left: L, right: R, definition: D) -> Self {
+        SweepBandJoin {
+            left: left.into_stream().fuse(),
+            right: right.into_stream().peekable(),
+            definition,
+            current: None,
+            window: VecDeque::new(),
+            output_buffer: VecDeque::new(),
+        }
+    }
+}
+
+impl<L, R, D, E> Join<L, R, D, E, ()> for SweepBandJoin<L, R, D>
+    where L: TryStream,
+          R: TryStream<Error=L::Error>,
+          L::Ok: Borrow<D::Left>,
+          R::Ok: Borrow<D::Right>,
+          D: InnerJoinPredicate + BandPredicate {
+    fn build(left: L, right: R, definition: D, _: E, _: ()) -> Self {
+        SweepBandJoin::new(left, right, definition)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{BandJoin, JoinInMemory, SweepBandJoin};
+
+    #[test]
+    fn sweep_band() {
+        let left = vec![0u32, 3, 3, 10, 20, 21];
+        let right = vec![1u32, 2, 5, 8, 9, 15, 22, 40];
+        let join = SweepBandJoin::build_in_memory(
+            left.clone(),
+            right.clone(),
+            BandJoin::new(|&l: &u32| l, |&r: &u32| r, 2, 2),
+            (),
+        );
+        let expected: Vec<_> = left.iter()
+            .flat_map(|&l| right.iter().filter(move |&&r| l <= r + 2 && r <= l + 2).map(move |&r| (l, r)))
+            .collect();
+        assert_eq!(expected, join.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sweep_band_asymmetric() {
+        // keys near the maximum overflow when the bounds are added
+        let left = vec![0u8, 4, 9, 250, 253, 255];
+        let right = vec![0u8, 1, 3, 4, 8, 10, 15, 248, 252, 254, 255];
+        let join = SweepBandJoin::build_in_memory(
+            left.clone(),
+            right.clone(),
+            BandJoin::new(|&l: &u8| l, |&r: &u8| r, 1, 5),
+            (),
+        );
+        let expected: Vec<_> = left.iter()
+            .flat_map(|&l| right.iter().filter(move |&&r| l as u32 <= r as u32 + 1 && r as u32 <= l as u32 + 5).map(move |&r| (l, r)))
+            .collect();
+        assert_eq!(expected, join.collect::<Vec<_>>());
+    }
+}
@@ -1,10 +1,14 @@
 use std::collections::VecDeque;
-use futures::{Stream, Poll, Async, stream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, stream};
 use multimap::MultiMap;
 use named_type::NamedType;
 use named_type_derive::*;
+use pin_project::pin_project;
 
 use super::Join;
+use crate::HeapSize;
 use crate::predicate::{HashPredicate, InnerJoinPredicate};
 
 #[derive(Debug)]
@@ -18,57 +22,63 @@ impl<E> From<E> for Error<E> {
     }
 }
 
+#[pin_project]
 #[derive(NamedType)]
-pub struct SymmetricHashJoin<L: Stream, R: Stream, D: InnerJoinPredicate + HashPredicate> {
+pub struct SymmetricHashJoin<L: TryStream, R: TryStream, D: InnerJoinPredicate + HashPredicate> {
     definition: D,
-    left: stream::Fuse<L>,
-    right: stream::Fuse<R>,
-    table_left: MultiMap<u64, L::Item>,
-    table_right: MultiMap<u64, R::Item>,
-    tuple_count: usize,
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Fuse<stream::IntoStream<R>>,
+    table_left: MultiMap<u64, L::Ok>,
+    table_right: MultiMap<u64, R::Ok>,
+    // bytes held by both tables
+    table_size: usize,
     memory_limit: usize,
     output_buffer: VecDeque<D::Output>,
 }
 impl<L, R, D> Stream for SymmetricHashJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error>,
-          D: InnerJoinPredicate + HashPredicate<Left=L::Item, Right=R::Item> {
-    type Item = D::Output;
-    type Error = Error<L::Error>;
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok> {
+    type Item = Result<D::Output, Error<L::Error>>;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
         loop {
             // carry-over buffer
-            if let Some(buffered) = self.output_buffer.pop_front() {
-                return Ok(Async::Ready(Some(buffered)));
+            if let Some(buffered) = this.output_buffer.pop_front() {
+                return Poll::Ready(Some(Ok(buffered)));
             }
 
-            let left = self.left.poll()?;
-            let right = self.right.poll()?;
+            let left = this.left.as_mut().try_poll_next(cx)?;
+            let right = this.right.as_mut().try_poll_next(cx)?;
 
             match (left, right) {
-                (Async::Ready(None), Async::Ready(None)) => return Ok(Async::Ready(None)),
-                (Async::NotReady, Async::NotReady) | (Async::Ready(None), Async::NotReady) | (Async::NotReady, Async::Ready(None)) => return Ok(Async::NotReady),
+                (Poll::Ready(None), Poll::Ready(None)) => return Poll::Ready(None),
+                (Poll::Pending, Poll::Pending) | (Poll::Ready(None), Poll::Pending) | (Poll::Pending, Poll::Ready(None)) => return Poll::Pending,
                 (l, r) => {
-                    let definition = &self.definition;
-                    if let Async::Ready(Some(l)) = l {
+                    let definition = &*this.definition;
+                    if let Poll::Ready(Some(l)) = l {
                         let hash = definition.hash_left(&l);
-                        self.output_buffer.extend(
-                            self.table_right.get_vec(&hash).into_iter().flatten()
+                        this.output_buffer.extend(
+                            this.table_right.get_vec(&hash).into_iter().flatten()
                                 .filter_map(|r| definition.eq(&l, r)));
-                        self.table_left.insert(hash, l);
-                        self.tuple_count += 1;
+                        *this.table_size += l.memory_size();
+                        this.table_left.insert(hash, l);
                     }
-                    if let Async::Ready(Some(r)) = r {
+                    if let Poll::Ready(Some(r)) = r {
                         let hash = definition.hash_right(&r);
-                        self.output_buffer.extend(
-                            self.table_left.get_vec(&hash).into_iter().flatten()
+                        this.output_buffer.extend(
+                            this.table_left.get_vec(&hash).into_iter().flatten()
                                 .filter_map(|l| definition.eq(l, &r)));
-                        self.table_right.insert(hash, r);
-                        self.tuple_count += 1;
+                        *this.table_size += r.memory_size();
+                        this.table_right.insert(hash, r);
                     }
-                    if self.tuple_count > self.memory_limit {
-                        return Err(Error::OutOfMemory);
+                    if *this.table_size > *this.memory_limit {
+                        return Poll::Ready(Some(Err(Error::OutOfMemory)));
                     }
                 }
             }
@@ -76,19 +86,21 @@ impl<L, R, D> Stream for SymmetricHashJoin<L, R, D>
     }
 }
 impl<L, R, D, E> Join<L, R, D, E, usize> for SymmetricHashJoin<L, R, D>
-    where L: Stream,
-          R: Stream<Error=L::Error>,
-          D: InnerJoinPredicate + HashPredicate<Left=L::Item, Right=R::Item> {
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok> {
     fn build(left: L, right: R, definition: D, _: E, main_memory: usize) -> Self {
         SymmetricHashJoin {
             definition,
-            left: left.fuse(),
-            right: right.fuse(),
+            left: left.into_stream().fuse(),
+            right: right.into_stream().fuse(),
             table_left: MultiMap::new(),
             table_right: MultiMap::new(),
             output_buffer: VecDeque::new(),
             memory_limit: main_memory,
-            tuple_count: 0,
+            table_size: 0,
         }
     }
 }
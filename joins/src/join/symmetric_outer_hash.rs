@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Stream, TryStream, StreamExt, TryStreamExt, stream};
+use multimap::MultiMap;
+use named_type::NamedType;
+use named_type_derive::*;
+use pin_project::pin_project;
+
+use super::{Join, OuterJoinKind};
+use super::symmetric_hash::Error;
+use crate::HeapSize;
+use crate::predicate::{HashPredicate, InnerJoinPredicate};
+
+/// Outer join variant of `SymmetricHashJoin`.
+///
+/// `K` is one of `LeftOuter`, `RightOuter` or `FullOuter`.
+/// Matches are produced as soon as possible, unmatched tuples are emitted once both inputs
+/// are exhausted.
+#[pin_project]
+#[derive(NamedType)]
+pub struct SymmetricHashOuterJoin<L: TryStream, R: TryStream, D: InnerJoinPredicate + HashPredicate, K> {
+    definition: D,
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Fuse<stream::IntoStream<R>>,
+    table_left: MultiMap<u64, (L::Ok, bool)>,
+    table_right: MultiMap<u64, (R::Ok, bool)>,
+    // bytes held by both tables
+    table_size: usize,
+    memory_limit: usize,
+    output_buffer: VecDeque<D::Output>,
+    finished: bool,
+    _kind: PhantomData<K>,
+}
+impl<L, R, D, K> Stream for SymmetricHashOuterJoin<L, R, D, K>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok>,
+          K: OuterJoinKind<D> {
+    type Item = Result<D::Output, Error<L::Error>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            // carry-over buffer
+            if let Some(buffered) = this.output_buffer.pop_front() {
+                return Poll::Ready(Some(Ok(buffered)));
+            } else if *this.finished {
+                return Poll::Ready(None);
+            }
+
+            let left = this.left.as_mut().try_poll_next(cx)?;
+            let right = this.right.as_mut().try_poll_next(cx)?;
+
+            let definition = &*this.definition;
+            match (left, right) {
+                (Poll::Ready(None), Poll::Ready(None)) => {
+                    // emit unmatched tuples
+                    if K::LEFT {
+                        this.output_buffer.extend(
+                            this.table_left.iter_all().flat_map(|(_, v)| v)
+                                .filter(|(_, matched)| !matched)
+                                .filter_map(|(l, _)| K::pad_right(definition, l)));
+                    }
+                    if K::RIGHT {
+                        this.output_buffer.extend(
+                            this.table_right.iter_all().flat_map(|(_, v)| v)
+                                .filter(|(_, matched)| !matched)
+                                .filter_map(|(r, _)| K::pad_left(definition, r)));
+                    }
+                    *this.finished = true;
+                }
+                (Poll::Pending, Poll::Pending) | (Poll::Ready(None), Poll::Pending) | (Poll::Pending, Poll::Ready(None)) => return Poll::Pending,
+                (l, r) => {
+                    if let Poll::Ready(Some(l)) = l {
+                        let hash = definition.hash_left(&l);
+                        let mut matched = false;
+                        for (r, r_matched) in this.table_right.get_vec_mut(&hash).into_iter().flatten() {
+                            if let Some(output) = definition.eq(&l, r) {
+                                this.output_buffer.push_back(output);
+                                *r_matched = true;
+                                matched = true;
+                            }
+                        }
+                        *this.table_size += l.memory_size();
+                        this.table_left.insert(hash, (l, matched));
+                    }
+                    if let Poll::Ready(Some(r)) = r {
+                        let hash = definition.hash_right(&r);
+                        let mut matched = false;
+                        for (l, l_matched) in this.table_left.get_vec_mut(&hash).into_iter().flatten() {
+                            if let Some(output) = definition.eq(l, &r) {
+                                this.output_buffer.push_back(output);
+                                *l_matched = true;
+                                matched = true;
+                            }
+                        }
+                        *this.table_size += r.memory_size();
+                        this.table_right.insert(hash, (r, matched));
+                    }
+                    if *this.table_size > *this.memory_limit {
+                        return Poll::Ready(Some(Err(Error::OutOfMemory)));
+                    }
+                }
+            }
+        }
+    }
+}
+impl<L, R, D, E, K> Join<L, R, D, E, usize> for SymmetricHashOuterJoin<L, R, D, K>
+    where L: TryStream,
+          L::Ok: HeapSize,
+          R: TryStream<Error=L::Error>,
+          R::Ok: HeapSize,
+          D: InnerJoinPredicate + HashPredicate<Left=L::Ok, Right=R::Ok>,
+          K: OuterJoinKind<D> {
+    fn build(left: L, right: R, definition: D, _: E, main_memory: usize) -> Self {
+        SymmetricHashOuterJoin {
+            definition,
+            left: left.into_stream().fuse(),
+            right: right.into_stream().fuse(),
+            table_left: MultiMap::new(),
+            table_right: MultiMap::new(),
+            output_buffer: VecDeque::new(),
+            memory_limit: main_memory,
+            table_size: 0,
+            finished: false,
+            _kind: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::TryStreamExt;
+    use futures::executor::block_on;
+    use crate::{EquiJoin, FullOuter, InnerJoinPredicate, IterSource, Join, LeftOuter, RightOuter, SymmetricHashOuterJoin};
+
+    // 7 has two partners, 0..5 and 10..15 have none
+    fn left() -> IterSource<impl Iterator<Item=i32> + Clone> {
+        IterSource::new((0..10).chain(Some(7)))
+    }
+    fn right() -> IterSource<impl Iterator<Item=i32> + Clone> {
+        IterSource::new((5..15).chain(Some(7)))
+    }
+    fn matches() -> impl Iterator<Item=i32> {
+        (5..10).chain(vec![7; 3])
+    }
+
+    #[test]
+    fn symmetric_outer_hash() {
+        let join = SymmetricHashOuterJoin::<_, _, _, LeftOuter>::build(left(), right(), EquiJoin::new(|&l: &i32| l, |&r: &i32| r).left_outer(), (), 1000);
+        let mut results: Vec<_> = block_on(join.try_collect()).unwrap();
+        results.sort_unstable();
+        let mut expected: Vec<_> = (0..5).map(|l| (l, None)).chain(matches().map(|x| (x, Some(x)))).collect();
+        expected.sort_unstable();
+        assert_eq!(expected, results);
+
+        let join = SymmetricHashOuterJoin::<_, _, _, RightOuter>::build(left(), right(), EquiJoin::new(|&l: &i32| l, |&r: &i32| r).right_outer(), (), 1000);
+        let mut results: Vec<_> = block_on(join.try_collect()).unwrap();
+        results.sort_unstable();
+        let mut expected: Vec<_> = matches().map(|x| (Some(x), x)).chain((10..15).map(|r| (None, r))).collect();
+        expected.sort_unstable();
+        assert_eq!(expected, results);
+
+        let join = SymmetricHashOuterJoin::<_, _, _, FullOuter>::build(left(), right(), EquiJoin::new(|&l: &i32| l, |&r: &i32| r).full_outer(), (), 1000);
+        let mut results: Vec<_> = block_on(join.try_collect()).unwrap();
+        results.sort_unstable();
+        let mut expected: Vec<_> = (0..5).map(|l| (Some(l), None))
+            .chain(matches().map(|x| (Some(x), Some(x))))
+            .chain((10..15).map(|r| (None, Some(r))))
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(expected, results);
+    }
+}
@@ -1,45 +1,76 @@
-use std::mem;
-use std::rc::Rc;
+use std::{iter, mem, vec};
 use std::collections::VecDeque;
-use futures::{Stream, Poll, Async, stream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Future, Stream, TryStream, StreamExt, TryStreamExt, ready, stream};
 use named_type::NamedType;
 use named_type_derive::*;
+use pin_project::pin_project;
+use either::Either;
 use itertools::{Itertools, MinMaxResult};
 use multimap::MultiMap;
-use crate::InnerJoinPredicate;
+use crate::{HeapSize, InnerJoinPredicate, MemoryPool, MemoryReservation};
 
-use super::{Join, ExternalStorage, External};
-use crate::predicate::HashPredicate;
+use super::{Join, JoinError, AsyncExternal, AsyncExternalStorage, Storage};
+use crate::predicate::{HashPredicate, JoinPredicate};
 
+/// Configuration of an `XJoin`.
+#[derive(Clone, Debug)]
+pub struct XJoinConfig {
+    /// Maximum size in bytes of the tuples (of both inputs) that are held in memory at once.
+    pub memory_limit: usize,
+    /// Number of partitions per input.
+    pub num_partitions: usize,
+    /// Optional pool that in-memory partitions are reserved from.
+    ///
+    /// If the pool runs dry or another join asks for memory back, the largest partition is evicted.
+    pub pool: Option<MemoryPool>,
+}
+
+#[pin_project]
 #[derive(NamedType)]
-pub enum XJoin<L, R, D, E>
+pub struct XJoin<L, R, D, E>
+where
+    L: TryStream,
+    R: TryStream<Error=L::Error>,
+    D: HashPredicate<Left=L::Ok, Right=R::Ok> + InnerJoinPredicate,
+    E: AsyncExternalStorage<Timestamped<L::Ok>> + AsyncExternalStorage<Timestamped<R::Ok>>
+{
+    #[pin]
+    left: stream::Fuse<stream::IntoStream<L>>,
+    #[pin]
+    right: stream::Fuse<stream::IntoStream<R>>,
+    state: State<D, E>,
+}
+
+enum State<D, E>
 where
-    L: Stream,
-    R: Stream<Error=L::Error>,
-    D: HashPredicate<Left=L::Item, Right=R::Item> + InnerJoinPredicate,
-    E: ExternalStorage<Timestamped<L::Item>> + ExternalStorage<Timestamped<R::Item>>
+    D: HashPredicate + InnerJoinPredicate,
+    E: AsyncExternalStorage<Timestamped<D::Left>> + AsyncExternalStorage<Timestamped<D::Right>>
 {
-    MainPhase(MainPhase<L, R, D, E>),
-    CleanupPhase(CleanupPhase<L, R, D, E>),
+    MainPhase(MainPhase<D, E>),
+    CleanupPhase(Box<CleanupPhase<D, E>>),
     Tmp,
 }
 
-pub struct MainPhase<L, R, D, E>
+pub struct MainPhase<D, E>
 where
-    L: Stream,
-    R: Stream<Error=L::Error>,
-    D: HashPredicate<Left=L::Item, Right=R::Item> + InnerJoinPredicate,
-    E: ExternalStorage<Timestamped<L::Item>> + ExternalStorage<Timestamped<R::Item>>
+    D: HashPredicate + InnerJoinPredicate,
+    E: AsyncExternalStorage<Timestamped<D::Left>> + AsyncExternalStorage<Timestamped<D::Right>>
 {
     definition: D,
     storage: E,
-    left: stream::Fuse<L>,
-    right: stream::Fuse<R>,
-    partitions_left: Vec<Partition<L::Item, E>>,
-    partitions_right: Vec<Partition<R::Item, E>>,
+    partitions_left: Vec<Partition<D::Left, E>>,
+    partitions_right: Vec<Partition<D::Right, E>>,
     stage2_cursor: usize,
-    overflow_memory: usize,
+    // stage 2 probes since the last input tuple
+    stage2_runs: usize,
+    // the stage 2 probe in progress, on a left or right partition
+    stage2: Option<Stage2Probe<D, E>>,
+    // bytes held by all in-memory partitions
+    inmemory_size: usize,
     memory_limit: usize,
+    reservation: Option<MemoryReservation>,
     timer: u64,
     output_buffer: VecDeque<D::Output>,
 }
@@ -51,247 +82,472 @@ pub struct Timestamped<T> {
     item: T,
 }
 
-struct Partition<T, E: ExternalStorage<Timestamped<T>>> {
+struct Partition<T, E: AsyncExternalStorage<Timestamped<T>>> {
     in_memory: Vec<(u64, T)>,
+    // bytes held by `in_memory`
+    size: usize,
     on_disk: Vec<E::External>,
+    // evicted runs on their way to `on_disk`
+    stores: VecDeque<Pin<Box<E::Store>>>,
     stage2_joins: Vec<(u64, u64)>,
 }
 // why can't derive figure this out?  :(
-impl<T, E: ExternalStorage<Timestamped<T>>> Default for Partition<T, E> {
+impl<T, E: AsyncExternalStorage<Timestamped<T>>> Default for Partition<T, E> {
     fn default() -> Self {
-        Partition { in_memory: Vec::new(), on_disk: Vec::new(), stage2_joins: Vec::new() }
+        Partition { in_memory: Vec::new(), size: 0, on_disk: Vec::new(), stores: VecDeque::new(), stage2_joins: Vec::new() }
     }
 }
-impl<T, E: ExternalStorage<Timestamped<T>>> Partition<T, E> {
+impl<T, E: AsyncExternalStorage<Timestamped<T>>> Partition<T, E> {
     fn evict(&mut self, storage: &mut E, t_out: u64, mem: &mut usize) {
-        *mem -= self.in_memory.len() - 1;
-        self.on_disk.push(storage.store(mem::replace(&mut self.in_memory, Vec::new()).into_iter().map(|(t_in, item)| Timestamped { t_in, t_out, item }).collect()));
+        *mem -= mem::take(&mut self.size);
+        self.stores.push_back(Box::pin(storage.store(mem::take(&mut self.in_memory).into_iter().map(|(t_in, item)| Timestamped { t_in, t_out, item }).collect())));
+    }
+
+    fn poll_stores(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), E::Error>> {
+        while let Some(store) = self.stores.front_mut() {
+            self.on_disk.push(ready!(store.as_mut().poll(cx))?);
+            self.stores.pop_front();
+        }
+        Poll::Ready(Ok(()))
     }
 }
 
-fn manage_side<T, U, O, E: ExternalStorage<Timestamped<T>> + ExternalStorage<Timestamped<U>>, F: FnOnce(&T) -> u64, G: Fn(&T, &U) -> Option<O>>(
-        v: Async<Option<T>>,
-        insert_partitions: &mut Vec<Partition<T, E>>,
-        probe_partitions: &Vec<Partition<U, E>>,
+/// Reads runs one after the other.
+struct RunScan<T, X: AsyncExternal<T>> {
+    next: usize,
+    fetch: Option<Pin<Box<X::Fetch>>>,
+}
+impl<T, X: AsyncExternal<T>> RunScan<T, X> {
+    fn new() -> Self {
+        RunScan { next: 0, fetch: None }
+    }
+
+    fn poll_next(&mut self, runs: &[X], cx: &mut Context<'_>) -> Poll<Option<Result<T, X::Error>>> {
+        loop {
+            if let Some(fetch) = &mut self.fetch {
+                if let Some(next) = ready!(fetch.as_mut().poll_next(cx)) {
+                    return Poll::Ready(Some(next));
+                }
+            }
+            // close the previous run before opening the next one
+            self.fetch = None;
+            match runs.get(self.next) {
+                Some(run) => self.fetch = Some(Box::pin(run.fetch())),
+                None => return Poll::Ready(None),
+            }
+            self.next += 1;
+        }
+    }
+}
+
+type Scan<T, E> = RunScan<Timestamped<T>, <E as AsyncExternalStorage<Timestamped<T>>>::External>;
+type Partitions<T, E> = vec::IntoIter<Partition<T, E>>;
+type Stage2Probe<D, E> = Either<Stage2<<D as JoinPredicate>::Left, E>, Stage2<<D as JoinPredicate>::Right, E>>;
+
+/// A stage 2 probe, reading the disk-resident tuples of a partition.
+struct Stage2<T, E: AsyncExternalStorage<Timestamped<T>>> {
+    partition: usize,
+    scan: Scan<T, E>,
+    // the timer when the probe started
+    timer: u64,
+    t_last: Option<u64>,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn manage_side<T: HeapSize, U, O, E: AsyncExternalStorage<Timestamped<T>> + AsyncExternalStorage<Timestamped<U>>, F: FnOnce(&T) -> u64, G: Fn(&T, &U) -> Option<O>>(
+        v: Poll<Option<T>>,
+        insert_partitions: &mut [Partition<T, E>],
+        probe_partitions: &[Partition<U, E>],
         output_buffer: &mut VecDeque<O>,
-        overflow_memory: &mut usize,
+        inmemory_size: &mut usize,
         timer: u64,
         hasher: F,
         joiner: G) {
-    if let Async::Ready(Some(v)) = v {
+    if let Poll::Ready(Some(v)) = v {
         let hash = hasher(&v);
         let hash = (hash % (insert_partitions.len() as u64)) as usize;
         let partition = &mut insert_partitions[hash];
-        if !partition.in_memory.is_empty() {
-            *overflow_memory += 1;
-        }
         output_buffer.extend(probe_partitions[hash].in_memory.iter().filter_map(|(_, c)| joiner(&v, c)));
-        partition.in_memory.push((timer, v));
+        let entry = (timer, v);
+        let size = entry.memory_size();
+        partition.size += size;
+        *inmemory_size += size;
+        partition.in_memory.push(entry);
     }
 }
-fn stage2<T, U, O, E: ExternalStorage<Timestamped<T>> + ExternalStorage<Timestamped<U>>, F: Fn(&T, &U) -> Option<O>>(
+/// Probes the in-memory tuples of `probe_partition` with the next disk-resident tuples of
+/// `disk_partition` until there is output. Returns whether the probe is done.
+fn poll_stage2<T, U, O, E: AsyncExternalStorage<Timestamped<T>> + AsyncExternalStorage<Timestamped<U>>, F: Fn(&T, &U) -> Option<O>>(
+        probe: &mut Stage2<T, E>,
         disk_partition: &mut Partition<T, E>,
         probe_partition: &Partition<U, E>,
         output_buffer: &mut VecDeque<O>,
-        timer: u64,
-        joiner: F) {
-    if probe_partition.in_memory.is_empty() || disk_partition.on_disk.is_empty() {
-        return;
-    }
-
+        joiner: F,
+        cx: &mut Context<'_>) -> Poll<Result<bool, <E as Storage>::Error>> {
     // TODO: perhaps use a hashtable in here, paper is unclear
-    let mut t_last = None;
-    for x in disk_partition.on_disk.iter().flat_map(|x| x.fetch()) {
+    while output_buffer.is_empty() {
+        let x = match ready!(probe.scan.poll_next(&disk_partition.on_disk, cx)) {
+            Some(x) => x?,
+            None => {
+                if let Some(t_last) = probe.t_last {
+                    disk_partition.stage2_joins.push((t_last, probe.timer));
+                }
+                return Poll::Ready(Ok(true));
+            }
+        };
         for y in &probe_partition.in_memory {
             let probed_before = disk_partition.stage2_joins.iter().filter(|&&(tl, _)| tl >= x.t_out).any(|&(_, ts)| y.0 < ts);
             if x.t_out <= y.0 && !probed_before {
-                output_buffer.extend(joiner(&x.item, &y.1).map(|yy| { /*println!("success for {},{} {:?} = {} {:?} ({:?})", x.t_in, x.t_out, x.item.debug(), y.0, y.1.debug(), disk_partition.stage2_joins);*/ yy}));
+                output_buffer.extend(joiner(&x.item, &y.1));
             }
         }
-        t_last = Some(x.t_out);
-    }
-    if let Some(t_last) = t_last {
-        disk_partition.stage2_joins.push((t_last, timer));
+        probe.t_last = Some(x.t_out);
     }
+    Poll::Ready(Ok(false))
 }
-impl<L, R, D, E> MainPhase<L, R, D, E>
+impl<D, E> MainPhase<D, E>
 where
-    L: Stream,
-    R: Stream<Error=L::Error>,
-    D: HashPredicate<Left=L::Item, Right=R::Item> + InnerJoinPredicate,
-    E: ExternalStorage<Timestamped<L::Item>> + ExternalStorage<Timestamped<R::Item>>
+    D: HashPredicate + InnerJoinPredicate,
+    E: AsyncExternalStorage<Timestamped<D::Left>> + AsyncExternalStorage<Timestamped<D::Right>>
 {
+    fn poll_stores(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), <E as Storage>::Error>> {
+        for partition in &mut self.partitions_left {
+            ready!(partition.poll_stores(cx))?;
+        }
+        for partition in &mut self.partitions_right {
+            ready!(partition.poll_stores(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Starts a stage 2 probe of the next partition, unless there is nothing to probe.
+    fn start_stage2(&mut self) {
+        let num_partitions = self.partitions_left.len();
+        let partition = self.stage2_cursor % (num_partitions * 2);
+        self.stage2_cursor += 1;
+        self.stage2_runs += 1;
+        let partnum = partition % num_partitions;
+        let (left, right) = (&self.partitions_left[partnum], &self.partitions_right[partnum]);
+        self.stage2 = if partition < num_partitions {
+            if right.in_memory.is_empty() || left.on_disk.is_empty() {
+                return;
+            }
+            Some(Either::Left(Stage2 { partition: partnum, scan: RunScan::new(), timer: self.timer, t_last: None }))
+        } else {
+            if left.in_memory.is_empty() || right.on_disk.is_empty() {
+                return;
+            }
+            Some(Either::Right(Stage2 { partition: partnum, scan: RunScan::new(), timer: self.timer, t_last: None }))
+        };
+    }
+
+    fn poll_stage2(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), <E as Storage>::Error>> {
+        let definition = &self.definition;
+        let done = match &mut self.stage2 {
+            Some(Either::Left(probe)) => {
+                let partnum = probe.partition;
+                poll_stage2(probe, &mut self.partitions_left[partnum], &self.partitions_right[partnum], &mut self.output_buffer, |x, y| definition.eq(x, y), cx)
+            }
+            Some(Either::Right(probe)) => {
+                let partnum = probe.partition;
+                poll_stage2(probe, &mut self.partitions_right[partnum], &self.partitions_left[partnum], &mut self.output_buffer, |y, x| definition.eq(x, y), cx)
+            }
+            None => return Poll::Ready(Ok(())),
+        };
+        match ready!(done) {
+            Ok(false) => (),
+            Ok(true) => self.stage2 = None,
+            Err(e) => {
+                self.stage2 = None;
+                return Poll::Ready(Err(e));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
     fn manage_eviction(&mut self) {
         self.timer += 1; // TODO: is this necessary?
         
-        if self.overflow_memory >= self.memory_limit {
+        // the pool may deny more memory or want some back
+        let granted = match &mut self.reservation {
+            Some(r) => !r.shrink_requested() && r.try_resize(self.inmemory_size),
+            None => true,
+        };
+        if self.inmemory_size >= self.memory_limit || (!granted && self.inmemory_size > 0) {
             //println!("== evicting ({}/{}) ==", self.memory_limit, self.partitions_left.len());
             // evict largest partition (no matter if left or right)
-            let largest_left = match self.partitions_left.iter_mut().minmax_by_key(|p| p.in_memory.len()) {
+            let largest_left = match self.partitions_left.iter_mut().minmax_by_key(|p| p.size) {
                 MinMaxResult::NoElements => unreachable!(),
                 MinMaxResult::OneElement(x) | MinMaxResult::MinMax(_, x) => x,
             };
-            let largest_right = match self.partitions_right.iter_mut().minmax_by_key(|p| p.in_memory.len()) {
+            let largest_right = match self.partitions_right.iter_mut().minmax_by_key(|p| p.size) {
                 MinMaxResult::NoElements => unreachable!(),
                 MinMaxResult::OneElement(x) | MinMaxResult::MinMax(_, x) => x,
             };
-            if largest_left.in_memory.len() > largest_right.in_memory.len() {
-                largest_left.evict(&mut self.storage, self.timer, &mut self.overflow_memory);
+            if largest_left.size > largest_right.size {
+                largest_left.evict(&mut self.storage, self.timer, &mut self.inmemory_size);
             } else {
-                largest_right.evict(&mut self.storage, self.timer, &mut self.overflow_memory);
+                largest_right.evict(&mut self.storage, self.timer, &mut self.inmemory_size);
             }
+            if let Some(r) = &mut self.reservation {
+                r.shrink_to(self.inmemory_size.min(r.size()));
+            }
+        }
+    }
+
+    fn switch_to_cleanup(self) -> CleanupPhase<D, E> {
+        CleanupPhase {
+            t_out: self.timer + 1,
+            partitions: self.partitions_left.into_iter().zip(self.partitions_right),
+            current: None,
+            definition: self.definition,
+            output_buffer: self.output_buffer,
         }
     }
+}
+
+/// Joins the partitions one after the other, hashing the left tuples of a partition and
+/// probing with the right ones.
+pub struct CleanupPhase<D, E>
+where
+    D: HashPredicate + InnerJoinPredicate,
+    E: AsyncExternalStorage<Timestamped<D::Left>> + AsyncExternalStorage<Timestamped<D::Right>>
+{
+    definition: D,
+    t_out: u64,
+    partitions: iter::Zip<Partitions<D::Left, E>, Partitions<D::Right, E>>,
+    current: Option<CleanupPartition<D, E>>,
+    output_buffer: VecDeque<D::Output>,
+}
+
+struct CleanupPartition<D, E>
+where
+    D: HashPredicate + InnerJoinPredicate,
+    E: AsyncExternalStorage<Timestamped<D::Left>> + AsyncExternalStorage<Timestamped<D::Right>>
+{
+    left: Partition<D::Left, E>,
+    right: Partition<D::Right, E>,
+    table: MultiMap<u64, Timestamped<D::Left>>,
+    scan_left: Scan<D::Left, E>,
+    scan_right: Scan<D::Right, E>,
+    // whether all left tuples are in `table`
+    built: bool,
+}
+
+impl<D, E> CleanupPartition<D, E>
+where
+    D: HashPredicate + InnerJoinPredicate,
+    E: AsyncExternalStorage<Timestamped<D::Left>> + AsyncExternalStorage<Timestamped<D::Right>>
+{
+    fn new(mut left: Partition<D::Left, E>, right: Partition<D::Right, E>, definition: &D, t_out: u64) -> Self {
+        let table = mem::take(&mut left.in_memory).into_iter()
+            .map(|(t_in, item)| (definition.hash_left(&item), Timestamped { t_in, t_out, item }))
+            .collect();
+        CleanupPartition { left, right, table, scan_left: RunScan::new(), scan_right: RunScan::new(), built: false }
+    }
+
+    fn probe(&self, r: &Timestamped<D::Right>, definition: &D, output_buffer: &mut VecDeque<D::Output>) {
+        let (ls2, rs2) = (&self.left.stage2_joins, &self.right.stage2_joins);
+        let hash = definition.hash_right(&r.item);
+        output_buffer.extend(self.table.get_vec(&hash).into_iter().flat_map(|l| l.iter()).filter_map(|l| {
+            // did we join these already?
+            if (l.t_in <= r.t_out) && (l.t_out > r.t_in) {
+                //println!("rejecting from memory {:?} ({}, {}) {:?} ({}, {})", l.item.debug(), l.t_in, l.t_out, r.item.debug(), r.t_in, r.t_out);
+                return None;
+            }
+            let left_probed_before = ls2.iter().filter(|&&(tl, _)| tl >= l.t_out).any(|&(_, ts)| r.t_in < ts && r.t_out >= ts);
+            let right_probed_before = rs2.iter().filter(|&&(tl, _)| tl >= r.t_out).any(|&(_, ts)| l.t_in < ts && l.t_out >= ts);
+            if left_probed_before || right_probed_before {
+                //println!("rejecting from PROBED BEFORE {:?} ({}, {}) {:?} ({}, {}) ;; {} {} {:?} {:?}", l.item.debug(), l.t_in, l.t_out, r.item.debug(), r.t_in, r.t_out, left_probed_before, right_probed_before, ls2, rs2);
+                return None;
+            }
 
-    fn switch_to_cleanup(self) -> CleanupPhase<L, R, D, E> {
-        let t_out = self.timer + 1;
-        let definition = Rc::new(self.definition);
-        self.partitions_left.into_iter().zip(self.partitions_right).flat_map(move |(l, r)| {
-            let ls2 = l.stage2_joins;
-            let rs2 = r.stage2_joins;
-            let left = l.in_memory.into_iter().map(move |(t_in, item)| Timestamped { t_in, t_out, item })
-                .chain(l.on_disk.into_iter().flat_map(|x| x.fetch()));
-            let right = r.in_memory.into_iter().map(move |(t_in, item)| Timestamped { t_in, t_out, item })
-                .chain(r.on_disk.into_iter().flat_map(|x| x.fetch()));
-                
-            let table: MultiMap<_, Timestamped<L::Item>> = left.map(|x| (definition.hash_left(&x.item), x)).collect();
-            let definition = Rc::clone(&definition);
-            right.flat_map(move |r| {
-                let hash = definition.hash_right(&r.item);
-                table.get_vec(&hash).into_iter().flat_map(|l| l.iter()).flat_map(|l| {
-                    // did we join these already?
-                    if (l.t_in <= r.t_out) && (l.t_out > r.t_in) {
-                        //println!("rejecting from memory {:?} ({}, {}) {:?} ({}, {})", l.item.debug(), l.t_in, l.t_out, r.item.debug(), r.t_in, r.t_out);
-                        return None;
+            definition.eq(&l.item, &r.item)
+        }));
+    }
+}
+
+impl<D, E> CleanupPhase<D, E>
+where
+    D: HashPredicate + InnerJoinPredicate,
+    E: AsyncExternalStorage<Timestamped<D::Left>> + AsyncExternalStorage<Timestamped<D::Right>>
+{
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<D::Output, <E as Storage>::Error>>> {
+        loop {
+            if let Some(buffered) = self.output_buffer.pop_front() {
+                return Poll::Ready(Some(Ok(buffered)));
+            }
+            let p = match &mut self.current {
+                Some(p) => p,
+                None => match self.partitions.next() {
+                    Some((left, right)) => {
+                        self.current = Some(CleanupPartition::new(left, right, &self.definition, self.t_out));
+                        continue;
                     }
-                    let left_probed_before = ls2.iter().filter(|&&(tl, _)| tl >= l.t_out).any(|&(_, ts)| r.t_in < ts && r.t_out >= ts);
-                    let right_probed_before = rs2.iter().filter(|&&(tl, _)| tl >= r.t_out).any(|&(_, ts)| l.t_in < ts && l.t_out >= ts);
-                    if left_probed_before || right_probed_before {
-                        //println!("rejecting from PROBED BEFORE {:?} ({}, {}) {:?} ({}, {}) ;; {} {} {:?} {:?}", l.item.debug(), l.t_in, l.t_out, r.item.debug(), r.t_in, r.t_out, left_probed_before, right_probed_before, ls2, rs2);
-                        return None;
+                    None => return Poll::Ready(None),
+                },
+            };
+            if !p.built {
+                // build the table from all left tuples first
+                match ready!(p.scan_left.poll_next(&p.left.on_disk, cx)) {
+                    Some(Ok(l)) => p.table.insert(self.definition.hash_left(&l.item), l),
+                    Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                    None => {
+                        p.built = true;
+                        let t_out = self.t_out;
+                        for (t_in, item) in mem::take(&mut p.right.in_memory) {
+                            p.probe(&Timestamped { t_in, t_out, item }, &self.definition, &mut self.output_buffer);
+                        }
                     }
-                    
-                    definition.eq(&l.item, &r.item)
-                }).collect::<Vec<_>>().into_iter()
-            })
-        })
+                }
+            } else {
+                match ready!(p.scan_right.poll_next(&p.right.on_disk, cx)) {
+                    Some(Ok(r)) => p.probe(&r, &self.definition, &mut self.output_buffer),
+                    Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                    None => self.current = None,
+                }
+            }
+        }
     }
 }
 
-type CleanupPhase<
-    L: Stream,
-    R: Stream<Error=L::Error>,
-    D: HashPredicate<Left=L::Item, Right=R::Item> + InnerJoinPredicate,
-    E: ExternalStorage<Timestamped<L::Item>> + ExternalStorage<Timestamped<R::Item>>,
-> = impl Iterator<Item=D::Output>;
-
 impl<L, R, D, E> Stream for XJoin<L, R, D, E>
 where
-    L: Stream,
-    R: Stream<Error=L::Error>,
-    D: HashPredicate<Left=L::Item, Right=R::Item> + InnerJoinPredicate,
-    E: ExternalStorage<Timestamped<L::Item>> + ExternalStorage<Timestamped<R::Item>>
+    L: TryStream,
+    L::Ok: HeapSize,
+    R: TryStream<Error=L::Error>,
+    R::Ok: HeapSize,
+    D: HashPredicate<Left=L::Ok, Right=R::Ok> + InnerJoinPredicate,
+    E: AsyncExternalStorage<Timestamped<L::Ok>> + AsyncExternalStorage<Timestamped<R::Ok>>
 {
-    type Item = D::Output;
-    type Error = L::Error;
+    type Item = Result<D::Output, JoinError<L::Error, <E as Storage>::Error>>;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let mut stage2_runs = 0;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
         loop {
-            match self {
-                XJoin::MainPhase(this) => {
-                    this.timer += 1;
+            match this.state {
+                State::MainPhase(mp) => {
+                    mp.timer += 1;
 
                     // carry-over buffer
-                    if let Some(buffered) = this.output_buffer.pop_front() {
+                    if let Some(buffered) = mp.output_buffer.pop_front() {
                         //println!("output {:?}", buffered.debug());
-                        return Ok(Async::Ready(Some(buffered)));
+                        return Poll::Ready(Some(Ok(buffered)));
+                    }
+                    // inputs wait for evicted runs to be stored and for stage 2 probes to finish
+                    ready!(mp.poll_stores(cx)).map_err(JoinError::Storage)?;
+                    if mp.stage2.is_some() {
+                        ready!(mp.poll_stage2(cx)).map_err(JoinError::Storage)?;
+                        continue;
                     }
 
-                    match (this.left.poll()?, this.right.poll()?) {
-                        (Async::Ready(None), Async::Ready(None)) => {
+                    match (this.left.as_mut().try_poll_next(cx)?, this.right.as_mut().try_poll_next(cx)?) {
+                        (Poll::Ready(None), Poll::Ready(None)) => {
                             // cleanup phase
                             // fall through to switch to cleanup phase
                             //println!("switching to cleanup");
                         }
-                        (Async::NotReady, Async::NotReady)
-                            | (Async::Ready(None), Async::NotReady)
-                            | (Async::NotReady, Async::Ready(None)) => {
+                        (Poll::Pending, Poll::Pending)
+                            | (Poll::Ready(None), Poll::Pending)
+                            | (Poll::Pending, Poll::Ready(None)) => {
                             // both inputs blocked: phase 2
-                            let num_partitions = this.partitions_left.len() * 2;
-                            if stage2_runs >= num_partitions {
+                            if mp.stage2_runs >= mp.partitions_left.len() * 2 {
                                 //println!("processed all disk partitions, still no data");
-                                return Ok(Async::NotReady);
-                            } else {
-                                let partition = this.stage2_cursor % num_partitions;
-                                let definition = &this.definition;
-                                let partnum = partition % (num_partitions / 2);
-                                if partition < (num_partitions / 2) {
-                                    stage2(&mut this.partitions_left[partnum], &this.partitions_right[partnum], &mut this.output_buffer, this.timer, |x, y| definition.eq(x, y));
-                                } else {
-                                    stage2(&mut this.partitions_right[partnum], &this.partitions_left[partnum], &mut this.output_buffer, this.timer, |y, x| definition.eq(x, y));
-                                }
-
-                                this.stage2_cursor += 1;
-                                stage2_runs += 1;
-                                continue;
+                                return Poll::Pending;
                             }
+                            mp.start_stage2();
+                            continue;
                         }
                         (l, r) => {
-                            stage2_runs = 0;
+                            mp.stage2_runs = 0;
                             // input ready: phase 1
                             {
-                                let definition = &this.definition;
-                                manage_side(l, &mut this.partitions_left, &this.partitions_right, &mut this.output_buffer, &mut this.overflow_memory, this.timer, |x| definition.hash_left(x), |x, y| definition.eq(x, y));
+                                let definition = &mp.definition;
+                                manage_side(l, &mut mp.partitions_left, &mp.partitions_right, &mut mp.output_buffer, &mut mp.inmemory_size, mp.timer, |x| definition.hash_left(x), |x, y| definition.eq(x, y));
                             }
-                            this.manage_eviction();
+                            mp.manage_eviction();
                             {
-                                let definition = &this.definition;
-                                manage_side(r, &mut this.partitions_right, &this.partitions_left, &mut this.output_buffer, &mut this.overflow_memory, this.timer, |x| definition.hash_right(x), |y, x| definition.eq(x, y));
+                                let definition = &mp.definition;
+                                manage_side(r, &mut mp.partitions_right, &mp.partitions_left, &mut mp.output_buffer, &mut mp.inmemory_size, mp.timer, |x| definition.hash_right(x), |y, x| definition.eq(x, y));
                             }
-                            this.manage_eviction();
+                            mp.manage_eviction();
                             continue;
                         }
                     }
                 }
-                XJoin::CleanupPhase(cp) => return Ok(Async::Ready(cp.next())),
-                XJoin::Tmp => unreachable!(),
+                State::CleanupPhase(cp) => return cp.poll_next(cx).map_err(JoinError::Storage),
+                State::Tmp => unreachable!(),
             }
             
-            *self = match mem::replace(self, XJoin::Tmp) {
-                XJoin::MainPhase(mp) => XJoin::CleanupPhase(mp.switch_to_cleanup()),
+            *this.state = match mem::replace(this.state, State::Tmp) {
+                State::MainPhase(mp) => State::CleanupPhase(Box::new(mp.switch_to_cleanup())),
                 _ => unreachable!(),
             }
         }
     }
 }
-impl<L, R, D, E> Join<L, R, D, E, usize> for XJoin<L, R, D, E>
+impl<L, R, D, E> Join<L, R, D, E, XJoinConfig> for XJoin<L, R, D, E>
 where
-    L: Stream,
-    R: Stream<Error=L::Error>,
-    D: HashPredicate<Left=L::Item, Right=R::Item> + InnerJoinPredicate,
-    E: ExternalStorage<Timestamped<L::Item>> + ExternalStorage<Timestamped<R::Item>>
+    L: TryStream,
+    L::Ok: HeapSize,
+    R: TryStream<Error=L::Error>,
+    R::Ok: HeapSize,
+    D: HashPredicate<Left=L::Ok, Right=R::Ok> + InnerJoinPredicate,
+    E: AsyncExternalStorage<Timestamped<L::Ok>> + AsyncExternalStorage<Timestamped<R::Ok>>
 {
-    fn build(left: L, right: R, definition: D, storage: E, memory_limit: usize) -> Self {
-        assert!(memory_limit >= 3);
-        let num_partitions = memory_limit / 3; // TODO: is this good?
+    fn build(left: L, right: R, definition: D, storage: E, config: XJoinConfig) -> Self {
+        assert!(config.num_partitions > 0);
+        assert!(config.memory_limit > 0);
         let mut partitions_left = Vec::new();
         let mut partitions_right = Vec::new();
-        partitions_left.resize_with(num_partitions, Default::default);
-        partitions_right.resize_with(num_partitions, Default::default);
-        XJoin::MainPhase(MainPhase {
-            definition,
-            storage,
-            left: left.fuse(),
-            right: right.fuse(),
-            partitions_left,
-            partitions_right,
-            output_buffer: VecDeque::new(),
-            memory_limit: memory_limit - num_partitions * 2, // = num_partitions + division remainder
-            overflow_memory: 0,
-            timer: 0,
-            stage2_cursor: 0,
-        })
+        partitions_left.resize_with(config.num_partitions, Default::default);
+        partitions_right.resize_with(config.num_partitions, Default::default);
+        XJoin {
+            left: left.into_stream().fuse(),
+            right: right.into_stream().fuse(),
+            state: State::MainPhase(MainPhase {
+                definition,
+                storage,
+                partitions_left,
+                partitions_right,
+                output_buffer: VecDeque::new(),
+                memory_limit: config.memory_limit,
+                reservation: config.pool.as_ref().map(MemoryPool::reserve),
+                inmemory_size: 0,
+                timer: 0,
+                stage2_cursor: 0,
+                stage2_runs: 0,
+                stage2: None,
+            }),
+        }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::mem;
+    use futures::{StreamExt, TryStreamExt, stream};
+    use futures::executor::block_on;
+    use crate::{EquiJoin, Join, XJoin, XJoinConfig};
+    use crate::join::sort_merge::test::{SlowStorage, yield_now};
+
+    #[test]
+    fn xjoin_async_storage() {
+        // both inputs stall before every tuple, so stage 2 probes run in between
+        let slow = |input: Vec<i32>| stream::iter(input).then(|x| async move {
+            yield_now().await;
+            Ok::<_, Infallible>(x)
+        });
+        let join = XJoin::build(
+            slow((0..400).map(|x| x % 200).collect()),
+            slow((100..300).collect()),
+            EquiJoin::new(|&l: &i32| l, |&r: &i32| r),
+            SlowStorage,
+            XJoinConfig { memory_limit: 64 * mem::size_of::<i32>(), num_partitions: 4, pool: None },
+        );
+        let mut results: Vec<_> = block_on(join.try_collect()).unwrap();
+        results.sort_unstable();
+        assert_eq!((100..200).flat_map(|x| [(x, x), (x, x)]).collect::<Vec<_>>(), results);
+    }
+}
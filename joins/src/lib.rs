@@ -1,11 +1,27 @@
 #![feature(type_alias_impl_trait)]
 #![deny(unsafe_code)]
 
+extern crate self as joins;
+
 pub mod predicate;
 pub mod join;
-mod value_skimmer;
 mod in_memory;
+mod heap_size;
+mod memory_pool;
+#[cfg(feature = "compat")]
+pub mod compat;
+#[cfg(feature = "file-storage")]
+pub mod file_storage;
+#[cfg(feature = "tokio-storage")]
+pub mod tokio_storage;
 
 pub use join::*;
 pub use predicate::*;
 pub use in_memory::*;
+#[cfg(feature = "file-storage")]
+pub use file_storage::{FileStorage, FileRun};
+#[cfg(feature = "tokio-storage")]
+pub use tokio_storage::{TokioFileStorage, TokioFileRun};
+pub use heap_size::HeapSize;
+pub use memory_pool::{MemoryPool, MemoryReservation};
+pub use joins_derive::{HeapSize, JoinKey};
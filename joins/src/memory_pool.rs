@@ -0,0 +1,163 @@
+//! Memory shared between concurrently running joins.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A memory budget in bytes that is shared by several joins.
+///
+/// Every join reserves the memory it holds through its own `MemoryReservation`. A reservation
+/// that can't grow because the pool is exhausted asks the largest other reservation to shrink.
+/// Joins check `MemoryReservation::shrink_requested` and spill tuples once it is set.
+///
+/// Cloning a pool yields another handle to the same budget.
+///
+/// # Example
+///
+/// ```
+/// use joins::MemoryPool;
+///
+/// let pool = MemoryPool::new(100);
+/// let mut a = pool.reserve();
+/// let mut b = pool.reserve();
+/// assert!(a.try_resize(80));
+/// assert!(!b.try_resize(40));
+/// assert!(a.shrink_requested());
+///
+/// a.shrink_to(50);
+/// assert!(!a.shrink_requested());
+/// assert!(b.try_resize(40));
+/// assert_eq!(90, pool.used());
+/// ```
+#[derive(Clone)]
+pub struct MemoryPool {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    capacity: usize,
+    used: usize,
+    consumers: Vec<Option<Consumer>>,
+}
+
+struct Consumer {
+    size: usize,
+    shrink_requested: bool,
+}
+
+impl MemoryPool {
+    /// Creates a pool holding `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        MemoryPool { inner: Arc::new(Mutex::new(Inner { capacity, used: 0, consumers: Vec::new() })) }
+    }
+
+    /// Registers a new consumer, which initially holds no memory.
+    pub fn reserve(&self) -> MemoryReservation {
+        let mut inner = self.lock();
+        let consumer = Consumer { size: 0, shrink_requested: false };
+        let id = match inner.consumers.iter().position(Option::is_none) {
+            Some(id) => {
+                inner.consumers[id] = Some(consumer);
+                id
+            }
+            None => {
+                inner.consumers.push(Some(consumer));
+                inner.consumers.len() - 1
+            }
+        };
+        MemoryReservation { pool: self.clone(), id, size: 0 }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.lock().capacity
+    }
+
+    /// Bytes currently reserved by all consumers.
+    pub fn used(&self) -> usize {
+        self.lock().used
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // the pool's bookkeeping stays consistent even if a holder panicked
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for MemoryPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.lock();
+        f.debug_struct("MemoryPool").field("capacity", &inner.capacity).field("used", &inner.used).finish()
+    }
+}
+
+/// The share of a `MemoryPool` held by a single join.
+///
+/// The reserved memory is returned to the pool when the reservation is dropped.
+pub struct MemoryReservation {
+    pool: MemoryPool,
+    id: usize,
+    size: usize,
+}
+
+impl MemoryReservation {
+    /// Bytes currently reserved.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Tries to resize the reservation to `size` bytes.
+    ///
+    /// Shrinking always succeeds and clears a pending shrink request. Growing fails if the pool
+    /// doesn't have enough free memory, in which case the largest other consumer is asked to
+    /// shrink and the reservation is left unchanged.
+    #[must_use]
+    pub fn try_resize(&mut self, size: usize) -> bool {
+        let mut inner = self.pool.lock();
+        let inner = &mut *inner;
+        if size > self.size && inner.used - self.size + size > inner.capacity {
+            let id = self.id;
+            let victim = inner.consumers.iter_mut().enumerate()
+                .filter(|(i, _)| *i != id)
+                .filter_map(|(_, c)| c.as_mut())
+                .filter(|c| c.size > 0)
+                .max_by_key(|c| c.size);
+            if let Some(victim) = victim {
+                victim.shrink_requested = true;
+            }
+            return false;
+        }
+        inner.used = inner.used - self.size + size;
+        let consumer = inner.consumers[self.id].as_mut().unwrap();
+        consumer.size = size;
+        if size < self.size {
+            consumer.shrink_requested = false;
+        }
+        self.size = size;
+        true
+    }
+
+    /// Shrinks the reservation to `size` bytes, which must not exceed the current size.
+    pub fn shrink_to(&mut self, size: usize) {
+        assert!(size <= self.size);
+        let resized = self.try_resize(size);
+        debug_assert!(resized);
+    }
+
+    /// Whether another consumer asked this one to release memory.
+    pub fn shrink_requested(&self) -> bool {
+        self.pool.lock().consumers[self.id].as_ref().unwrap().shrink_requested
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        let mut inner = self.pool.lock();
+        inner.used -= self.size;
+        inner.consumers[self.id] = None;
+    }
+}
+
+impl fmt::Debug for MemoryReservation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryReservation").field("size", &self.size).finish()
+    }
+}
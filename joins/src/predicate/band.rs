@@ -0,0 +1,113 @@
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use num_traits::CheckedAdd;
+
+use super::*;
+
+/// A band (range) join.
+///
+/// Joins tuples whose keys lie within a band around each other: a left tuple matches a right
+/// tuple if `key_left - lower <= key_right <= key_left + upper`.
+/// It returns `(left, right)` for matching tuples.
+///
+/// # Example
+///
+/// ```
+/// use joins::BandJoin;
+/// #[derive(Clone, Debug)]
+/// struct Reading { ts: u64, value: f64 }
+///
+/// // |l.ts - r.ts| <= 5
+/// BandJoin::new(|l: &Reading| l.ts, |r: &Reading| r.ts, 5, 5);
+/// ```
+///
+/// The bounds are only ever added to keys, so unsigned keys work fine. A sum that overflows
+/// lies beyond every key. Both bounds must not be negative.
+/// Besides `InnerJoinPredicate`, this implements `MergePredicate` (ordering tuples by key)
+/// and `BandPredicate`, so it can be used with a `SweepBandJoin`.
+#[derive(Clone, Copy)]
+pub struct BandJoin<Left, Right, Key, GetKeyLeft, GetKeyRight>
+where GetKeyLeft: Fn(&Left) -> Key,
+      GetKeyRight: Fn(&Right) -> Key {
+    get_key_left: GetKeyLeft,
+    get_key_right: GetKeyRight,
+    lower: Key,
+    upper: Key,
+
+    _phantom: PhantomData<fn(&Left, &Right) -> Key>,
+}
+
+impl<Left, Right, Key, GetKeyLeft, GetKeyRight> BandJoin<Left, Right, Key, GetKeyLeft, GetKeyRight>
+where GetKeyLeft: Fn(&Left) -> Key,
+      GetKeyRight: Fn(&Right) -> Key,
+      Key: Ord + Copy + CheckedAdd,
+      Left: Clone,
+      Right: Clone {
+    pub fn new(get_key_left: GetKeyLeft, get_key_right: GetKeyRight, lower: Key, upper: Key) -> Self {
+        BandJoin { get_key_left, get_key_right, lower, upper, _phantom: PhantomData }
+    }
+}
+
+impl<Left, Right, Key, GetKeyLeft, GetKeyRight> JoinPredicate for BandJoin<Left, Right, Key, GetKeyLeft, GetKeyRight>
+where GetKeyLeft: Fn(&Left) -> Key,
+      GetKeyRight: Fn(&Right) -> Key,
+      Key: Ord + Copy + CheckedAdd,
+      Left: Clone,
+      Right: Clone {
+    type Left = Left;
+    type Right = Right;
+}
+impl<Left, Right, Key, GetKeyLeft, GetKeyRight> InnerJoinPredicate for BandJoin<Left, Right, Key, GetKeyLeft, GetKeyRight>
+where GetKeyLeft: Fn(&Left) -> Key,
+      GetKeyRight: Fn(&Right) -> Key,
+      Key: Ord + Copy + CheckedAdd,
+      Left: Clone,
+      Right: Clone {
+    type Output = (Left, Right);
+    fn eq(&self, left: &Self::Left, right: &Self::Right) -> Option<Self::Output> {
+        if self.below_band(left, right) || self.above_band(left, right) {
+            None
+        } else {
+            Some((left.clone(), right.clone()))
+        }
+    }
+}
+impl<Left, Right, Key, GetKeyLeft, GetKeyRight> OuterJoinPredicate for BandJoin<Left, Right, Key, GetKeyLeft, GetKeyRight>
+where GetKeyLeft: Fn(&Left) -> Key,
+      GetKeyRight: Fn(&Right) -> Key,
+      Key: Ord + Copy + CheckedAdd,
+      Left: Clone,
+      Right: Clone {
+    fn eq(&self, left: &Self::Left, right: &Self::Right) -> bool {
+        !self.below_band(left, right) && !self.above_band(left, right)
+    }
+}
+impl<Left, Right, Key, GetKeyLeft, GetKeyRight> MergePredicate for BandJoin<Left, Right, Key, GetKeyLeft, GetKeyRight>
+where GetKeyLeft: Fn(&Left) -> Key,
+      GetKeyRight: Fn(&Right) -> Key,
+      Key: Ord + Copy + CheckedAdd,
+      Left: Clone,
+      Right: Clone {
+    fn cmp(&self, left: &Self::Left, right: &Self::Right) -> Option<Ordering> {
+        Some((self.get_key_left)(left).cmp(&(self.get_key_right)(right)))
+    }
+    fn cmp_left(&self, a: &Self::Left, b: &Self::Left) -> Ordering {
+        (self.get_key_left)(a).cmp(&(self.get_key_left)(b))
+    }
+    fn cmp_right(&self, a: &Self::Right, b: &Self::Right) -> Ordering {
+        (self.get_key_right)(a).cmp(&(self.get_key_right)(b))
+    }
+}
+impl<Left, Right, Key, GetKeyLeft, GetKeyRight> BandPredicate for BandJoin<Left, Right, Key, GetKeyLeft, GetKeyRight>
+where GetKeyLeft: Fn(&Left) -> Key,
+      GetKeyRight: Fn(&Right) -> Key,
+      Key: Ord + Copy + CheckedAdd,
+      Left: Clone,
+      Right: Clone {
+    fn below_band(&self, left: &Self::Left, right: &Self::Right) -> bool {
+        (self.get_key_right)(right).checked_add(&self.lower).is_some_and(|r| r < (self.get_key_left)(left))
+    }
+    fn above_band(&self, left: &Self::Left, right: &Self::Right) -> bool {
+        (self.get_key_left)(left).checked_add(&self.upper).is_some_and(|l| (self.get_key_right)(right) > l)
+    }
+}
@@ -0,0 +1,216 @@
+use std::cmp::Ordering;
+use crate::{InnerJoinPredicate, OuterJoinPredicate, PadLeftPredicate, PadRightPredicate};
+
+use super::{JoinPredicate, MergePredicate, HashPredicate, BandPredicate, IntervalPredicate};
+
+/// Forwards all the optional predicate traits of the first component to a conjunction.
+///
+/// A conjunction only ever matches a subset of the pairs its first component matches,
+/// so hash partitioning and merge order of that component remain valid.
+macro_rules! conjunction {
+    ($name:ident<$p:ident, $q:ident> where $($bounds:tt)*) => {
+        impl<$p: JoinPredicate, $q> JoinPredicate for $name<$p, $q> where $($bounds)* {
+            type Left = $p::Left;
+            type Right = $p::Right;
+        }
+        impl<$p: InnerJoinPredicate, $q> InnerJoinPredicate for $name<$p, $q> where $($bounds)* {
+            type Output = $p::Output;
+
+            fn eq(&self, left: &Self::Left, right: &Self::Right) -> Option<Self::Output> {
+                self.predicate.eq(left, right).filter(|_| self.residual(left, right))
+            }
+        }
+        impl<$p: OuterJoinPredicate, $q> OuterJoinPredicate for $name<$p, $q> where $($bounds)* {
+            fn eq(&self, left: &Self::Left, right: &Self::Right) -> bool {
+                self.predicate.eq(left, right) && self.residual(left, right)
+            }
+            fn is_null_left(&self, left: &Self::Left) -> bool {
+                self.predicate.is_null_left(left)
+            }
+            fn is_null_right(&self, right: &Self::Right) -> bool {
+                self.predicate.is_null_right(right)
+            }
+        }
+        impl<$p: PadRightPredicate, $q> PadRightPredicate for $name<$p, $q> where $($bounds)* {
+            fn pad_right(&self, left: &Self::Left) -> Self::Output { self.predicate.pad_right(left) }
+        }
+        impl<$p: PadLeftPredicate, $q> PadLeftPredicate for $name<$p, $q> where $($bounds)* {
+            fn pad_left(&self, right: &Self::Right) -> Self::Output { self.predicate.pad_left(right) }
+        }
+        impl<$p: MergePredicate, $q> MergePredicate for $name<$p, $q> where $($bounds)* {
+            fn cmp(&self, left: &Self::Left, right: &Self::Right) -> Option<Ordering> { self.predicate.cmp(left, right) }
+            fn cmp_left(&self, a: &Self::Left, b: &Self::Left) -> Ordering { self.predicate.cmp_left(a, b) }
+            fn cmp_right(&self, a: &Self::Right, b: &Self::Right) -> Ordering { self.predicate.cmp_right(a, b) }
+        }
+        impl<$p: BandPredicate, $q> BandPredicate for $name<$p, $q> where $($bounds)* {
+            fn below_band(&self, left: &Self::Left, right: &Self::Right) -> bool { self.predicate.below_band(left, right) }
+            fn above_band(&self, left: &Self::Left, right: &Self::Right) -> bool { self.predicate.above_band(left, right) }
+        }
+        impl<$p: IntervalPredicate, $q> IntervalPredicate for $name<$p, $q> where $($bounds)* {
+            fn left_ends_before(&self, left: &Self::Left, right: &Self::Right) -> bool { self.predicate.left_ends_before(left, right) }
+            fn right_ends_before(&self, left: &Self::Left, right: &Self::Right) -> bool { self.predicate.right_ends_before(left, right) }
+        }
+        impl<$p: HashPredicate, $q> HashPredicate for $name<$p, $q> where $($bounds)* {
+            fn hash_left(&self, x: &Self::Left) -> u64 { self.predicate.hash_left(x) }
+            fn hash_right(&self, x: &Self::Right) -> u64 { self.predicate.hash_right(x) }
+            fn hash_left_seeded(&self, x: &Self::Left, seed: u64) -> u64 { self.predicate.hash_left_seeded(x, seed) }
+            fn hash_right_seeded(&self, x: &Self::Right, seed: u64) -> u64 { self.predicate.hash_right_seeded(x, seed) }
+        }
+    }
+}
+
+/// A predicate with an additional residual condition, see `JoinPredicate::filter`.
+#[derive(Clone, Copy)]
+pub struct FilterPredicate<P, F> {
+    predicate: P,
+    filter: F,
+}
+
+impl<P: JoinPredicate, F: Fn(&P::Left, &P::Right) -> bool> FilterPredicate<P, F> {
+    pub fn new(predicate: P, filter: F) -> Self {
+        FilterPredicate { predicate, filter }
+    }
+
+    fn residual(&self, left: &P::Left, right: &P::Right) -> bool {
+        (self.filter)(left, right)
+    }
+}
+
+conjunction!(FilterPredicate<P, F> where F: Fn(&P::Left, &P::Right) -> bool);
+
+/// The conjunction of two predicates, see `JoinPredicate::and`.
+#[derive(Clone, Copy)]
+pub struct AndPredicate<P, Q> {
+    predicate: P,
+    other: Q,
+}
+
+impl<P: JoinPredicate, Q: OuterJoinPredicate<Left=P::Left, Right=P::Right>> AndPredicate<P, Q> {
+    pub fn new(predicate: P, other: Q) -> Self {
+        AndPredicate { predicate, other }
+    }
+
+    fn residual(&self, left: &P::Left, right: &P::Right) -> bool {
+        self.other.eq(left, right)
+    }
+}
+
+conjunction!(AndPredicate<P, Q> where Q: OuterJoinPredicate<Left=P::Left, Right=P::Right>);
+
+/// The disjunction of two predicates, see `JoinPredicate::or`.
+///
+/// Neither hash values nor merge order carry over to a disjunction, so this can only be used
+/// with joins that compare all pairs of tuples, such as the `NestedLoopJoin`.
+#[derive(Clone, Copy)]
+pub struct OrPredicate<P, Q> {
+    predicate: P,
+    other: Q,
+}
+
+impl<P: JoinPredicate, Q: JoinPredicate<Left=P::Left, Right=P::Right>> OrPredicate<P, Q> {
+    pub fn new(predicate: P, other: Q) -> Self {
+        OrPredicate { predicate, other }
+    }
+}
+
+impl<P: JoinPredicate, Q: JoinPredicate<Left=P::Left, Right=P::Right>> JoinPredicate for OrPredicate<P, Q> {
+    type Left = P::Left;
+    type Right = P::Right;
+}
+impl<P, Q> InnerJoinPredicate for OrPredicate<P, Q>
+    where
+        P: InnerJoinPredicate,
+        Q: InnerJoinPredicate<Left=P::Left, Right=P::Right, Output=P::Output>,
+{
+    type Output = P::Output;
+
+    fn eq(&self, left: &Self::Left, right: &Self::Right) -> Option<Self::Output> {
+        self.predicate.eq(left, right).or_else(|| self.other.eq(left, right))
+    }
+}
+impl<P, Q> OuterJoinPredicate for OrPredicate<P, Q>
+    where
+        P: OuterJoinPredicate,
+        Q: OuterJoinPredicate<Left=P::Left, Right=P::Right>,
+{
+    fn eq(&self, left: &Self::Left, right: &Self::Right) -> bool {
+        self.predicate.eq(left, right) || self.other.eq(left, right)
+    }
+}
+impl<P, Q> PadRightPredicate for OrPredicate<P, Q>
+    where
+        P: PadRightPredicate,
+        Q: InnerJoinPredicate<Left=P::Left, Right=P::Right, Output=P::Output>,
+{
+    fn pad_right(&self, left: &Self::Left) -> Self::Output { self.predicate.pad_right(left) }
+}
+impl<P, Q> PadLeftPredicate for OrPredicate<P, Q>
+    where
+        P: PadLeftPredicate,
+        Q: InnerJoinPredicate<Left=P::Left, Right=P::Right, Output=P::Output>,
+{
+    fn pad_left(&self, right: &Self::Right) -> Self::Output { self.predicate.pad_left(right) }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{BandJoin, EquiJoin, IntervalJoin, IntervalOverlap, JoinInMemory, JoinPredicate, NestedLoopJoin, OrderedMergeJoin, SimpleHashJoin, SweepBandJoin};
+
+    type Tuple = (i32, i32);
+
+    /// Sorted by the first component.
+    fn tuples(n: i32) -> Vec<Tuple> {
+        (0..n).flat_map(|a| (0..3).map(move |b| (a, (a + b) % 4))).collect()
+    }
+
+    fn brute_force(left: &[Tuple], right: &[Tuple], matches: impl Fn(&Tuple, &Tuple) -> bool) -> Vec<(Tuple, Tuple)> {
+        let matches = &matches;
+        let mut expected: Vec<_> = left.iter()
+            .flat_map(|l| right.iter().filter(move |r| matches(l, r)).map(move |r| (*l, *r)))
+            .collect();
+        expected.sort_unstable();
+        expected
+    }
+
+    fn sorted(results: impl Iterator<Item=(Tuple, Tuple)>) -> Vec<(Tuple, Tuple)> {
+        let mut results: Vec<_> = results.collect();
+        results.sort_unstable();
+        results
+    }
+
+    #[test]
+    fn and() {
+        let (left, right) = (tuples(10), tuples(12));
+        let predicate = EquiJoin::new(|l: &Tuple| l.0, |r: &Tuple| r.0).and(EquiJoin::new(|l: &Tuple| l.1, |r: &Tuple| r.1));
+        let expected = brute_force(&left, &right, |l, r| l == r);
+        assert!(!expected.is_empty());
+        assert_eq!(expected, sorted(SimpleHashJoin::build_in_memory(left.clone(), right.clone(), predicate.by_ref(), usize::MAX)));
+        assert_eq!(expected, sorted(OrderedMergeJoin::build_in_memory(left, right, predicate, ())));
+    }
+
+    #[test]
+    fn filter() {
+        let (left, right) = (tuples(10), tuples(12));
+        let predicate = EquiJoin::new(|l: &Tuple| l.0, |r: &Tuple| r.0).filter(|l: &Tuple, r: &Tuple| l.1 < r.1);
+        let expected = brute_force(&left, &right, |l, r| l.0 == r.0 && l.1 < r.1);
+        assert_eq!(expected, sorted(SimpleHashJoin::build_in_memory(left.clone(), right.clone(), predicate.by_ref(), usize::MAX)));
+        assert_eq!(expected, sorted(OrderedMergeJoin::build_in_memory(left.clone(), right.clone(), predicate, ())));
+
+        let band = BandJoin::new(|l: &Tuple| l.0, |r: &Tuple| r.0, 1, 2).filter(|l: &Tuple, r: &Tuple| l.1 != r.1);
+        let expected_band = brute_force(&left, &right, |l, r| l.0 - 1 <= r.0 && r.0 <= l.0 + 2 && l.1 != r.1);
+        assert_eq!(expected_band, sorted(SweepBandJoin::build_in_memory(left.clone(), right.clone(), band, ())));
+
+        // intervals [a, a + b + 1)
+        let interval = IntervalOverlap::new(|l: &Tuple| (l.0, l.0 + l.1 + 1), |r: &Tuple| (r.0, r.0 + r.1 + 1)).filter(|l: &Tuple, r: &Tuple| l.0 != r.0);
+        let expected_interval = brute_force(&left, &right, |l, r| l.0 < r.0 + r.1 + 1 && r.0 < l.0 + l.1 + 1 && l.0 != r.0);
+        assert_eq!(expected_interval, sorted(IntervalJoin::build_in_memory(left, right, interval, 4)));
+    }
+
+    #[test]
+    fn or() {
+        let (left, right) = (tuples(10), tuples(12));
+        let predicate = EquiJoin::new(|l: &Tuple| l.0, |r: &Tuple| r.0).or(EquiJoin::new(|l: &Tuple| l.1, |r: &Tuple| r.0));
+        let expected = brute_force(&left, &right, |l, r| l.0 == r.0 || l.1 == r.0);
+        assert_eq!(expected, sorted(NestedLoopJoin::build_in_memory(left, right, predicate, ())));
+    }
+}
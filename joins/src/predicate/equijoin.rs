@@ -1,4 +1,4 @@
-use std::hash::{Hash, Hasher};
+use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
 use std::cmp::Ordering;
 use std::marker::PhantomData;
@@ -13,7 +13,7 @@ use super::*;
 /// That's what this type is for.
 ///
 /// It joins tuples by a **key** which is specified using closures.
-/// It returns `(left, right)` for tuples with equal keys.
+/// It returns `(left, right)` for tuples with equal keys, unless a different `OutputBuilder` is set.
 ///
 /// # Example
 ///
@@ -36,79 +36,131 @@ use super::*;
 /// In order to use this type, your tuples need to implement the following traits
 ///
 /// * `KeyLeft: PartialEq<KeyRight>`: this is the bare minimum so we can actually join tuples
-/// * `KeyLeft: Clone, KeyRight: Clone`: this is required to produce output tuples since any given
-///   input tuple may match several times
+/// * `Left: Clone, Right: Clone`: this is required to produce the default `(left, right)` output tuples
+///   since any given input tuple may match several times. Use `with_output` to lift this requirement.
 /// * `KeyLeft: Ord + PartialOrd<KeyRight>, KeyRight: Ord`: this is **optional** and enables the `MergePredicate` implementation for this join
 /// * `KeyLeft: Hash, KeyRight: Hash`: this is **optional** and enables the `HashPredicate` implementation for this join
+///
+/// # Hashing
+///
+/// Keys are hashed with `DefaultHasher` unless a different `BuildHasher` is passed to `with_hasher`.
+/// Seeded hashes feed the seed into the hasher before the key.
 #[derive(Clone, Copy)]
-pub struct EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight>
+pub struct EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S = BuildHasherDefault<DefaultHasher>, B = CloneOutput>
 where GetKeyLeft: Fn(&Left) -> KeyLeft,
       GetKeyRight: Fn(&Right) -> KeyRight {
     get_key_left: GetKeyLeft,
     get_key_right: GetKeyRight,
+    hasher: S,
+    output: B,
 
     // why is this necessary ???
     left: PhantomData<fn(&Left) -> KeyLeft>,
     right: PhantomData<fn(&Right) -> KeyLeft>,
 }
 
-// TODO: support non-Clone by delegating Output generation to a trait parameter
-//       (which defaults to a clone-dependent implementation that generates tuples)
-// TODO: allow passing in a different hasher maybe?
-
 impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight> EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight>
 where GetKeyLeft: Fn(&Left) -> KeyLeft,
       GetKeyRight: Fn(&Right) -> KeyRight,
-      KeyLeft: PartialEq<KeyRight>,
-      Left: Clone,
-      Right: Clone {
+      KeyLeft: PartialEq<KeyRight> {
     pub fn new(get_key_left: GetKeyLeft, get_key_right: GetKeyRight) -> Self {
-        EquiJoin { get_key_left, get_key_right, left: PhantomData, right: PhantomData }
+        EquiJoin::with_hasher(get_key_left, get_key_right, BuildHasherDefault::default())
+    }
+}
+
+impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S> EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S>
+where GetKeyLeft: Fn(&Left) -> KeyLeft,
+      GetKeyRight: Fn(&Right) -> KeyRight,
+      KeyLeft: PartialEq<KeyRight> {
+    /// Like `new`, but hashes keys using `hasher` instead of `DefaultHasher`.
+    ///
+    /// ```
+    /// use std::collections::hash_map::RandomState;
+    /// use joins::{EquiJoin, HashPredicate};
+    ///
+    /// let join = EquiJoin::with_hasher(|&l: &u32| l, |&r: &u32| r, RandomState::new());
+    /// assert_eq!(join.hash_left(&42), join.hash_right(&42));
+    /// assert_eq!(join.hash_left_seeded(&42, 1), join.hash_right_seeded(&42, 1));
+    /// ```
+    pub fn with_hasher(get_key_left: GetKeyLeft, get_key_right: GetKeyRight, hasher: S) -> Self {
+        EquiJoin { get_key_left, get_key_right, hasher, output: CloneOutput, left: PhantomData, right: PhantomData }
+    }
+}
+
+impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, B> EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, B>
+where GetKeyLeft: Fn(&Left) -> KeyLeft,
+      GetKeyRight: Fn(&Right) -> KeyRight,
+      KeyLeft: PartialEq<KeyRight> {
+    /// Replaces the way output tuples are generated for matching tuples.
+    ///
+    /// `output` is usually a closure `Fn(&Left, &Right) -> Output`, which allows joining
+    /// tuples that don't implement `Clone`.
+    ///
+    /// ```
+    /// use std::rc::Rc;
+    /// use joins::{EquiJoin, InnerJoinPredicate};
+    ///
+    /// struct Row { id: u32, payload: Vec<u8> }
+    /// let rows = vec![Rc::new(Row { id: 1, payload: vec![0; 1024] })];
+    ///
+    /// // only Rc clones are emitted
+    /// let join = EquiJoin::new(|l: &Rc<Row>| l.id, |&r: &u32| r);
+    /// assert!(join.eq(&rows[0], &1).is_some());
+    ///
+    /// // project a single column out of a non-Clone row
+    /// let join = EquiJoin::new(|l: &Row| l.id, |&r: &u32| r).with_output(|l: &Row, _: &u32| l.payload.len());
+    /// assert_eq!(Some(1024), join.eq(&rows[0], &1));
+    /// ```
+    pub fn with_output<O>(self, output: O) -> EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, O> {
+        let EquiJoin { get_key_left, get_key_right, hasher, .. } = self;
+        EquiJoin { get_key_left, get_key_right, hasher, output, left: PhantomData, right: PhantomData }
+    }
+
+    fn hash_key<K: Hash>(&self, key: K, seed: Option<u64>) -> u64 where S: BuildHasher {
+        let mut hasher = self.hasher.build_hasher();
+        if let Some(seed) = seed {
+            hasher.write_u64(seed);
+        }
+        key.hash(&mut hasher);
+        hasher.finish()
     }
 }
 
-impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight> JoinPredicate for EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight>
+impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, B> JoinPredicate for EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, B>
 where GetKeyLeft: Fn(&Left) -> KeyLeft,
       GetKeyRight: Fn(&Right) -> KeyRight,
-      KeyLeft: PartialEq<KeyRight>,
-      Left: Clone,
-      Right: Clone {
+      KeyLeft: PartialEq<KeyRight> {
     type Left = Left;
     type Right = Right;
 }
-impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight> InnerJoinPredicate for EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight>
+impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, B> InnerJoinPredicate for EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, B>
     where GetKeyLeft: Fn(&Left) -> KeyLeft,
           GetKeyRight: Fn(&Right) -> KeyRight,
           KeyLeft: PartialEq<KeyRight>,
-          Left: Clone,
-          Right: Clone {
-    type Output = (Left, Right);
+          B: OutputBuilder<Left, Right> {
+    type Output = B::Output;
     fn eq(&self, left: &Self::Left, right: &Self::Right) -> Option<Self::Output> {
         if (self.get_key_left)(left) == (self.get_key_right)(right) {
-            Some((left.clone(), right.clone()))
+            Some(self.output.build(left, right))
         } else {
             None
         }
     }
 }
-impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight> OuterJoinPredicate for EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight>
+impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, B> OuterJoinPredicate for EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, B>
     where GetKeyLeft: Fn(&Left) -> KeyLeft,
           GetKeyRight: Fn(&Right) -> KeyRight,
-          KeyLeft: PartialEq<KeyRight>,
-          Left: Clone,
-          Right: Clone {
+          KeyLeft: PartialEq<KeyRight> {
     fn eq(&self, left: &Self::Left, right: &Self::Right) -> bool {
         (self.get_key_left)(left) == (self.get_key_right)(right)
     }
 }
 
-impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight> MergePredicate for EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight>
+impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, B> MergePredicate for EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, B>
 where GetKeyLeft: Fn(&Left) -> KeyLeft,
       GetKeyRight: Fn(&Right) -> KeyRight,
       KeyLeft: Ord + PartialOrd<KeyRight>,
-      KeyRight: Ord,
-      Left: Clone,
-      Right: Clone {
+      KeyRight: Ord {
     fn cmp(&self, left: &Self::Left, right: &Self::Right) -> Option<Ordering> {
         (self.get_key_left)(left).partial_cmp(&(self.get_key_right)(right))
     }
@@ -120,21 +172,48 @@ where GetKeyLeft: Fn(&Left) -> KeyLeft,
     }
 }
 
-impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight> HashPredicate for EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight>
+impl<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, B> HashPredicate for EquiJoin<Left, Right, KeyLeft, KeyRight, GetKeyLeft, GetKeyRight, S, B>
 where GetKeyLeft: Fn(&Left) -> KeyLeft,
       GetKeyRight: Fn(&Right) -> KeyRight,
       KeyLeft: PartialEq<KeyRight> + Hash,
       KeyRight: Hash,
-      Left: Clone,
-      Right: Clone {
+      S: BuildHasher {
     fn hash_left(&self, x: &Self::Left) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        (self.get_key_left)(x).hash(&mut hasher);
-        hasher.finish()
+        self.hash_key((self.get_key_left)(x), None)
     }
     fn hash_right(&self, x: &Self::Right) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        (self.get_key_right)(x).hash(&mut hasher);
-        hasher.finish()
+        self.hash_key((self.get_key_right)(x), None)
+    }
+    fn hash_left_seeded(&self, x: &Self::Left, seed: u64) -> u64 {
+        self.hash_key((self.get_key_left)(x), Some(seed))
+    }
+    fn hash_right_seeded(&self, x: &Self::Right, seed: u64) -> u64 {
+        self.hash_key((self.get_key_right)(x), Some(seed))
+    }
+}
+
+/// Generates the output tuple of an `EquiJoin` for a pair of matching tuples.
+///
+/// This is implemented for all closures `Fn(&Left, &Right) -> Output`.
+pub trait OutputBuilder<Left, Right> {
+    type Output;
+    fn build(&self, left: &Left, right: &Right) -> Self::Output;
+}
+
+/// The default `OutputBuilder`, which clones both tuples into a `(left, right)` pair.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CloneOutput;
+
+impl<Left: Clone, Right: Clone> OutputBuilder<Left, Right> for CloneOutput {
+    type Output = (Left, Right);
+    fn build(&self, left: &Left, right: &Right) -> Self::Output {
+        (left.clone(), right.clone())
+    }
+}
+
+impl<Left, Right, Output, F: Fn(&Left, &Right) -> Output> OutputBuilder<Left, Right> for F {
+    type Output = Output;
+    fn build(&self, left: &Left, right: &Right) -> Self::Output {
+        self(left, right)
     }
 }
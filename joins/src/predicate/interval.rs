@@ -0,0 +1,115 @@
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+
+use super::*;
+
+/// An interval overlap join.
+///
+/// Both sides provide a half-open interval `[start, end)` through a closure.
+/// Tuples are joined if their intervals overlap, i.e. `l.start < r.end && r.start < l.end`.
+/// It returns `(left, right)` for overlapping tuples.
+///
+/// # Example
+///
+/// ```
+/// use joins::IntervalOverlap;
+/// #[derive(Clone, Debug)]
+/// struct Shift { begin: u32, end: u32 }
+/// #[derive(Clone, Debug)]
+/// struct Meeting { at: u32, duration: u32 }
+///
+/// IntervalOverlap::new(|s: &Shift| (s.begin, s.end), |m: &Meeting| (m.at, m.at + m.duration));
+/// ```
+///
+/// Besides `InnerJoinPredicate`, this implements `MergePredicate` (ordering tuples by start)
+/// and `IntervalPredicate`, so it can be used with an `IntervalJoin`.
+#[derive(Clone, Copy)]
+pub struct IntervalOverlap<Left, Right, Point, GetLeft, GetRight>
+where GetLeft: Fn(&Left) -> (Point, Point),
+      GetRight: Fn(&Right) -> (Point, Point) {
+    get_left: GetLeft,
+    get_right: GetRight,
+
+    _phantom: PhantomData<fn(&Left, &Right) -> Point>,
+}
+
+impl<Left, Right, Point, GetLeft, GetRight> IntervalOverlap<Left, Right, Point, GetLeft, GetRight>
+where GetLeft: Fn(&Left) -> (Point, Point),
+      GetRight: Fn(&Right) -> (Point, Point),
+      Point: Ord,
+      Left: Clone,
+      Right: Clone {
+    pub fn new(get_left: GetLeft, get_right: GetRight) -> Self {
+        IntervalOverlap { get_left, get_right, _phantom: PhantomData }
+    }
+
+    fn overlaps(&self, left: &Left, right: &Right) -> bool {
+        let (l_start, l_end) = (self.get_left)(left);
+        let (r_start, r_end) = (self.get_right)(right);
+        l_start < r_end && r_start < l_end
+    }
+}
+
+impl<Left, Right, Point, GetLeft, GetRight> JoinPredicate for IntervalOverlap<Left, Right, Point, GetLeft, GetRight>
+where GetLeft: Fn(&Left) -> (Point, Point),
+      GetRight: Fn(&Right) -> (Point, Point),
+      Point: Ord,
+      Left: Clone,
+      Right: Clone {
+    type Left = Left;
+    type Right = Right;
+}
+impl<Left, Right, Point, GetLeft, GetRight> InnerJoinPredicate for IntervalOverlap<Left, Right, Point, GetLeft, GetRight>
+where GetLeft: Fn(&Left) -> (Point, Point),
+      GetRight: Fn(&Right) -> (Point, Point),
+      Point: Ord,
+      Left: Clone,
+      Right: Clone {
+    type Output = (Left, Right);
+    fn eq(&self, left: &Self::Left, right: &Self::Right) -> Option<Self::Output> {
+        if self.overlaps(left, right) {
+            Some((left.clone(), right.clone()))
+        } else {
+            None
+        }
+    }
+}
+impl<Left, Right, Point, GetLeft, GetRight> OuterJoinPredicate for IntervalOverlap<Left, Right, Point, GetLeft, GetRight>
+where GetLeft: Fn(&Left) -> (Point, Point),
+      GetRight: Fn(&Right) -> (Point, Point),
+      Point: Ord,
+      Left: Clone,
+      Right: Clone {
+    fn eq(&self, left: &Self::Left, right: &Self::Right) -> bool {
+        self.overlaps(left, right)
+    }
+}
+impl<Left, Right, Point, GetLeft, GetRight> MergePredicate for IntervalOverlap<Left, Right, Point, GetLeft, GetRight>
+where GetLeft: Fn(&Left) -> (Point, Point),
+      GetRight: Fn(&Right) -> (Point, Point),
+      Point: Ord,
+      Left: Clone,
+      Right: Clone {
+    fn cmp(&self, left: &Self::Left, right: &Self::Right) -> Option<Ordering> {
+        Some((self.get_left)(left).0.cmp(&(self.get_right)(right).0))
+    }
+    fn cmp_left(&self, a: &Self::Left, b: &Self::Left) -> Ordering {
+        (self.get_left)(a).0.cmp(&(self.get_left)(b).0)
+    }
+    fn cmp_right(&self, a: &Self::Right, b: &Self::Right) -> Ordering {
+        (self.get_right)(a).0.cmp(&(self.get_right)(b).0)
+    }
+}
+impl<Left, Right, Point, GetLeft, GetRight> IntervalPredicate for IntervalOverlap<Left, Right, Point, GetLeft, GetRight>
+where GetLeft: Fn(&Left) -> (Point, Point),
+      GetRight: Fn(&Right) -> (Point, Point),
+      Point: Ord,
+      Left: Clone,
+      Right: Clone {
+    fn left_ends_before(&self, left: &Self::Left, right: &Self::Right) -> bool {
+        (self.get_left)(left).1 <= (self.get_right)(right).0
+    }
+    fn right_ends_before(&self, left: &Self::Left, right: &Self::Right) -> bool {
+        (self.get_right)(right).1 <= (self.get_left)(left).0
+    }
+}
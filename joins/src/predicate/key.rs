@@ -0,0 +1,90 @@
+use super::*;
+
+/// A tuple type that carries its own join key.
+///
+/// This is usually derived: `#[derive(JoinKey)]` uses the fields marked with `#[join(key)]`
+/// as the key, in declaration order. A single key field is used as-is, several key fields
+/// are combined into a tuple.
+///
+/// # Example
+///
+/// ```
+/// use joins::{EquiJoin, JoinKey};
+/// #[derive(Clone, Debug, JoinKey)]
+/// struct User { #[join(key)] id: u32, name: String }
+/// #[derive(Clone, Debug, JoinKey)]
+/// struct Order { order_id: u32, #[join(key)] user_id: u32 }
+///
+/// assert_eq!(7, User { id: 7, name: "alice".into() }.join_key());
+/// EquiJoin::<User, Order, _, _, _, _>::on_join_keys();
+///
+/// #[derive(Clone, Debug, JoinKey)]
+/// struct Point(#[join(key)] i32, #[join(key)] i32, String);
+/// assert_eq!((1, 2), Point(1, 2, "a".into()).join_key());
+/// ```
+pub trait JoinKey {
+    type Key;
+    fn join_key(&self) -> Self::Key;
+}
+
+impl<Left, Right> EquiJoin<Left, Right, Left::Key, Right::Key, fn(&Left) -> Left::Key, fn(&Right) -> Right::Key>
+where Left: JoinKey,
+      Right: JoinKey,
+      Left::Key: PartialEq<Right::Key> {
+    /// Creates an `EquiJoin` that joins tuples by their `JoinKey`s.
+    pub fn on_join_keys() -> Self {
+        EquiJoin::new(Left::join_key, Right::join_key)
+    }
+}
+
+/// Builds an `EquiJoin` from a list of field equalities.
+///
+/// `on!(Left.a = Right.b)` joins by a single field,
+/// `on!(Left.a = Right.z, Left.c = Right.y)` joins by several fields at once.
+/// All fields are cloned to build the keys.
+///
+/// # Example
+///
+/// ```
+/// use joins::predicate::on;
+/// #[derive(Clone, Debug)]
+/// struct User { id: u32, name: String }
+/// #[derive(Clone, Debug)]
+/// struct Order { order_id: u32, user_id: u32 }
+///
+/// on!(Order.user_id = User.id);
+/// on!(Order.user_id = User.id, Order.order_id = User.id);
+/// ```
+///
+/// Every equality must name the left type on the left and the right type on the right:
+///
+/// ```compile_fail
+/// use joins::predicate::on;
+/// struct A { x: u32, y: u32 }
+/// struct B { x: u32, y: u32 }
+///
+/// on!(A.x = B.y, B.x = A.y);
+/// ```
+#[macro_export]
+macro_rules! on {
+    ($left:ident . $lf:tt = $right:ident . $rf:tt) => {
+        $crate::EquiJoin::new(
+            |l: &$left| ::std::clone::Clone::clone(&l.$lf),
+            |r: &$right| ::std::clone::Clone::clone(&r.$rf),
+        )
+    };
+    ($left:ident . $lf:tt = $right:ident . $rf:tt $(, $_left:ident . $lfs:tt = $_right:ident . $rfs:tt)+ $(,)?) => {
+        $crate::EquiJoin::new(
+            |l: &$left| {
+                // rejects equalities that name the types the other way around
+                $(let _: &$_left = l;)+
+                (::std::clone::Clone::clone(&l.$lf), $(::std::clone::Clone::clone(&l.$lfs)),+)
+            },
+            |r: &$right| {
+                $(let _: &$_right = r;)+
+                (::std::clone::Clone::clone(&r.$rf), $(::std::clone::Clone::clone(&r.$rfs)),+)
+            },
+        )
+    };
+}
+pub use on;
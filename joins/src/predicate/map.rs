@@ -1,7 +1,7 @@
 use std::cmp::Ordering;
 use std::borrow::Borrow;
 use std::marker::PhantomData;
-use crate::{InnerJoinPredicate, OuterJoinPredicate};
+use crate::{InnerJoinPredicate, OuterJoinPredicate, PadLeftPredicate, PadRightPredicate};
 
 use super::{JoinPredicate, MergePredicate, HashPredicate};
 
@@ -57,6 +57,32 @@ impl<P, F, T, O> OuterJoinPredicate for MapLeftPredicate<P, F, T, O>
     fn eq(&self, left: &Self::Left, right: &Self::Right) -> bool {
         self.predicate.eq((self.mapping)(left).borrow(), right)
     }
+    fn is_null_left(&self, left: &Self::Left) -> bool {
+        self.predicate.is_null_left((self.mapping)(left).borrow())
+    }
+    fn is_null_right(&self, right: &Self::Right) -> bool {
+        self.predicate.is_null_right(right)
+    }
+}
+impl<P, F, T, O> PadRightPredicate for MapLeftPredicate<P, F, T, O>
+    where
+        P: PadRightPredicate,
+        F: Fn(&T) -> O,
+        O: Borrow<P::Left>,
+{
+    fn pad_right(&self, left: &Self::Left) -> Self::Output {
+        self.predicate.pad_right((self.mapping)(left).borrow())
+    }
+}
+impl<P, F, T, O> PadLeftPredicate for MapLeftPredicate<P, F, T, O>
+    where
+        P: PadLeftPredicate,
+        F: Fn(&T) -> O,
+        O: Borrow<P::Left>,
+{
+    fn pad_left(&self, right: &Self::Right) -> Self::Output {
+        self.predicate.pad_left(right)
+    }
 }
 impl<P, F, T, O> MergePredicate for MapLeftPredicate<P, F, T, O>
     where
@@ -82,6 +108,8 @@ impl<P, F, T, O> HashPredicate for MapLeftPredicate<P, F, T, O>
 {
     fn hash_left(&self, x: &Self::Left) -> u64 { self.predicate.hash_left((self.mapping)(x).borrow()) }
     fn hash_right(&self, x: &Self::Right) -> u64 { self.predicate.hash_right(x) }
+    fn hash_left_seeded(&self, x: &Self::Left, seed: u64) -> u64 { self.predicate.hash_left_seeded((self.mapping)(x).borrow(), seed) }
+    fn hash_right_seeded(&self, x: &Self::Right, seed: u64) -> u64 { self.predicate.hash_right_seeded(x, seed) }
 }
 
 #[derive(Clone)]
@@ -136,6 +164,32 @@ impl<P, F, T, O> OuterJoinPredicate for MapRightPredicate<P, F, T, O>
     fn eq(&self, left: &Self::Left, right: &Self::Right) -> bool {
         self.predicate.eq(left, (self.mapping)(right).borrow())
     }
+    fn is_null_left(&self, left: &Self::Left) -> bool {
+        self.predicate.is_null_left(left)
+    }
+    fn is_null_right(&self, right: &Self::Right) -> bool {
+        self.predicate.is_null_right((self.mapping)(right).borrow())
+    }
+}
+impl<P, F, T, O> PadRightPredicate for MapRightPredicate<P, F, T, O>
+    where
+        P: PadRightPredicate,
+        F: Fn(&T) -> O,
+        O: Borrow<P::Right>,
+{
+    fn pad_right(&self, left: &Self::Left) -> Self::Output {
+        self.predicate.pad_right(left)
+    }
+}
+impl<P, F, T, O> PadLeftPredicate for MapRightPredicate<P, F, T, O>
+    where
+        P: PadLeftPredicate,
+        F: Fn(&T) -> O,
+        O: Borrow<P::Right>,
+{
+    fn pad_left(&self, right: &Self::Right) -> Self::Output {
+        self.predicate.pad_left((self.mapping)(right).borrow())
+    }
 }
 impl<P, F, T, O> MergePredicate for MapRightPredicate<P, F, T, O>
     where
@@ -161,6 +215,8 @@ impl<P, F, T, O> HashPredicate for MapRightPredicate<P, F, T, O>
 {
     fn hash_left(&self, x: &Self::Left) -> u64 { self.predicate.hash_left(x) }
     fn hash_right(&self, x: &Self::Right) -> u64 { self.predicate.hash_right((self.mapping)(x).borrow()) }
+    fn hash_left_seeded(&self, x: &Self::Left, seed: u64) -> u64 { self.predicate.hash_left_seeded(x, seed) }
+    fn hash_right_seeded(&self, x: &Self::Right, seed: u64) -> u64 { self.predicate.hash_right_seeded((self.mapping)(x).borrow(), seed) }
 }
 
 #[derive(Clone)]
@@ -203,6 +259,24 @@ impl<P, F, T, O> InnerJoinPredicate for MapOutputPredicate<P, F, T, O>
         self.predicate.eq(left, right).map(&self.mapping)
     }
 }
+impl<P, F, T, O> PadRightPredicate for MapOutputPredicate<P, F, T, O>
+    where
+        P: PadRightPredicate<Output = T>,
+        F: Fn(T) -> O,
+{
+    fn pad_right(&self, left: &Self::Left) -> Self::Output {
+        (self.mapping)(self.predicate.pad_right(left))
+    }
+}
+impl<P, F, T, O> PadLeftPredicate for MapOutputPredicate<P, F, T, O>
+    where
+        P: PadLeftPredicate<Output = T>,
+        F: Fn(T) -> O,
+{
+    fn pad_left(&self, right: &Self::Right) -> Self::Output {
+        (self.mapping)(self.predicate.pad_left(right))
+    }
+}
 impl<P, F, T, O> MergePredicate for MapOutputPredicate<P, F, T, O>
     where
         P: MergePredicate,
@@ -219,4 +293,6 @@ impl<P, F, T, O> HashPredicate for MapOutputPredicate<P, F, T, O>
 {
     fn hash_left(&self, x: &Self::Left) -> u64 { self.predicate.hash_left(x) }
     fn hash_right(&self, x: &Self::Right) -> u64 { self.predicate.hash_right(x) }
+    fn hash_left_seeded(&self, x: &Self::Left, seed: u64) -> u64 { self.predicate.hash_left_seeded(x, seed) }
+    fn hash_right_seeded(&self, x: &Self::Right, seed: u64) -> u64 { self.predicate.hash_right_seeded(x, seed) }
 }
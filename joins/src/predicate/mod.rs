@@ -20,6 +20,13 @@
 //!   invoke the join predicate `o(n)` times.
 //!   Note that this depends entirely on the hash function and the actual data - in the worst
 //!   case where every single tuple happens to hash to the same value this is still `O(n²)`.
+//!
+//! Predicates that match keys within a range rather than on equality can implement
+//! `BandPredicate` on top of `MergePredicate`, which enables sweep-based joins such as `SweepBandJoin`.
+//! Likewise, `IntervalPredicate` enables the plane-sweep `IntervalJoin` for overlapping intervals.
+//!
+//! Mixed predicates such as `a.x = b.y AND a.ts < b.ts` are built with `filter` and `and`,
+//! which keep the hash and merge capabilities of the equality part.
 
 use std::borrow::Borrow;
 use std::cmp::Ordering;
@@ -27,11 +34,21 @@ use std::rc::Rc;
 use std::sync::Arc;
 
 mod equijoin;
-pub use equijoin::EquiJoin;
+pub use equijoin::{EquiJoin, OutputBuilder, CloneOutput};
+mod band;
+pub use band::BandJoin;
+mod interval;
+pub use interval::IntervalOverlap;
+mod key;
+pub use key::{JoinKey, on};
 mod swap;
 pub use swap::SwapPredicate;
 mod map;
 pub use map::{MapLeftPredicate, MapRightPredicate, MapOutputPredicate};
+mod combine;
+pub use combine::{FilterPredicate, AndPredicate, OrPredicate};
+mod outer;
+pub use outer::{PadLeftPredicate, PadRightPredicate, LeftOuterPredicate, RightOuterPredicate, FullOuterPredicate};
 
 pub trait JoinPredicate {
     type Left;
@@ -59,6 +76,50 @@ pub trait JoinPredicate {
         MapRightPredicate::new(self, mapping)
     }
 
+    /// Restricts this predicate by a residual condition, e.g. `a.ts < b.ts` on top of an equi-join.
+    ///
+    /// The residual is only evaluated for pairs matched by this predicate. Its `HashPredicate`
+    /// and `MergePredicate` implementations carry over, so hash and merge joins remain usable.
+    ///
+    /// ```
+    /// use joins::{EquiJoin, JoinInMemory, JoinPredicate, SimpleHashJoin};
+    ///
+    /// // a.x = b.y AND a.ts < b.ts
+    /// let left = vec![(1, 10), (1, 30), (2, 10)];
+    /// let right = vec![(1, 20), (2, 5)];
+    /// let predicate = EquiJoin::new(|l: &(u32, u32)| l.0, |r: &(u32, u32)| r.0)
+    ///     .filter(|l, r| l.1 < r.1);
+    /// let join = SimpleHashJoin::build_in_memory(left, right, predicate, 10);
+    /// assert_eq!(vec![((1, 10), (1, 20))], join.collect::<Vec<_>>());
+    /// ```
+    fn filter<F>(self, filter: F) -> FilterPredicate<Self, F>
+    where
+        F: Fn(&Self::Left, &Self::Right) -> bool,
+        Self: Sized,
+    {
+        FilterPredicate::new(self, filter)
+    }
+
+    /// Matches pairs that match both this and the `other` predicate.
+    ///
+    /// Like `filter`, this keeps the output, `HashPredicate` and `MergePredicate` implementations of `self`.
+    fn and<Q>(self, other: Q) -> AndPredicate<Self, Q>
+    where
+        Q: OuterJoinPredicate<Left=Self::Left, Right=Self::Right>,
+        Self: Sized,
+    {
+        AndPredicate::new(self, other)
+    }
+
+    /// Matches pairs that match this or the `other` predicate.
+    fn or<Q>(self, other: Q) -> OrPredicate<Self, Q>
+    where
+        Q: JoinPredicate<Left=Self::Left, Right=Self::Right>,
+        Self: Sized,
+    {
+        OrPredicate::new(self, other)
+    }
+
     fn by_ref(&self) -> &Self {
         self
     }
@@ -75,9 +136,32 @@ pub trait InnerJoinPredicate: JoinPredicate {
     {
         MapOutputPredicate::new(self, mapping)
     }
+
+    fn left_outer(self) -> LeftOuterPredicate<Self> where Self: Sized {
+        LeftOuterPredicate::new(self)
+    }
+
+    fn right_outer(self) -> RightOuterPredicate<Self> where Self: Sized {
+        RightOuterPredicate::new(self)
+    }
+
+    fn full_outer(self) -> FullOuterPredicate<Self> where Self: Sized {
+        FullOuterPredicate::new(self)
+    }
 }
 pub trait OuterJoinPredicate: JoinPredicate {
     fn eq(&self, left: &Self::Left, right: &Self::Right) -> bool;
+
+    /// Whether every comparison involving this left tuple is unknown (i.e. its key is SQL `NULL`).
+    ///
+    /// Only relevant for `NOT IN` semantics, see `AntiJoinMode::NotIn`.
+    fn is_null_left(&self, _left: &Self::Left) -> bool {
+        false
+    }
+    /// Whether every comparison involving this right tuple is unknown (i.e. its key is SQL `NULL`).
+    fn is_null_right(&self, _right: &Self::Right) -> bool {
+        false
+    }
 }
 
 pub trait MergePredicate: JoinPredicate {
@@ -86,9 +170,51 @@ pub trait MergePredicate: JoinPredicate {
     fn cmp_right(&self, a: &Self::Right, b: &Self::Right) -> Ordering;
 }
 
+/// A `MergePredicate` whose matches form a band around each left tuple.
+///
+/// Given inputs sorted by the merge order, a right tuple below the band of some left tuple
+/// can't match that left tuple or any of its successors. Conversely, a right tuple above
+/// the band of a left tuple can't match it, nor can any of the right tuple's successors.
+pub trait BandPredicate: MergePredicate {
+    fn below_band(&self, left: &Self::Left, right: &Self::Right) -> bool;
+    fn above_band(&self, left: &Self::Left, right: &Self::Right) -> bool;
+}
+
+/// A `MergePredicate` matching tuples that represent overlapping intervals.
+///
+/// The merge order must sort tuples by the start of their interval.
+pub trait IntervalPredicate: MergePredicate {
+    /// Whether the interval of `left` ends before the interval of `right` starts.
+    fn left_ends_before(&self, left: &Self::Left, right: &Self::Right) -> bool;
+    /// Whether the interval of `right` ends before the interval of `left` starts.
+    fn right_ends_before(&self, left: &Self::Left, right: &Self::Right) -> bool;
+}
+
 pub trait HashPredicate: JoinPredicate {
     fn hash_left(&self, x: &Self::Left) -> u64;
     fn hash_right(&self, x: &Self::Right) -> u64;
+
+    /// Hashes a left tuple with the hash function selected by `seed`.
+    ///
+    /// Partitioned joins use this to re-hash tuples with independent functions when recursing.
+    /// Like the unseeded variants, matching tuples must produce the same hash for the same seed.
+    /// The default implementation merely scrambles `hash_left`, so tuples with colliding hashes
+    /// keep colliding - predicates that can should feed the seed into their hash function instead.
+    fn hash_left_seeded(&self, x: &Self::Left, seed: u64) -> u64 {
+        mix_hash(self.hash_left(x), seed)
+    }
+    /// Hashes a right tuple with the hash function selected by `seed`, see `hash_left_seeded`.
+    fn hash_right_seeded(&self, x: &Self::Right, seed: u64) -> u64 {
+        mix_hash(self.hash_right(x), seed)
+    }
+}
+
+/// Scrambles a hash value depending on `seed` (splitmix64 finalizer).
+pub(crate) fn mix_hash(hash: u64, seed: u64) -> u64 {
+    let mut x = hash ^ seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
 }
 
 macro_rules! blanket_impl {
@@ -108,6 +234,12 @@ macro_rules! blanket_impl {
             fn eq(&self, left: &Self::Left, right: &Self::Right) -> bool {
                 (**self).eq(left, right)
             }
+            fn is_null_left(&self, left: &Self::Left) -> bool {
+                (**self).is_null_left(left)
+            }
+            fn is_null_right(&self, right: &Self::Right) -> bool {
+                (**self).is_null_right(right)
+            }
         }
         impl<$($lt,)? T: MergePredicate> MergePredicate for $t {
             fn cmp(&self, left: &Self::Left, right: &Self::Right) -> Option<Ordering> {
@@ -120,9 +252,25 @@ macro_rules! blanket_impl {
                 (**self).cmp_right(a, b)
             }
         }
+        impl<$($lt,)? T: PadRightPredicate> PadRightPredicate for $t {
+            fn pad_right(&self, left: &Self::Left) -> Self::Output { (**self).pad_right(left) }
+        }
+        impl<$($lt,)? T: PadLeftPredicate> PadLeftPredicate for $t {
+            fn pad_left(&self, right: &Self::Right) -> Self::Output { (**self).pad_left(right) }
+        }
+        impl<$($lt,)? T: BandPredicate> BandPredicate for $t {
+            fn below_band(&self, left: &Self::Left, right: &Self::Right) -> bool { (**self).below_band(left, right) }
+            fn above_band(&self, left: &Self::Left, right: &Self::Right) -> bool { (**self).above_band(left, right) }
+        }
+        impl<$($lt,)? T: IntervalPredicate> IntervalPredicate for $t {
+            fn left_ends_before(&self, left: &Self::Left, right: &Self::Right) -> bool { (**self).left_ends_before(left, right) }
+            fn right_ends_before(&self, left: &Self::Left, right: &Self::Right) -> bool { (**self).right_ends_before(left, right) }
+        }
         impl<$($lt,)? T: HashPredicate> HashPredicate for $t {
             fn hash_left(&self, x: &Self::Left) -> u64 { (**self).hash_left(x) }
             fn hash_right(&self, x: &Self::Right) -> u64 { (**self).hash_right(x) }
+            fn hash_left_seeded(&self, x: &Self::Left, seed: u64) -> u64 { (**self).hash_left_seeded(x, seed) }
+            fn hash_right_seeded(&self, x: &Self::Right, seed: u64) -> u64 { (**self).hash_right_seeded(x, seed) }
         }
     }
 }
@@ -0,0 +1,102 @@
+use std::cmp::Ordering;
+use crate::InnerJoinPredicate;
+
+use super::{JoinPredicate, MergePredicate, HashPredicate};
+
+/// Produces output tuples for left tuples that have no join partner.
+///
+/// Required by left and full outer joins.
+pub trait PadRightPredicate: InnerJoinPredicate {
+    fn pad_right(&self, left: &Self::Left) -> Self::Output;
+}
+
+/// Produces output tuples for right tuples that have no join partner.
+///
+/// Required by right and full outer joins.
+pub trait PadLeftPredicate: InnerJoinPredicate {
+    fn pad_left(&self, right: &Self::Right) -> Self::Output;
+}
+
+macro_rules! outer_predicate {
+    ($name:ident, $doc:literal, $output:ty, |$l:ident, $r:ident| $eq:expr) => {
+        #[doc = $doc]
+        #[derive(Clone, Copy)]
+        pub struct $name<P>(P);
+
+        impl<P: JoinPredicate> $name<P> {
+            pub fn new(predicate: P) -> Self {
+                $name(predicate)
+            }
+        }
+
+        impl<P: JoinPredicate> JoinPredicate for $name<P> {
+            type Left = P::Left;
+            type Right = P::Right;
+        }
+        impl<P> InnerJoinPredicate for $name<P>
+            where
+                P: InnerJoinPredicate<Output = (<P as JoinPredicate>::Left, <P as JoinPredicate>::Right)>,
+        {
+            type Output = $output;
+
+            fn eq(&self, left: &Self::Left, right: &Self::Right) -> Option<Self::Output> {
+                self.0.eq(left, right).map(|($l, $r)| $eq)
+            }
+        }
+        impl<P: MergePredicate> MergePredicate for $name<P> {
+            fn cmp(&self, left: &Self::Left, right: &Self::Right) -> Option<Ordering> { self.0.cmp(left, right) }
+            fn cmp_left(&self, a: &Self::Left, b: &Self::Left) -> Ordering { self.0.cmp_left(a, b) }
+            fn cmp_right(&self, a: &Self::Right, b: &Self::Right) -> Ordering { self.0.cmp_right(a, b) }
+        }
+        impl<P: HashPredicate> HashPredicate for $name<P> {
+            fn hash_left(&self, x: &Self::Left) -> u64 { self.0.hash_left(x) }
+            fn hash_right(&self, x: &Self::Right) -> u64 { self.0.hash_right(x) }
+            fn hash_left_seeded(&self, x: &Self::Left, seed: u64) -> u64 { self.0.hash_left_seeded(x, seed) }
+            fn hash_right_seeded(&self, x: &Self::Right, seed: u64) -> u64 { self.0.hash_right_seeded(x, seed) }
+        }
+    }
+}
+
+outer_predicate!(LeftOuterPredicate, "Turns a predicate producing `(Left, Right)` into one producing `(Left, Option<Right>)`.",
+    (P::Left, Option<P::Right>), |l, r| (l, Some(r)));
+outer_predicate!(RightOuterPredicate, "Turns a predicate producing `(Left, Right)` into one producing `(Option<Left>, Right)`.",
+    (Option<P::Left>, P::Right), |l, r| (Some(l), r));
+outer_predicate!(FullOuterPredicate, "Turns a predicate producing `(Left, Right)` into one producing `(Option<Left>, Option<Right>)`.",
+    (Option<P::Left>, Option<P::Right>), |l, r| (Some(l), Some(r)));
+
+impl<P> PadRightPredicate for LeftOuterPredicate<P>
+    where
+        P: InnerJoinPredicate<Output = (<P as JoinPredicate>::Left, <P as JoinPredicate>::Right)>,
+        P::Left: Clone,
+{
+    fn pad_right(&self, left: &Self::Left) -> Self::Output {
+        (left.clone(), None)
+    }
+}
+impl<P> PadLeftPredicate for RightOuterPredicate<P>
+    where
+        P: InnerJoinPredicate<Output = (<P as JoinPredicate>::Left, <P as JoinPredicate>::Right)>,
+        P::Right: Clone,
+{
+    fn pad_left(&self, right: &Self::Right) -> Self::Output {
+        (None, right.clone())
+    }
+}
+impl<P> PadRightPredicate for FullOuterPredicate<P>
+    where
+        P: InnerJoinPredicate<Output = (<P as JoinPredicate>::Left, <P as JoinPredicate>::Right)>,
+        P::Left: Clone,
+{
+    fn pad_right(&self, left: &Self::Left) -> Self::Output {
+        (Some(left.clone()), None)
+    }
+}
+impl<P> PadLeftPredicate for FullOuterPredicate<P>
+    where
+        P: InnerJoinPredicate<Output = (<P as JoinPredicate>::Left, <P as JoinPredicate>::Right)>,
+        P::Right: Clone,
+{
+    fn pad_left(&self, right: &Self::Right) -> Self::Output {
+        (None, Some(right.clone()))
+    }
+}
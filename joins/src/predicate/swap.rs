@@ -1,7 +1,7 @@
 use std::cmp::Ordering;
-use crate::{InnerJoinPredicate, OuterJoinPredicate};
+use crate::{InnerJoinPredicate, OuterJoinPredicate, PadLeftPredicate, PadRightPredicate};
 
-use super::{JoinPredicate, MergePredicate, HashPredicate};
+use super::{JoinPredicate, MergePredicate, HashPredicate, IntervalPredicate};
 
 pub struct SwapPredicate<P>(P);
 
@@ -26,6 +26,22 @@ impl<P: OuterJoinPredicate> OuterJoinPredicate for SwapPredicate<P> {
     fn eq(&self, left: &Self::Left, right: &Self::Right) -> bool {
         self.0.eq(right, left)
     }
+    fn is_null_left(&self, left: &Self::Left) -> bool {
+        self.0.is_null_right(left)
+    }
+    fn is_null_right(&self, right: &Self::Right) -> bool {
+        self.0.is_null_left(right)
+    }
+}
+impl<P: PadLeftPredicate> PadRightPredicate for SwapPredicate<P> {
+    fn pad_right(&self, left: &Self::Left) -> Self::Output {
+        self.0.pad_left(left)
+    }
+}
+impl<P: PadRightPredicate> PadLeftPredicate for SwapPredicate<P> {
+    fn pad_left(&self, right: &Self::Right) -> Self::Output {
+        self.0.pad_right(right)
+    }
 }
 impl<P: MergePredicate> MergePredicate for SwapPredicate<P> {
     fn cmp(&self, left: &Self::Left, right: &Self::Right) -> Option<Ordering> {
@@ -38,7 +54,17 @@ impl<P: MergePredicate> MergePredicate for SwapPredicate<P> {
         self.0.cmp_left(a, b)
     }
 }
+impl<P: IntervalPredicate> IntervalPredicate for SwapPredicate<P> {
+    fn left_ends_before(&self, left: &Self::Left, right: &Self::Right) -> bool {
+        self.0.right_ends_before(right, left)
+    }
+    fn right_ends_before(&self, left: &Self::Left, right: &Self::Right) -> bool {
+        self.0.left_ends_before(right, left)
+    }
+}
 impl<P: HashPredicate> HashPredicate for SwapPredicate<P> {
     fn hash_left(&self, x: &Self::Left) -> u64 { self.0.hash_right(x) }
     fn hash_right(&self, x: &Self::Right) -> u64 { self.0.hash_left(x) }
+    fn hash_left_seeded(&self, x: &Self::Left, seed: u64) -> u64 { self.0.hash_right_seeded(x, seed) }
+    fn hash_right_seeded(&self, x: &Self::Right, seed: u64) -> u64 { self.0.hash_left_seeded(x, seed) }
 }
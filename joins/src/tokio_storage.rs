@@ -0,0 +1,170 @@
+//! File-backed `AsyncExternalStorage` using tokio's file I/O.
+
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use futures::{Stream, stream};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tempfile::TempPath;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::{AsyncExternal, AsyncExternalStorage, Storage};
+use crate::file_storage::from_bincode;
+
+/// Spills runs to temporary files without blocking the executor.
+///
+/// Like `FileStorage`, but runs are written and read through `tokio::fs`, so it must be used
+/// from within a tokio runtime. Tuples are serialized one at a time as the run is written, so
+/// storing a run never holds a second copy of it.
+#[derive(Clone, Debug)]
+pub struct TokioFileStorage {
+    dir: PathBuf,
+}
+
+impl TokioFileStorage {
+    /// Spills to the system's temporary directory.
+    pub fn new() -> Self {
+        TokioFileStorage::in_dir(std::env::temp_dir())
+    }
+
+    /// Spills to `dir`, which must exist.
+    pub fn in_dir(dir: impl Into<PathBuf>) -> Self {
+        TokioFileStorage { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Default for TokioFileStorage {
+    fn default() -> Self {
+        TokioFileStorage::new()
+    }
+}
+
+impl Storage for TokioFileStorage {
+    type Error = io::Error;
+}
+impl<T: Serialize + DeserializeOwned + Send + 'static> AsyncExternalStorage<T> for TokioFileStorage {
+    type External = TokioFileRun<T>;
+    type Store = Pin<Box<dyn Future<Output=io::Result<TokioFileRun<T>>> + Send>>;
+
+    fn store(&mut self, tuples: Vec<T>) -> Self::Store {
+        let len = tuples.len();
+        let dir = self.dir.clone();
+        Box::pin(async move {
+            let create = move || tempfile::Builder::new().prefix("joins-run-").tempfile_in(&dir);
+            let (file, path) = tokio::task::spawn_blocking(create).await.map_err(io::Error::other)??.into_parts();
+            let mut writer = BufWriter::new(File::from_std(file));
+            // every tuple is written as a length-prefixed frame
+            let mut frame = Vec::new();
+            for tuple in tuples {
+                frame.clear();
+                bincode::serialize_into(&mut frame, &tuple).map_err(|e| from_bincode(*e))?;
+                writer.write_u64_le(frame.len() as u64).await?;
+                writer.write_all(&frame).await?;
+            }
+            writer.flush().await?;
+            Ok(TokioFileRun { path: Arc::new(path), len, _item: PhantomData })
+        })
+    }
+}
+
+/// A run stored in a temporary file.
+///
+/// The file is deleted once the run and all streams fetching it are dropped.
+pub struct TokioFileRun<T> {
+    path: Arc<TempPath>,
+    len: usize,
+    _item: PhantomData<fn() -> T>,
+}
+
+impl<T> TokioFileRun<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T: DeserializeOwned + Send + 'static> AsyncExternal<T> for TokioFileRun<T> {
+    type Error = io::Error;
+    type Fetch = Pin<Box<dyn Stream<Item=io::Result<T>> + Send>>;
+
+    fn fetch(&self) -> Self::Fetch {
+        let state = (Arc::clone(&self.path), None, self.len);
+        // the reader comes with the number of bytes left in the file
+        Box::pin(stream::try_unfold(state, |(path, reader, remaining): (_, Option<(BufReader<File>, u64)>, _)| async move {
+            if remaining == 0 {
+                return Ok(None);
+            }
+            let (mut reader, bytes) = match reader {
+                Some(reader) => reader,
+                None => {
+                    let file = File::open(&**path).await?;
+                    let bytes = file.metadata().await?.len();
+                    (BufReader::new(file), bytes)
+                }
+            };
+            let len = reader.read_u64_le().await?;
+            // a corrupt length must not turn into a huge allocation
+            let bytes = bytes.checked_sub(8).and_then(|b| b.checked_sub(len))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "frame exceeds the run's file"))?;
+            let mut frame = vec![0; len as usize];
+            reader.read_exact(&mut frame).await?;
+            let item = bincode::deserialize(&frame).map_err(|e| from_bincode(*e))?;
+            Ok(Some((item, (path, Some((reader, bytes)), remaining - 1))))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use futures::TryStreamExt;
+    use crate::{AsyncExternal, AsyncExternalStorage, EquiJoin, IterSource, Join, SortMergeJoin, TokioFileStorage};
+
+    #[tokio::test]
+    async fn tokio_file_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let join = SortMergeJoin::build(
+            IterSource::new((0..100u32).rev()),
+            IterSource::new(50..150u32),
+            EquiJoin::new(|&l: &u32| l, |&r: &u32| r),
+            TokioFileStorage::in_dir(dir.path()),
+            10,
+        );
+        let results: Vec<_> = join.try_collect().await.unwrap();
+        assert_eq!((50..100).map(|x| (x, x)).collect::<Vec<_>>(), results);
+        assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn tokio_file_storage_corrupt_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = TokioFileStorage::in_dir(dir.path());
+        let run = storage.store(vec![1u32, 2, 3]).await.unwrap();
+        assert_eq!(vec![1, 2, 3], run.fetch().try_collect::<Vec<_>>().await.unwrap());
+
+        // the first frame claims to span far beyond the end of the file
+        let bytes = std::fs::read(&**run.path).unwrap();
+        let mut corrupt = bytes.clone();
+        corrupt[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&**run.path, &corrupt).unwrap();
+        let e = run.fetch().try_collect::<Vec<_>>().await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+
+        // the file ends in the middle of the last frame
+        std::fs::write(&**run.path, &bytes[..bytes.len() - 1]).unwrap();
+        let e = run.fetch().try_collect::<Vec<_>>().await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+    }
+}